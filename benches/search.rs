@@ -0,0 +1,56 @@
+//! Compares the two ways `/search` has matched tracks: lowercasing every title and
+//! artist per request, and comparing against the lowercased copies built once when the
+//! library loads (`build_search_index`). The server is a single binary, so the matching
+//! is reproduced here over a synthetic library of the same shape.
+//!
+//! Run with `cargo bench --bench search`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+struct Track {
+    title: String,
+    artist: String,
+}
+
+fn library(size: usize) -> Vec<Track> {
+    (0..size)
+        .map(|i| Track {
+            title: format!("Some Song Title Number {}", i),
+            artist: format!("The Artist Called {}", i % 500),
+        })
+        .collect()
+}
+
+/// Before: two `String` allocations per track on every request.
+fn search_lowercasing(tracks: &[Track], query: &str) -> usize {
+    tracks.iter()
+        .filter(|t| t.title.to_lowercase().contains(query) || t.artist.to_lowercase().contains(query))
+        .count()
+}
+
+/// After: the lowercased fields come from the index, so a request allocates nothing per track.
+fn search_indexed(index: &[(String, String)], query: &str) -> usize {
+    index.iter()
+        .filter(|(title, artist)| title.contains(query) || artist.contains(query))
+        .count()
+}
+
+fn bench_search(c: &mut Criterion) {
+    let tracks = library(20_000);
+    let index: Vec<(String, String)> = tracks.iter()
+        .map(|t| (t.title.to_lowercase(), t.artist.to_lowercase()))
+        .collect();
+    let query = "number 1999";
+
+    let mut group = c.benchmark_group("search 20k tracks");
+    group.bench_function("lowercase per request", |b| {
+        b.iter(|| search_lowercasing(black_box(&tracks), black_box(query)))
+    });
+    group.bench_function("precomputed index", |b| {
+        b.iter(|| search_indexed(black_box(&index), black_box(query)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_search);
+criterion_main!(benches);
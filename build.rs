@@ -0,0 +1,43 @@
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Records which commit and when this binary was built, for GET /version.
+fn main() {
+    // Docker builds have no .git; let them pass the sha in instead (used as given)
+    let sha = match std::env::var("GIT_SHA").ok().filter(|s| !s.is_empty()) {
+        Some(sha) => sha,
+        None => git_sha().unwrap_or_else(|| "unknown".to_string()),
+    };
+    let built_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    println!("cargo:rustc-env=PLEX_RADIO_GIT_SHA={}", sha);
+    println!("cargo:rustc-env=PLEX_RADIO_BUILD_TIMESTAMP={}", built_at);
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    // Watching paths that don't exist would rerun this script on every build
+    if Path::new(".git").is_dir() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/index");
+    }
+}
+
+/// The short sha of HEAD, with `-dirty` when tracked files have uncommitted changes.
+fn git_sha() -> Option<String> {
+    let sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string())?;
+    let dirty = Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| !out.stdout.is_empty())
+        .unwrap_or(false);
+    Some(if dirty { format!("{}-dirty", sha) } else { sha })
+}
@@ -1,44 +1,852 @@
 use axum::{
     body::Body,
-    extract::{Query, State},
-    http::{header, StatusCode},
-    response::{Html, IntoResponse, Json, Response},
-    routing::get,
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Json, Response,
+    },
+    routing::{get, post},
     Router,
 };
 use bytes::Bytes;
+use clap::Parser;
 use futures::Stream;
-use rand::Rng;
+use rand::{distributions::{Distribution, WeightedIndex}, rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::{net::SocketAddr, sync::Arc, time::{Duration, SystemTime}};
-use tracing::{error, info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::{net::{IpAddr, SocketAddr}, sync::Arc, time::{Duration, Instant, SystemTime}};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc, watch, Notify, Semaphore};
+use tower_http::compression::CompressionLayer;
+use tracing::{debug, error, info, warn};
+use utoipa::{OpenApi, ToSchema};
 
 // --- Configuration & State ---
 
+/// Recently played tracks per client_id, newest first.
+type History = Arc<std::sync::Mutex<HashMap<String, Vec<Track>>>>;
+type Preferences = Arc<std::sync::Mutex<HashMap<String, (Instant, StationPreferences)>>>;
+
 #[derive(Clone)]
 struct AppState {
     client: Client,
+    // Client without a total timeout, for long-lived audio streams
+    stream_client: Client,
     plex_url: String,
-    plex_token: String,
-    // We cache track keys to avoid hitting the DB for every song
-    tracks: Arc<Vec<Track>>,
+    plex_token: PlexToken,
+    // We cache track keys to avoid hitting the DB for every song.
+    // Swapped wholesale on refresh; readers take a snapshot with `library()`
+    library: Arc<std::sync::RwLock<Arc<Library>>>,
+    // Where the cache is (re)loaded from, and whether duplicates are collapsed
+    library_source: Arc<LibrarySource>,
+    dedup_tracks: bool,
+    // Held while a library refresh runs, so two refreshes never race
+    refresh_lock: Arc<tokio::sync::Mutex<()>>,
+    // Required for /admin endpoints (ADMIN_TOKEN); None disables them
+    admin_token: Option<String>,
     // Map session_id -> Current Track
-    sessions: Arc<std::sync::Mutex<HashMap<String, (Track, SystemTime)>>>,
-    // Map client_id -> History (Recent Tracks)
-    history: Arc<std::sync::Mutex<HashMap<String, Vec<Track>>>>,
+    sessions: Arc<std::sync::Mutex<HashMap<String, SessionInfo>>>,
+    // Map session_id -> Controls of its open /radio stream (pause, stop)
+    session_controls: Arc<std::sync::Mutex<HashMap<String, SessionControl>>>,
+    // Session ids whose now-playing changed (track change, resume, end), for /events
+    track_changes: broadcast::Sender<String>,
+    // Map session_id -> Tracks requested with POST /queue, played before the station resumes
+    queues: Arc<std::sync::Mutex<HashMap<String, VecDeque<Track>>>>,
+    // Map client_id -> History (Recent Tracks); None when DISABLE_HISTORY is set
+    history: Option<History>,
+    // Map client_id -> The station it last tuned in to, kept for PREFERENCES_TTL; None when DISABLE_HISTORY is set
+    preferences: Option<Preferences>,
+    // Map "client_id:seed" -> Selector, so seeded stations survive skips/reconnects
+    seeded_selectors: Arc<std::sync::Mutex<HashMap<String, Selector>>>,
+    // Map track key -> Net like/dislike score from POST /feedback (shared by all clients)
+    feedback: Arc<std::sync::Mutex<HashMap<String, i32>>>,
+    // Map client_id -> Tracks/Artists that client never wants to hear
+    blocked: Arc<std::sync::Mutex<HashMap<String, BlockList>>>,
+    // Map track key -> Details fetched from Plex for /track, kept for TRACK_DETAILS_TTL
+    track_details: Arc<std::sync::Mutex<HashMap<String, (Instant, TrackDetails)>>>,
+    // Sections search and playback are limited to; None allows every loaded section
+    playable_sections: Option<Arc<HashSet<String>>>,
+    // Leave tracks rated explicit out of search and playback (BLOCK_EXPLICIT)
+    block_explicit: bool,
     bitrate: u32,
     audio_boost: u32,
+    output_format: OutputFormat,
+    // Named alternatives to the three settings above, picked with `?profile=`
+    audio_profiles: Arc<AudioProfiles>,
+    // Transcoder `context` (TRANSCODE_CONTEXT): "static" or "streaming"
+    transcode_context: String,
+    media_version: MediaVersion,
     passthrough: bool,
+    stream_health: StreamHealth,
+    // Whether Plex keeps refusing to transcode (see `record_transcode_failure`)
+    transcoder: Arc<TranscoderStatus>,
+    // Switch to passthrough once the transcoder is found unavailable (AUTO_PASSTHROUGH_ON_TRANSCODE_FAIL)
+    auto_passthrough: bool,
+    // Bytes to collect before a new listener gets the first chunk (0 = send immediately)
+    prebuffer_bytes: usize,
+    // Finished transcodes kept on disk (DISK_CACHE_DIR); None when disabled
+    disk_cache: Option<Arc<DiskCache>>,
+    // Limits how many /radio streams (and thus Plex transcodes) run at once
+    stream_permits: Arc<Semaphore>,
+    max_streams: usize,
+    // HLS session_id -> (playlist id, stream slot) held by that session's current transcode
+    #[cfg(feature = "hls")]
+    hls_permits: Arc<std::sync::Mutex<HashMap<String, (u64, tokio::sync::OwnedSemaphorePermit)>>>,
+    // Session IDs whose Plex transcode should be stopped (consumed by a cleanup task)
+    transcode_stop_tx: mpsc::UnboundedSender<String>,
+    protocol: StreamProtocol,
+    plex_client: Arc<PlexClientInfo>,
+    // PLEX_EXTRA_HEADERS; the clients send them by default, this copy is for /admin/debug/transcode
+    extra_headers: Arc<reqwest::header::HeaderMap>,
+    web: WebConfig,
+    default_shuffle: bool,
+    // Route audio through the Web Audio visualizer in the UI
+    enable_visualizer: bool,
+    dayparts: Arc<Dayparts>,
+}
+
+/// Thresholds for the "finished too quickly" heuristic that catches broken transcodes.
+#[derive(Clone, Copy, Debug)]
+struct StreamHealth {
+    /// A track that sent fewer bytes than this is considered failed
+    min_bytes: usize,
+    /// A track that ended sooner than this is considered failed
+    min_duration: Duration,
+    /// Pause after a failure before trying the next track
+    error_backoff: Duration,
+    /// Send a silent MP3 frame when Plex has been quiet this long (STREAM_HEARTBEAT)
+    heartbeat: Option<Duration>,
+    /// Give up on a (non-requested) track when Plex sends nothing this long after connecting
+    first_byte_timeout: Option<Duration>,
+    /// Stop the transcode of a listener that hasn't read for this long (IDLE_STREAM_TIMEOUT)
+    idle_timeout: Option<Duration>,
+}
+
+impl StreamHealth {
+    fn from_env() -> Self {
+        let var = |name: &str, default: u64| -> u64 {
+            std::env::var(name)
+                .map(|v| v.parse().unwrap_or_else(|_| panic!("{} must be a number", name)))
+                .unwrap_or(default)
+        };
+        StreamHealth {
+            min_bytes: var("MIN_STREAM_BYTES", 1024) as usize,
+            min_duration: Duration::from_secs(var("MIN_STREAM_SECS", 2)),
+            error_backoff: Duration::from_secs(var("ERROR_BACKOFF_SECS", 5)),
+            heartbeat: (std::env::var("STREAM_HEARTBEAT").as_deref() == Ok("true"))
+                .then(|| Duration::from_secs(var("STREAM_HEARTBEAT_SECS", 10))),
+            first_byte_timeout: Some(var("FIRST_BYTE_TIMEOUT_SECS", 8))
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            idle_timeout: Some(var("IDLE_STREAM_TIMEOUT", 0))
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+        }
+    }
+
+    /// Why a track that just ended looks like a broken transcode, if it does: too few
+    /// bytes, or an end sooner than `min_duration`. A quick end isn't held against tracks
+    /// that are meant to be short (a jingle) or that were asked for.
+    fn quick_end(&self, track: &Track, bytes_sent: usize, streamed_for: Duration, requested: bool) -> Option<String> {
+        let short_track = track.duration > 0 && Duration::from_millis(track.duration) < self.min_duration;
+        if bytes_sent < self.min_bytes {
+            Some(format!("only {} bytes (MIN_STREAM_BYTES={})", bytes_sent, self.min_bytes))
+        } else if streamed_for < self.min_duration && !short_track && !requested {
+            Some(format!(
+                "only {:.1}s (MIN_STREAM_SECS={})",
+                streamed_for.as_secs_f32(),
+                self.min_duration.as_secs()
+            ))
+        } else {
+            None
+        }
+    }
+
+    /// How long to wait after a quick end before the next track. Tracks with an unknown
+    /// (zero) length can't be judged, so they never cause a back-off.
+    fn backoff_after(&self, track: &Track, quick_end: Option<&str>) -> Option<Duration> {
+        quick_end.filter(|_| track.duration > 0).map(|_| self.error_backoff)
+    }
+}
+
+/// Refusals in a row, each naming the transcoder, before it is reported unavailable.
+const TRANSCODER_REFUSAL_THRESHOLD: u32 = 3;
+
+/// Phrases in a Plex error body (lowercased) that mean the transcoder can't run at all,
+/// rather than that one track or request failed.
+const TRANSCODER_UNAVAILABLE_MARKERS: [&str; 7] = [
+    "transcoder is disabled",
+    "transcoding is disabled",
+    "transcoder not available",
+    "transcoder is not available",
+    "transcoder unavailable",
+    "not allowed to transcode",
+    "plex pass",
+];
+
+/// What we know about Plex's transcoder from the transcodes it refused.
+#[derive(Debug, Default)]
+struct TranscoderStatus {
+    // Recognized refusals since the last transcode that started
+    refusals: AtomicU32,
+    // What Plex said, once `TRANSCODER_REFUSAL_THRESHOLD` is reached
+    unavailable: std::sync::Mutex<Option<String>>,
+    // Playing files directly since then (AUTO_PASSTHROUGH_ON_TRANSCODE_FAIL); until restart
+    fallen_back: AtomicBool,
+}
+
+/// Sample rate and channel limits for transcoded audio (PLEX_AUDIO_SAMPLE_RATE,
+/// PLEX_AUDIO_CHANNELS). Unset values are left to Plex.
+#[derive(Clone, Copy, Debug)]
+struct OutputFormat {
+    sample_rate: Option<u32>,
+    channels: Option<u32>,
+}
+
+/// Sample rates (Hz) MP3 can carry.
+const ALLOWED_SAMPLE_RATES: [u32; 9] = [8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000];
+
+impl OutputFormat {
+    fn from_env() -> Self {
+        let var = |name: &str| -> Option<u32> {
+            let value = std::env::var(name).ok().filter(|v| !v.is_empty())?;
+            Some(value.parse().unwrap_or_else(|_| panic!("{} must be a number", name)))
+        };
+        let sample_rate = var("PLEX_AUDIO_SAMPLE_RATE");
+        if let Some(rate) = sample_rate.filter(|r| !ALLOWED_SAMPLE_RATES.contains(r)) {
+            panic!("PLEX_AUDIO_SAMPLE_RATE={} is not an MP3 sample rate (e.g. 44100 or 48000)", rate);
+        }
+        let channels = var("PLEX_AUDIO_CHANNELS");
+        if let Some(count) = channels.filter(|c| !(1..=2).contains(c)) {
+            panic!("PLEX_AUDIO_CHANNELS={} is not supported; MP3 carries 1 or 2 channels", count);
+        }
+        OutputFormat { sample_rate, channels }
+    }
+
+    /// The `X-Plex-Client-Profile-Extra` limitations that apply this format to the
+    /// transcoder's MP3 output, or `None` when nothing is configured.
+    fn profile_extra(&self) -> Option<String> {
+        let limits: Vec<String> = [("audio.samplingRate", self.sample_rate), ("audio.channels", self.channels)]
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let value = value?;
+                Some(format!(
+                    "add-limitation(scope=musicCodec&scopeName=mp3&type=upperBound&name={}&value={})",
+                    name, value
+                ))
+            })
+            .collect();
+        (!limits.is_empty()).then(|| limits.join("+"))
+    }
+}
+
+/// The settings a transcode is made with. Requests get the global PLEX_BITRATE,
+/// PLEX_AUDIO_BOOST and output format unless they pick a named profile.
+#[derive(Clone, Copy, Debug)]
+struct AudioProfile {
+    bitrate: u32,
+    audio_boost: u32,
+    output_format: OutputFormat,
+}
+
+/// Named audio profiles (AUDIO_PROFILES), e.g. `home:bitrate=320;mobile:bitrate=128,channels=1`,
+/// selected with `?profile=`. Keys a profile leaves out come from the global settings.
+#[derive(Debug)]
+struct AudioProfiles {
+    named: HashMap<String, AudioProfile>,
+    // Used when a request doesn't name one (DEFAULT_PROFILE)
+    default: Option<String>,
+}
+
+impl AudioProfiles {
+    fn from_env(base: AudioProfile) -> anyhow::Result<Self> {
+        let named = match std::env::var("AUDIO_PROFILES").ok().filter(|v| !v.trim().is_empty()) {
+            Some(raw) => raw.split(';')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(|entry| parse_audio_profile(entry, base))
+                .collect::<anyhow::Result<HashMap<_, _>>>()?,
+            None => HashMap::new(),
+        };
+        let default = std::env::var("DEFAULT_PROFILE").ok()
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty());
+        if let Some(name) = default.as_deref().filter(|name| !named.contains_key(*name)) {
+            anyhow::bail!("DEFAULT_PROFILE '{}' is not defined in AUDIO_PROFILES", name);
+        }
+        Ok(AudioProfiles { named, default })
+    }
+
+    /// Profile names, sorted, for error messages and /version.
+    fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.named.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+/// Parses `name:key=value,...` with keys bitrate, boost, sample_rate and channels.
+fn parse_audio_profile(entry: &str, base: AudioProfile) -> anyhow::Result<(String, AudioProfile)> {
+    let (name, settings) = entry.split_once(':').unwrap_or((entry, ""));
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        anyhow::bail!("AUDIO_PROFILES entry '{}' must look like name:bitrate=128", entry);
+    }
+    let mut profile = base;
+    for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (key, value) = setting.split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("AUDIO_PROFILES setting '{}' in '{}' must look like key=value", setting, name))?;
+        let key = key.trim();
+        if !["bitrate", "boost", "sample_rate", "channels"].contains(&key) {
+            anyhow::bail!(
+                "AUDIO_PROFILES {}: unknown setting '{}' (expected bitrate, boost, sample_rate or channels)",
+                name, key
+            );
+        }
+        let value: u32 = value.trim().parse()
+            .map_err(|_| anyhow::anyhow!("AUDIO_PROFILES {}: {} must be a number", name, key))?;
+        match key {
+            "bitrate" if value > 0 => profile.bitrate = value,
+            "boost" => profile.audio_boost = clamp_audio_boost(value, &format!("AUDIO_PROFILES {}: boost", name)),
+            "sample_rate" if ALLOWED_SAMPLE_RATES.contains(&value) => profile.output_format.sample_rate = Some(value),
+            "channels" if (1..=2).contains(&value) => profile.output_format.channels = Some(value),
+            _ => anyhow::bail!("AUDIO_PROFILES {}: {}={} is not supported", name, key, value),
+        }
+    }
+    Ok((name, profile))
+}
+
+/// How we identify ourselves to Plex (the `X-Plex-*` headers).
+#[derive(Debug)]
+struct PlexClientInfo {
+    identifier: String,
+    product: String,
+    version: String,
+    device: String,
+}
+
+impl PlexClientInfo {
+    fn from_env() -> Self {
+        let var = |name: &str, default: &str| {
+            std::env::var(name)
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| default.to_string())
+        };
+        let identifier = std::env::var("PLEX_CLIENT_IDENTIFIER")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| load_or_create_client_id(&var("PLEX_CLIENT_ID_FILE", ".plex-radio-client-id")));
+
+        PlexClientInfo {
+            identifier,
+            product: var("PLEX_PRODUCT", "Plex Radio"),
+            version: var("PLEX_VERSION", "1.0"),
+            device: var("PLEX_DEVICE", "Plex Radio"),
+        }
+    }
+
+    fn user_agent(&self) -> String {
+        format!("{}/{}", self.product.replace(' ', "-"), self.version)
+    }
+}
+
+/// Reads the persisted client identifier, generating (and saving) a random UUID the
+/// first time so Plex sees the same device across restarts.
+fn load_or_create_client_id(path: &str) -> String {
+    if let Ok(id) = std::fs::read_to_string(path) {
+        let id = id.trim();
+        if !id.is_empty() {
+            return id.to_string();
+        }
+    }
+
+    let mut rng = rand::thread_rng();
+    let id = format!(
+        "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+        rng.gen::<u32>(),
+        rng.gen::<u16>(),
+        rng.gen::<u16>() & 0x0fff,
+        (rng.gen::<u16>() & 0x3fff) | 0x8000,
+        rng.gen::<u64>() & 0xffff_ffff_ffff,
+    );
+    match std::fs::write(path, &id) {
+        Ok(()) => info!("Generated Plex client identifier {} (saved to {})", id, path),
+        Err(e) => warn!("Could not save Plex client identifier to {}: {}. It will change on restart.", path, e),
+    }
+    id
+}
+
+/// How the transcoder delivers audio to us.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StreamProtocol {
+    /// A single progressive MP3 stream per track (default)
+    Http,
+    /// HLS playlists + segments, proxied through /radio/hls
+    #[cfg(feature = "hls")]
+    Hls,
+}
+
+impl StreamProtocol {
+    fn as_str(self) -> &'static str {
+        match self {
+            StreamProtocol::Http => "http",
+            #[cfg(feature = "hls")]
+            StreamProtocol::Hls => "hls",
+        }
+    }
+}
+
+/// Which version of a track with several media versions (e.g. a lossless and a lossy
+/// copy) is played, as set by MEDIA_VERSION.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MediaVersion {
+    /// Whatever Plex lists first (default)
+    First,
+    HighestBitrate,
+    LowestBitrate,
+}
+
+impl MediaVersion {
+    fn as_str(self) -> &'static str {
+        match self {
+            MediaVersion::First => "first",
+            MediaVersion::HighestBitrate => "highest",
+            MediaVersion::LowestBitrate => "lowest",
+        }
+    }
+
+    /// Index of the version to play. Versions Plex reports no bitrate for are skipped
+    /// when comparing bitrates.
+    fn pick(self, media: &[TrackMedia]) -> usize {
+        let rated = media.iter().enumerate().filter_map(|(i, m)| Some((i, m.bitrate?)));
+        let picked = match self {
+            MediaVersion::First => None,
+            MediaVersion::HighestBitrate => rated.max_by_key(|&(_, kbps)| kbps),
+            MediaVersion::LowestBitrate => rated.min_by_key(|&(_, kbps)| kbps),
+        };
+        picked.map_or(0, |(i, _)| i)
+    }
+}
+
+/// Media version and part explicitly requested for a track (`?media_index=&part_index=`).
+#[derive(Clone, Copy, Debug, Default)]
+struct MediaIndexes {
+    media_index: Option<usize>,
+    part_index: Option<usize>,
+}
+
+impl MediaIndexes {
+    fn from_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let index = |name: &str| {
+            params.get(name)
+                .map(|s| s.parse::<usize>().map_err(|_| format!("`{}` must be a non-negative number", name)))
+                .transpose()
+        };
+        Ok(MediaIndexes { media_index: index("media_index")?, part_index: index("part_index")? })
+    }
+}
+
+/// Time-of-day genre schedule for `/radio/auto` (DAYPARTS), e.g. `6-10=ambient;18-24=rock`.
+#[derive(Debug)]
+struct Dayparts {
+    parts: Vec<Daypart>,
+    // Added to UTC to get the local time the hours refer to (DAYPART_UTC_OFFSET)
+    utc_offset_mins: i64,
+}
+
+#[derive(Debug)]
+struct Daypart {
+    start_hour: u32,
+    // Exclusive; smaller than `start_hour` for parts that run past midnight
+    end_hour: u32,
+    // Lowercased, like StationFilter's
+    genre: String,
+}
+
+impl Dayparts {
+    fn from_env() -> anyhow::Result<Self> {
+        let utc_offset_mins = match std::env::var("DAYPART_UTC_OFFSET").ok().filter(|v| !v.is_empty()) {
+            Some(hours) => {
+                let hours: f64 = hours.parse()
+                    .map_err(|_| anyhow::anyhow!("DAYPART_UTC_OFFSET must be a number of hours (e.g. -5 or 5.5)"))?;
+                (hours * 60.0).round() as i64
+            }
+            None => 0,
+        };
+        let parts = match std::env::var("DAYPARTS").ok().filter(|v| !v.trim().is_empty()) {
+            Some(raw) => raw.split(';')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(Daypart::parse)
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+        Ok(Dayparts { parts, utc_offset_mins })
+    }
+
+    /// The genre scheduled for the current local hour, if any part covers it.
+    fn current_genre(&self) -> Option<&str> {
+        let utc_mins = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs() as i64 / 60;
+        let hour = ((utc_mins + self.utc_offset_mins).rem_euclid(24 * 60) / 60) as u32;
+        self.parts.iter().find(|p| p.covers(hour)).map(|p| p.genre.as_str())
+    }
+}
+
+impl Daypart {
+    /// Parses `start-end=genre`, with hours from 0 to 24.
+    fn parse(entry: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("DAYPARTS entry '{}' must look like 6-10=genre", entry);
+        let (hours, genre) = entry.split_once('=').ok_or_else(invalid)?;
+        let (start, end) = hours.split_once('-').ok_or_else(invalid)?;
+        let hour = |h: &str| h.trim().parse::<u32>().ok().filter(|h| *h <= 24).ok_or_else(invalid);
+        let (start_hour, end_hour) = (hour(start)? % 24, hour(end)? % 24);
+        let genre = genre.trim().to_lowercase();
+        if genre.is_empty() || start_hour == end_hour {
+            return Err(invalid());
+        }
+        Ok(Daypart { start_hour, end_hour, genre })
+    }
+
+    fn covers(&self, hour: u32) -> bool {
+        if self.start_hour < self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+/// Where the track cache is loaded from: a playlist if one is configured,
+/// otherwise every item of the configured type in the sections.
+struct LibrarySource {
+    section_ids: Vec<String>,
+    // Plex type id loaded from each section (10 = track)
+    track_type: String,
+    playlist_id: Option<String>,
+}
+
+/// The cached track list plus anything derived from it.
+struct Library {
+    tracks: Vec<Track>,
+    // Lowercased (title, artist) per track, parallel to `tracks`, so search doesn't allocate
+    search_index: Vec<(String, String)>,
+}
+
+impl Library {
+    fn new(tracks: Vec<Track>) -> Self {
+        let search_index = build_search_index(&tracks);
+        Library { tracks, search_index }
+    }
+
+    /// Indexes of the tracks whose title or artist contains `query` (lowercased), best
+    /// matches first: an exact title or artist, then one that starts with the query, then
+    /// the rest. Ties keep library order.
+    fn search(&self, query: &str) -> Vec<usize> {
+        let rank = |field: &str| {
+            if field == query {
+                Some(0)
+            } else if field.starts_with(query) {
+                Some(1)
+            } else if field.contains(query) {
+                Some(2)
+            } else {
+                None
+            }
+        };
+        let mut matches: Vec<(u8, usize)> = self.search_index.iter()
+            .enumerate()
+            .filter_map(|(idx, (title, artist))| {
+                let best = match (rank(title), rank(artist)) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                best.map(|best| (best, idx))
+            })
+            .collect();
+        matches.sort_by_key(|&(best, _)| best);
+        matches.into_iter().map(|(_, idx)| idx).collect()
+    }
+}
+
+impl AppState {
+    /// Whether tracks are played as their original files: PLEX_PASSTHROUGH, or the
+    /// automatic fallback after the transcoder was found unavailable.
+    fn plays_passthrough(&self) -> bool {
+        self.passthrough || self.transcoder.fallen_back.load(Ordering::Relaxed)
+    }
+
+    /// The current track cache. Streams keep the snapshot they started with, so their
+    /// candidate indexes stay valid across a refresh.
+    fn library(&self) -> Arc<Library> {
+        self.library.read().unwrap().clone()
+    }
+
+    /// Whether the track cache holds anything yet; streaming and search answer 503 until it does.
+    fn is_ready(&self) -> bool {
+        !self.library.read().unwrap().tracks.is_empty()
+    }
+
+    /// Puts a track at the front of the client's recent tracks (kept to 10), unless
+    /// history is disabled.
+    fn record_history(&self, client_id: &str, track: &Track) {
+        let Some(history) = &self.history else { return };
+        if let Ok(mut history_map) = history.lock() {
+            let list = history_map.entry(client_id.to_string()).or_default();
+            list.insert(0, track.clone());
+            if list.len() > 10 {
+                list.pop();
+            }
+        }
+    }
+
+    /// Saves the station a client tuned in to for `/preferences`, unless history is
+    /// disabled or the stream has no `client_id`.
+    fn record_preferences(&self, params: &HashMap<String, String>, pairs: &[(String, String)]) {
+        let Some(preferences) = &self.preferences else { return };
+        let Some(client_id) = params.get("client_id").filter(|id| !id.is_empty()) else { return };
+        let station_params: Vec<(&str, &str)> = STATION_PARAMS.iter()
+            .flat_map(|&name| -> Vec<(&str, &str)> {
+                // Repeatable, so only the raw pairs have them all
+                if name == "exclude_artist" {
+                    pairs.iter().filter(|(k, _)| k == name).map(|(_, v)| (name, v.as_str())).collect()
+                } else {
+                    params.get(name).map(|v| (name, v.as_str())).into_iter().collect()
+                }
+            })
+            .filter(|(_, v)| !v.is_empty())
+            .collect();
+        let query: Vec<String> = station_params.iter()
+            .map(|(name, value)| format!("{}={}", name, url_encode(value)))
+            .collect();
+        let station = ["album", "playlist", "auto"].into_iter()
+            .find(|name| params.contains_key(*name))
+            .unwrap_or("radio");
+        let saved = StationPreferences {
+            station: station.to_string(),
+            genre: params.get("genre").filter(|g| !g.is_empty()).cloned(),
+            profile: params.get("profile").filter(|p| !p.is_empty()).cloned(),
+            url: if query.is_empty() { "/radio".to_string() } else { format!("/radio?{}", query.join("&")) },
+            saved_at: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+        };
+        if let Ok(mut map) = preferences.lock() {
+            map.retain(|_, (saved_at, _)| saved_at.elapsed() < PREFERENCES_TTL);
+            map.insert(client_id.clone(), (Instant::now(), saved));
+        }
+    }
+
+    /// Takes the next track lined up for the session with `POST /queue`, if any.
+    fn next_queued(&self, session_id: &str) -> Option<Track> {
+        let mut queues = self.queues.lock().ok()?;
+        let queue = queues.get_mut(session_id)?;
+        let track = queue.pop_front();
+        if queue.is_empty() {
+            queues.remove(session_id);
+        }
+        track
+    }
+
+    /// The tracks lined up for the session, in play order.
+    fn queued_requests(&self, session_id: &str) -> Vec<Track> {
+        self.queues.lock().ok()
+            .and_then(|queues| queues.get(session_id).map(|q| q.iter().cloned().collect()))
+            .unwrap_or_default()
+    }
+
+    /// Whether `client_id` has blocked this track or its artist.
+    fn is_blocked(&self, client_id: &str, track: &Track) -> bool {
+        self.blocked.lock().ok()
+            .and_then(|map| map.get(client_id).map(|b| b.blocks(track)))
+            .unwrap_or(false)
+    }
+
+    /// Whether the track may be searched and played: its library section is allowed
+    /// (PLAYABLE_SECTIONS) and it isn't explicit while BLOCK_EXPLICIT is set.
+    fn is_playable(&self, track: &Track) -> bool {
+        self.playable_sections.as_ref().is_none_or(|allowed| allowed.contains(&track.section_id))
+            && !(self.block_explicit && track.is_explicit())
+    }
+
+    /// Shuffle weight per candidate from the tracks' feedback scores, with the client's
+    /// recent tracks left out after a skip, or `None` when neither applies (uniform
+    /// shuffle). Leaving them out keeps a skip, which reconnects with a fresh shuffle,
+    /// from rolling the track that was just skipped; later picks of the stream shuffle as
+    /// usual. The newest go first, and at least one track stays in.
+    fn shuffle_weights(&self, library: &Library, candidates: &[usize], client_id: &str, after_skip: bool) -> Option<Vec<f64>> {
+        let recent = self.history.as_ref()
+            .filter(|_| after_skip)
+            .and_then(|history| history.lock().ok()?.get(client_id).cloned())
+            .unwrap_or_default();
+        let feedback = self.feedback.lock().ok()?;
+        if feedback.is_empty() && recent.is_empty() {
+            return None;
+        }
+        let mut weights: Vec<f64> = candidates.iter()
+            .map(|&idx| feedback_weight(feedback.get(&library.tracks[idx].key).copied().unwrap_or(0)))
+            .collect();
+
+        let positions: HashMap<&str, usize> = candidates.iter().enumerate()
+            .map(|(pos, &idx)| (library.tracks[idx].key.as_str(), pos))
+            .collect();
+        let mut left = weights.len();
+        for track in &recent {
+            if left <= 1 {
+                break;
+            }
+            if let Some(&pos) = positions.get(track.key.as_str()) {
+                if weights[pos] > 0.0 {
+                    weights[pos] = 0.0;
+                    left -= 1;
+                }
+            }
+        }
+        Some(weights)
+    }
+
+    /// The station's tracks, limited to the playable sections.
+    fn station_candidates(&self, library: &Library, filter: &StationFilter) -> Vec<usize> {
+        let mut candidates = filter.candidates(&library.tracks);
+        candidates.retain(|&idx| self.is_playable(&library.tracks[idx]));
+        candidates
+    }
+}
+
+/// Tracks and artists a client has asked never to hear again.
+#[derive(Clone, Debug, Default)]
+struct BlockList {
+    keys: HashSet<String>,
+    // Lowercased artist names
+    artists: HashSet<String>,
+}
+
+impl BlockList {
+    fn blocks(&self, track: &Track) -> bool {
+        self.keys.contains(&track.key) || self.artists.contains(&track.artist.to_lowercase())
+    }
+}
+
+/// What a session is currently playing.
+#[derive(Clone, Debug)]
+struct SessionInfo {
+    track: Track,
+    // Listener that opened the stream; other client ids can't read its now-playing
+    client_id: String,
+    started_at: SystemTime,
+    // Effective maxAudioBitrate (kbps) for this session
+    bitrate: u32,
+    // How the current track is being served
+    mode: StreamMode,
+    // Selection state as of the current track, used to preview what comes next
+    selector: Option<Selector>,
+    // Tracks already lined up (e.g. the rest of an album in album mode)
+    queued: Vec<Track>,
+}
+
+/// Audio bytes of one track, from Plex or the disk cache.
+type ByteStream = std::pin::Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+/// How a track is served, as decided by `prepare_track_request` (or the disk cache).
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum StreamMode {
+    /// Through the universal transcoder
+    Transcode,
+    /// The original file, unmodified (PLEX_PASSTHROUGH)
+    Passthrough,
+    /// A complete earlier transcode, read back from DISK_CACHE_DIR
+    Cached,
 }
 
-#[derive(Clone, Debug, Serialize)]
+/// How many times a specifically requested track is retried before giving up on it.
+const SPECIFIC_TRACK_RETRIES: u32 = 2;
+
+/// How long `/track` reuses details it fetched from Plex.
+const TRACK_DETAILS_TTL: Duration = Duration::from_secs(300);
+
+/// How long a client's last station is kept for `/preferences` after it was tuned in to.
+const PREFERENCES_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+
+/// Query params that make up a station, saved for `/preferences`. What's playing right
+/// now (`track`, `offset`) and the session aren't part of it.
+const STATION_PARAMS: [&str; 16] = [
+    "genre", "artist", "year_min", "year_max", "dur_min", "dur_max", "exclude_artist", "clean",
+    "album", "playlist", "auto", "loop", "shuffle", "shuffle_mode", "profile", "bitrate",
+];
+
+/// Lowest and highest `audioBoost` (%) the Plex transcoder accepts.
+const AUDIO_BOOST_RANGE: (u32, u32) = (100, 300);
+
+/// How often a paused session pings Plex so its transcode isn't reaped.
+const PAUSE_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Feedback scores are kept within ±FEEDBACK_MAX_SCORE.
+const FEEDBACK_MAX_SCORE: i32 = 5;
+
+/// Each net like makes a track this much more likely to be shuffled in (and each
+/// dislike divides by it), so a track at -5 comes up about 1/8 as often as an unrated one.
+const FEEDBACK_WEIGHT_STEP: f64 = 1.5;
+
+/// Default and largest number of `/search` results.
+const SEARCH_LIMIT_DEFAULT: usize = 50;
+const SEARCH_LIMIT_MAX: usize = 200;
+
+/// Default and largest page size for `/tracks`, `/artists` and `/albums`.
+const TRACKS_PAGE_DEFAULT: usize = 100;
+const TRACKS_PAGE_MAX: usize = 500;
+
+/// How often `/events` sends a keepalive comment, so proxies don't close quiet streams.
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Most tracks a session may have lined up with `POST /queue`.
+const QUEUE_MAX_LEN: usize = 50;
+
+/// Bitrates (kbps) a client may request via `/radio?bitrate=`.
+const ALLOWED_BITRATES: [u32; 4] = [128, 192, 256, 320];
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
 struct Track {
     key: String,
     title: String,
     artist: String,
     duration: u64,
+    // ratingKey of the parent album (used by album play mode)
+    album_key: String,
+    // Title of the parent album, when Plex gave one
+    album: Option<String>,
+    genres: Vec<String>,
+    year: Option<u32>,
+    // Library section the track was loaded from (empty when Plex didn't say)
+    section_id: String,
+    // File container ("mp3", "flac", ...), which passthrough streams as-is
+    container: Option<String>,
+    // Plex `contentRating`; "explicit" is left out by BLOCK_EXPLICIT and `clean=true`
+    content_rating: Option<String>,
+    // A media version is split into several files (long recordings, DJ sets)
+    #[serde(skip)]
+    multi_part: bool,
+}
+
+impl Track {
+    fn is_explicit(&self) -> bool {
+        self.content_rating.as_deref().is_some_and(|r| r.trim().eq_ignore_ascii_case("explicit"))
+    }
+}
+
+/// A cached track plus what `/track` looks up in Plex: cover art and any album,
+/// year or genres the library listing left out.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+struct TrackDetails {
+    #[serde(flatten)]
+    track: Track,
+    // Proxied through `/track/art`, so the Plex token stays on the server
+    art_url: Option<String>,
+    #[serde(skip)]
+    thumb: Option<String>,
 }
 
 // --- Plex API Models ---
@@ -64,8 +872,67 @@ struct PlexMetadata {
     title: String,
     #[serde(rename = "grandparentTitle", default)]
     artist: String,
+    // Album title, used as the artist fallback for singles/podcasts
+    #[serde(rename = "parentTitle", default)]
+    album: String,
     #[serde(default)]
     duration: u64,
+    #[serde(rename = "parentRatingKey", default)]
+    album_key: String,
+    // Track number within the disc
+    #[serde(default)]
+    index: u32,
+    // Disc number within the album
+    #[serde(rename = "parentIndex", default)]
+    parent_index: u32,
+    #[serde(rename = "Genre", default)]
+    genres: Vec<PlexTag>,
+    // Tracks rarely carry their own year; the album's is the usual source
+    year: Option<u32>,
+    #[serde(rename = "parentYear")]
+    parent_year: Option<u32>,
+    #[serde(rename = "originallyAvailableAt")]
+    originally_available_at: Option<String>,
+    #[serde(rename = "librarySectionID")]
+    section_id: Option<u64>,
+    #[serde(rename = "contentRating")]
+    content_rating: Option<String>,
+    #[serde(rename = "Media", default)]
+    media: Vec<TrackMedia>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PlexTag {
+    tag: String,
+}
+
+impl From<PlexMetadata> for Track {
+    fn from(m: PlexMetadata) -> Self {
+        // Some tracks have no artist at all; fall back to the album, then a placeholder
+        let artist = [m.artist.trim(), m.album.trim()]
+            .into_iter()
+            .find(|s| !s.is_empty())
+            .unwrap_or("Unknown Artist")
+            .to_string();
+
+        Track {
+            key: m.rating_key,
+            title: m.title,
+            artist,
+            duration: m.duration,
+            album_key: m.album_key,
+            album: Some(m.album.trim().to_string()).filter(|a| !a.is_empty()),
+            genres: m.genres.into_iter().map(|g| g.tag).collect(),
+            year: m.year.or(m.parent_year).or_else(|| {
+                // "YYYY-MM-DD"
+                m.originally_available_at.as_deref()?.get(..4)?.parse().ok()
+            }),
+            section_id: m.section_id.map(|id| id.to_string()).unwrap_or_default(),
+            content_rating: m.content_rating.filter(|r| !r.trim().is_empty()),
+            multi_part: m.media.iter().any(|media| media.parts.len() > 1),
+            container: m.media.into_iter().next().and_then(|media| media.container),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -91,42 +958,205 @@ struct TrackMediaContainer {
 
 #[derive(Deserialize, Debug)]
 struct TrackMetadata {
-    #[serde(rename = "Media")]
+    #[serde(default)]
+    duration: u64,
+    #[serde(rename = "Media", default)]
     media: Vec<TrackMedia>,
+    #[serde(rename = "parentTitle", default)]
+    album: String,
+    year: Option<u32>,
+    #[serde(rename = "parentYear")]
+    parent_year: Option<u32>,
+    #[serde(rename = "Genre", default)]
+    genres: Vec<PlexTag>,
+    // Album cover; tracks seldom have their own
+    thumb: Option<String>,
+    #[serde(rename = "parentThumb")]
+    parent_thumb: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 struct TrackMedia {
-    #[serde(rename = "Part")]
+    #[serde(default)]
+    duration: u64,
+    // kbps
+    bitrate: Option<u32>,
+    container: Option<String>,
+    #[serde(rename = "Part", default)]
     parts: Vec<TrackPart>,
 }
 
 #[derive(Deserialize, Debug)]
 struct TrackPart {
     key: String,
+    #[serde(default)]
+    duration: u64,
+}
+
+/// Attributes Plex's JSON sends as numbers; in XML every attribute is a string.
+const PLEX_NUMERIC_ATTRIBUTES: [&str; 8] =
+    ["duration", "index", "parentIndex", "year", "parentYear", "librarySectionID", "size", "bitrate"];
+
+/// Item elements that Plex's JSON lists under `Metadata` (XML names them by type).
+const PLEX_METADATA_ELEMENTS: [&str; 6] = ["Track", "Video", "Photo", "Playlist", "Directory", "Metadata"];
+
+/// Decodes a Plex response, accepting XML from servers that ignore
+/// `Accept: application/json` (older versions and some configurations).
+async fn read_plex_body<T: DeserializeOwned>(resp: reqwest::Response) -> anyhow::Result<T> {
+    let is_xml = resp.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.contains("xml"));
+    let body = resp.bytes().await?;
+    if is_xml || body.trim_ascii_start().starts_with(b"<") {
+        debug!("Plex answered with XML instead of JSON; converting it");
+        return Ok(serde_json::from_value(plex_xml_to_json(&body)?)?);
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Converts a Plex XML document into the shape of its JSON API, so the same structs
+/// read both: attributes become fields and child elements become arrays named after
+/// the element (`Media`, `Part`, `Genre`, ... and `Metadata` for the items).
+fn plex_xml_to_json(xml: &[u8]) -> anyhow::Result<serde_json::Value> {
+    use quick_xml::events::{BytesStart, Event};
+    use serde_json::{Map, Value};
+
+    fn element(e: &BytesStart, in_container: bool) -> anyhow::Result<(String, Map<String, Value>)> {
+        let mut name = String::from_utf8_lossy(e.name().as_ref()).into_owned();
+        // Library sections stay `Directory`, as in JSON; items of any type become `Metadata`
+        if in_container && PLEX_METADATA_ELEMENTS.contains(&name.as_str()) && !is_section(e) {
+            name = "Metadata".to_string();
+        }
+        let mut fields = Map::new();
+        for attr in e.attributes() {
+            let attr = attr?;
+            let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+            let value = attr.unescape_value()?.into_owned();
+            let value = match value.parse::<u64>() {
+                Ok(n) if PLEX_NUMERIC_ATTRIBUTES.contains(&key.as_str()) => Value::from(n),
+                _ => Value::String(value),
+            };
+            fields.insert(key, value);
+        }
+        Ok((name, fields))
+    }
+
+    // `/library/sections` lists its sections as `<Directory>` with no `ratingKey`
+    fn is_section(e: &BytesStart) -> bool {
+        e.name().as_ref() == b"Directory"
+            && !e.attributes().flatten().any(|a| a.key.as_ref() == b"ratingKey")
+    }
+
+    fn attach(parent: Option<&mut (String, Map<String, Value>)>, root: &mut Option<Value>, name: String, fields: Map<String, Value>) {
+        match parent {
+            Some((_, parent)) => {
+                if let Some(list) = parent.entry(name).or_insert_with(|| Value::Array(Vec::new())).as_array_mut() {
+                    list.push(Value::Object(fields));
+                }
+            }
+            None => *root = Some(Value::Object(Map::from_iter([(name, Value::Object(fields))]))),
+        }
+    }
+
+    let mut reader = quick_xml::Reader::from_reader(xml);
+    let mut buf = Vec::new();
+    let mut stack: Vec<(String, Map<String, Value>)> = Vec::new();
+    let mut root = None;
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => stack.push(element(&e, stack.len() == 1)?),
+            Event::Empty(e) => {
+                let (name, fields) = element(&e, stack.len() == 1)?;
+                attach(stack.last_mut(), &mut root, name, fields);
+            }
+            Event::End(_) => {
+                if let Some((name, fields)) = stack.pop() {
+                    attach(stack.last_mut(), &mut root, name, fields);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    root.ok_or_else(|| anyhow::anyhow!("Plex returned an empty XML document"))
 }
 
 // --- Implementation ---
 
+// Command line flags. Everything else is configured through environment
+// variables (or `.env`), see the README.
+#[derive(Parser, Debug)]
+#[command(version, about = "Streams a Plex music library as an internet radio station")]
+struct Cli {
+    /// Check the configuration and the Plex connection, print a report and exit
+    /// (non-zero on failure) without starting the server
+    #[arg(long)]
+    check: bool,
+}
+
+/// Log filter used when RUST_LOG is unset (or invalid).
+const DEFAULT_LOG_FILTER: &str = "info,plex_radio_rust=debug";
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // 1. Initialize Logging
+    let cli = Cli::parse();
+
+    // .env is read first, so it can set RUST_LOG too
+    dotenvy::dotenv().ok();
+
+    // 1. Initialize Logging (RUST_LOG, e.g. `warn` or `plex_radio_rust=trace`)
+    let log_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(DEFAULT_LOG_FILTER));
     tracing_subscriber::fmt()
-        .with_env_filter("info,plex_radio_rust=debug")
+        .with_env_filter(log_filter)
         .init();
 
     // 2. Load Config
-    dotenvy::dotenv().ok();
-    let plex_url = std::env::var("PLEX_URL")
-        .expect("PLEX_URL must be set")
-        .trim_end_matches('/')
-        .to_string();
+    let plex_url_env = std::env::var("PLEX_URL")
+        .ok()
+        .map(|v| v.trim_end_matches('/').to_string())
+        .filter(|v| !v.is_empty());
+    // Feature: Find the server through plex.tv when no PLEX_URL is given
+    let server_name = std::env::var("PLEX_SERVER_NAME")
+        .ok()
+        .filter(|v| !v.is_empty());
+    if plex_url_env.is_none() && server_name.is_none() {
+        anyhow::bail!("PLEX_URL (or PLEX_SERVER_NAME) must be set");
+    }
     let plex_token = std::env::var("PLEX_TOKEN").expect("PLEX_TOKEN must be set");
     let section_id_env = std::env::var("PLEX_SECTION_ID")
         .ok()
         .filter(|v| !v.is_empty());
-    info!("Plex URL: {}", plex_url);
+    let section_name = std::env::var("PLEX_SECTION_NAME")
+        .ok()
+        .filter(|v| !v.is_empty());
+    // Feature: Load another Plex type id from the sections (default 10, tracks)
+    let track_type = std::env::var("PLEX_TRACK_TYPE").unwrap_or_else(|_| "10".to_string());
+    if track_type.parse::<u32>().is_err() {
+        anyhow::bail!("PLEX_TRACK_TYPE must be a number");
+    }
+    // Feature: Play a Plex playlist instead of whole sections
+    let playlist_id = std::env::var("PLEX_PLAYLIST_ID")
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
     let port = std::env::var("PORT").unwrap_or_else(|_| "3000".to_string());
+    // Feature: Configurable Bind Address (default all interfaces)
+    let bind_addr = std::env::var("BIND_ADDR").unwrap_or_else(|_| "0.0.0.0".to_string());
+    // IPv6 addresses may be given with brackets, as in URLs (`[::]`)
+    let ip: IpAddr = bind_addr
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| anyhow::anyhow!("BIND_ADDR '{}' is not a valid IP address", bind_addr))?;
+    // Feature: Accept IPv4 too on an IPv6 BIND_ADDR like `::` (default true, dual-stack)
+    let dual_stack = std::env::var("BIND_DUAL_STACK").unwrap_or_else(|_| "true".to_string()) != "false";
+    let port: u16 = port
+        .parse()
+        .map_err(|_| anyhow::anyhow!("PORT '{}' is not a valid port number", port))?;
+    let addr = SocketAddr::new(ip, port);
     
     // Feature: Configurable Bitrate (default 320 kbps)
     let bitrate = std::env::var("PLEX_BITRATE")
@@ -134,934 +1164,6283 @@ async fn main() -> anyhow::Result<()> {
         .parse()
         .expect("PLEX_BITRATE must be a number");
     // Feature: Configurable Audio Boost (default 100)
-    let audio_boost = std::env::var("PLEX_AUDIO_BOOST")
-        .unwrap_or_else(|_| "100".to_string())
-        .parse()
-        .expect("PLEX_AUDIO_BOOST must be a number");
+    let audio_boost = clamp_audio_boost(
+        std::env::var("PLEX_AUDIO_BOOST")
+            .unwrap_or_else(|_| "100".to_string())
+            .parse()
+            .expect("PLEX_AUDIO_BOOST must be a number"),
+        "PLEX_AUDIO_BOOST",
+    );
+    // Feature: Transcoder context (default static)
+    let transcode_context = std::env::var("TRANSCODE_CONTEXT").unwrap_or_else(|_| "static".to_string());
+    if !["static", "streaming"].contains(&transcode_context.as_str()) {
+        anyhow::bail!("TRANSCODE_CONTEXT must be 'static' or 'streaming'");
+    }
+    // Feature: Pick among a track's media versions (default: the first one)
+    let media_version = match std::env::var("MEDIA_VERSION").unwrap_or_else(|_| "first".to_string()).as_str() {
+        "first" => MediaVersion::First,
+        "highest" => MediaVersion::HighestBitrate,
+        "lowest" => MediaVersion::LowestBitrate,
+        other => anyhow::bail!("MEDIA_VERSION '{}' must be first, highest or lowest", other),
+    };
     // Feature: Passthrough Mode (default false)
     let passthrough = std::env::var("PLEX_PASSTHROUGH").unwrap_or_else(|_| "false".to_string()) == "true";
+    // Feature: Fall back to passthrough when Plex's transcoder is unavailable (default false)
+    let auto_passthrough = std::env::var("AUTO_PASSTHROUGH_ON_TRANSCODE_FAIL").unwrap_or_else(|_| "false".to_string()) == "true";
+    // Feature: Default shuffle mode offered to the web UI (default true)
+    let default_shuffle = std::env::var("DEFAULT_SHUFFLE").unwrap_or_else(|_| "true".to_string()) != "false";
+    // Feature: Web UI Visualizer (default true; some Safari versions stall with it)
+    let enable_visualizer = std::env::var("ENABLE_VISUALIZER").unwrap_or_else(|_| "true".to_string()) != "false";
+    // Feature: Time-of-day genre schedule for /radio/auto
+    let dayparts = Dayparts::from_env()?;
+    // Feature: Named audio profiles selectable with ?profile= (AUDIO_PROFILES, DEFAULT_PROFILE)
+    let output_format = OutputFormat::from_env();
+    let audio_profiles = AudioProfiles::from_env(AudioProfile { bitrate, audio_boost, output_format })?;
+    // Feature: Keep no per-client play history on the server (default false)
+    let disable_history = std::env::var("DISABLE_HISTORY").unwrap_or_else(|_| "false".to_string()) == "true";
+    // Feature: Collapse duplicate (artist, title) tracks (default false)
+    let dedup_tracks = std::env::var("DEDUP_TRACKS").unwrap_or_else(|_| "false".to_string()) == "true";
+    // Feature: Restrict search/playback to some of the loaded sections (default all)
+    let playable_sections: Option<HashSet<String>> = std::env::var("PLAYABLE_SECTIONS")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| v.split(',').map(|id| id.trim().to_string()).filter(|id| !id.is_empty()).collect());
+    // Feature: Skip tracks Plex rates explicit (default false)
+    let block_explicit = std::env::var("BLOCK_EXPLICIT").unwrap_or_else(|_| "false".to_string()) == "true";
+    // Feature: Answer malformed query params with 400 instead of defaults (default false)
+    let strict_params = std::env::var("STRICT_PARAMS").unwrap_or_else(|_| "false".to_string()) == "true";
+    // Feature: Startup Prebuffer (default 0, i.e. off)
+    let prebuffer_bytes: usize = std::env::var("PREBUFFER_BYTES")
+        .unwrap_or_else(|_| "0".to_string())
+        .parse()
+        .expect("PREBUFFER_BYTES must be a number");
+    // Feature: Admin Endpoints (disabled unless a token is set)
+    let admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|v| !v.is_empty());
+    // Feature: Concurrent Stream Limit (default 10)
+    let max_streams: usize = std::env::var("MAX_CONCURRENT_STREAMS")
+        .unwrap_or_else(|_| "10".to_string())
+        .parse()
+        .expect("MAX_CONCURRENT_STREAMS must be a number");
+    // Feature: Transcode Protocol (default http, hls requires the `hls` cargo feature)
+    let protocol = match std::env::var("PLEX_PROTOCOL").unwrap_or_else(|_| "http".to_string()).as_str() {
+        "http" => StreamProtocol::Http,
+        #[cfg(feature = "hls")]
+        "hls" => StreamProtocol::Hls,
+        other => panic!("PLEX_PROTOCOL '{}' is not supported by this build", other),
+    };
 
-    info!("Initializing Plex Radio...");
+    // Feature: Plex Request Timeout (default 10s) and Connection Pool
+    let plex_timeout = Duration::from_secs(
+        std::env::var("PLEX_TIMEOUT_SECS")
+            .unwrap_or_else(|_| "10".to_string())
+            .parse()
+            .expect("PLEX_TIMEOUT_SECS must be a number"),
+    );
+    let pool_max_idle: usize = std::env::var("PLEX_POOL_MAX_IDLE")
+        .unwrap_or_else(|_| "8".to_string())
+        .parse()
+        .expect("PLEX_POOL_MAX_IDLE must be a number");
+    let pool_idle_timeout = Duration::from_secs(
+        std::env::var("PLEX_POOL_IDLE_TIMEOUT_SECS")
+            .unwrap_or_else(|_| "90".to_string())
+            .parse()
+            .expect("PLEX_POOL_IDLE_TIMEOUT_SECS must be a number"),
+    );
+    // Feature: Stalled Stream Detection (default 60s without data)
+    let stream_read_timeout = Duration::from_secs(
+        std::env::var("PLEX_STREAM_READ_TIMEOUT_SECS")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse()
+            .expect("PLEX_STREAM_READ_TIMEOUT_SECS must be a number"),
+    );
 
-    // 3. Initialize HTTP Client
+    // Feature: Wait for a Plex server that is still starting up (default 5 retries, 5s apart)
+    let startup_retries: u32 = std::env::var("STARTUP_RETRIES")
+        .unwrap_or_else(|_| "5".to_string())
+        .parse()
+        .expect("STARTUP_RETRIES must be a number");
+    let startup_retry_interval = Duration::from_secs(
+        std::env::var("STARTUP_RETRY_SECS")
+            .unwrap_or_else(|_| "5".to_string())
+            .parse()
+            .expect("STARTUP_RETRY_SECS must be a number"),
+    );
+    // Feature: On-disk cache of finished transcodes (off unless DISK_CACHE_DIR is set)
+    let disk_cache_dir: Option<PathBuf> = std::env::var("DISK_CACHE_DIR")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .map(PathBuf::from);
+    let disk_cache_max_mb: u64 = std::env::var("DISK_CACHE_MAX_MB")
+        .unwrap_or_else(|_| "1024".to_string())
+        .parse()
+        .expect("DISK_CACHE_MAX_MB must be a number");
+    // Feature: Extra headers for every request to the Plex server (e.g. auth for a reverse proxy)
+    let extra_headers = match std::env::var("PLEX_EXTRA_HEADERS") {
+        Ok(v) if !v.trim().is_empty() => parse_extra_headers(&v)?,
+        _ => reqwest::header::HeaderMap::new(),
+    };
+    // Feature: Token as a query param too, for proxies that strip headers (default false)
+    let token_in_query = std::env::var("TOKEN_IN_QUERY").unwrap_or_else(|_| "false".to_string()) == "true";
+    let plex_token = PlexToken { value: plex_token, in_query: token_in_query };
+
+    info!("Initializing Plex Radio...");
+    let plex_client = PlexClientInfo::from_env();
+
+    // 3. Initialize HTTP Clients
+    // Metadata/control calls get a total timeout. The audio stream can't: reqwest's
+    // timeout covers reading the body too, which would cut every song off mid-play.
     let client = Client::builder()
-        .timeout(Duration::from_secs(10))
+        .user_agent(plex_client.user_agent())
+        .timeout(plex_timeout)
+        .default_headers(extra_headers.clone())
+        .pool_max_idle_per_host(pool_max_idle)
+        .pool_idle_timeout(pool_idle_timeout)
+        .build()?;
+    // Instead, the stream client only gives up when Plex stops sending data altogether.
+    let stream_client = stream_client_builder(plex_timeout, stream_read_timeout)
+        .user_agent(plex_client.user_agent())
+        .default_headers(extra_headers.clone())
+        .pool_max_idle_per_host(pool_max_idle)
+        .pool_idle_timeout(pool_idle_timeout)
         .build()?;
+    // plex.tv is called without PLEX_EXTRA_HEADERS, which are meant for the server only
+    // (including the /identity probes that look for it)
+    let plex_tv_client = Client::builder()
+        .user_agent(plex_client.user_agent())
+        .timeout(plex_timeout)
+        .build()?;
+    let plex_tv = std::env::var("PLEX_TV_URL").unwrap_or_else(|_| "https://plex.tv".to_string());
+
+    // 3.1. Resolve the server URL (Configured or discovered via plex.tv)
+    let plex_url = match (plex_url_env, server_name) {
+        (Some(url), _) => url,
+        (None, Some(name)) => {
+            info!("PLEX_URL not set, looking up server '{}' on plex.tv...", name);
+            retry_startup("Looking up the server", startup_retries, startup_retry_interval, || {
+                discover_server_url(&plex_tv_client, &client, &plex_client, &plex_tv, &plex_token, &name)
+            })
+            .await?
+        }
+        (None, None) => unreachable!("checked while loading the config"),
+    };
+    info!("Plex URL: {}", plex_url);
+
+    // 3.2. Resolve the Plex Home user to stream as (optional)
+    let plex_token = PlexToken {
+        value: resolve_user_token(&plex_tv_client, &plex_client, &plex_tv, plex_token.value, startup_retries, startup_retry_interval).await,
+        ..plex_token
+    };
+
+    // 3.3. Wait for Plex to answer (it may still be booting, e.g. in docker-compose)
+    wait_for_plex(&client, &plex_url, &plex_token, startup_retries, startup_retry_interval).await?;
 
-    // 3.5. Resolve Section ID (Configured or Auto-detected)
-    let section_id = match section_id_env {
-        Some(id) => id,
+    // 3.5. Resolve Section IDs (Configured or Auto-detected; a playlist needs none)
+    let section_ids: Vec<String> = match section_id_env {
+        _ if playlist_id.is_some() => Vec::new(),
+        Some(ids) => ids.split(',').map(|id| id.trim().to_string()).filter(|id| !id.is_empty()).collect(),
         None => {
             info!("PLEX_SECTION_ID not set, attempting to auto-detect music library...");
-            detect_music_section(&client, &plex_url, &plex_token).await?
+            vec![detect_music_section(&client, &plex_url, &plex_token, section_name.as_deref()).await?]
         }
     };
 
+    let library_source = LibrarySource { section_ids, track_type, playlist_id };
+
+    // 3.6. `--check`: report on the library instead of serving it
+    if cli.check {
+        return check_library(&client, &plex_url, &plex_token, &library_source).await;
+    }
+
     // 4. Pre-fetch Library Content (Cache Warming)
-    info!("Fetching track list from Plex Library ID: {}", section_id);
-    let tracks = fetch_library_tracks(&client, &plex_url, &plex_token, &section_id).await?;
+    let mut tracks = load_library(&client, &plex_url, &plex_token, &library_source).await;
+    if dedup_tracks {
+        tracks = dedup_library(tracks);
+    }
     info!("Loaded {} tracks into rotation.", tracks.len());
 
     if tracks.is_empty() {
-        error!("No tracks found. Please check your Section ID.");
-        return Ok(());
+        error!("No tracks found. Please check your Section ID (or PLEX_PLAYLIST_ID).");
+        anyhow::bail!("no tracks loaded from any library section");
+    }
+    if block_explicit {
+        report_explicit(&tracks);
     }
 
+    // 4.2. Open the disk cache (transcodes only; passthrough reads the files as they are)
+    let disk_cache = match disk_cache_dir.filter(|_| !passthrough) {
+        Some(dir) => {
+            let cache = DiskCache::open(dir, disk_cache_max_mb * 1024 * 1024).await?;
+            info!(
+                "Disk cache at {} holds {} MB of {} MB",
+                cache.dir.display(),
+                cache.used_bytes() / (1024 * 1024),
+                disk_cache_max_mb
+            );
+            Some(Arc::new(cache))
+        }
+        None => None,
+    };
+
+    // 4.5. Start the transcode cleanup task (stops Plex sessions when listeners leave)
+    let (transcode_stop_tx, transcode_stop_rx) = mpsc::unbounded_channel();
+    tokio::spawn(stop_transcode_sessions(
+        client.clone(),
+        plex_url.clone(),
+        plex_token.clone(),
+        transcode_stop_rx,
+    ));
+
     let state = AppState {
         client,
+        stream_client,
         plex_url,
         plex_token,
-        tracks: Arc::new(tracks),
+        library: Arc::new(std::sync::RwLock::new(Arc::new(Library::new(tracks)))),
+        library_source: Arc::new(library_source),
+        dedup_tracks,
+        refresh_lock: Arc::new(tokio::sync::Mutex::new(())),
+        admin_token,
         sessions: Arc::new(std::sync::Mutex::new(HashMap::new())),
-        history: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        session_controls: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        queues: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        track_changes: broadcast::channel(64).0,
+        history: (!disable_history).then(|| Arc::new(std::sync::Mutex::new(HashMap::new()))),
+        preferences: (!disable_history).then(|| Arc::new(std::sync::Mutex::new(HashMap::new()))),
+        seeded_selectors: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        feedback: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        blocked: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        track_details: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        playable_sections: playable_sections.map(Arc::new),
+        block_explicit,
         bitrate,
         audio_boost,
+        output_format,
+        audio_profiles: Arc::new(audio_profiles),
+        transcode_context,
+        media_version,
         passthrough,
+        stream_health: StreamHealth::from_env(),
+        transcoder: Arc::new(TranscoderStatus::default()),
+        auto_passthrough,
+        prebuffer_bytes,
+        disk_cache,
+        stream_permits: Arc::new(Semaphore::new(max_streams)),
+        max_streams,
+        #[cfg(feature = "hls")]
+        hls_permits: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        transcode_stop_tx,
+        protocol,
+        plex_client: Arc::new(plex_client),
+        extra_headers: Arc::new(extra_headers),
+        web: WebConfig::from_env(),
+        default_shuffle,
+        enable_visualizer,
+        dayparts: Arc::new(dayparts),
     };
 
     // 5. Setup Router
-    let app = Router::new()
-        .route("/", get(web_interface))
+    // Audio streams are never compressed: it gains nothing and delays the bytes
+    let streaming = Router::new()
         .route("/radio", get(stream_radio))
+        .route("/radio/album", get(stream_album))
+        .route("/radio/playlist", get(stream_playlist))
+        .route("/radio/auto", get(stream_auto));
+    #[cfg(feature = "hls")]
+    let streaming = streaming.route("/radio/hls/*path", get(hls_proxy));
+
+    // What overlays need is readable from any origin
+    let cross_origin = Router::new()
         .route("/now-playing", get(now_playing))
+        .route("/overlay", get(overlay))
+        .route("/events", get(now_playing_events))
+        .route("/track/art", get(track_art))
+        .layer(axum::middleware::map_response(allow_any_origin));
+
+    let api = Router::new()
+        .merge(cross_origin)
+        .route("/", get(web_interface))
+        .route("/pause", post(pause_session))
+        .route("/resume", get(resume_info).post(resume_session))
+        .route("/upcoming", get(upcoming_tracks))
+        .route("/np", get(now_playing_text))
+        .route("/queue", get(queue_info).post(enqueue_track).delete(clear_queue))
+        .route("/config", get(client_config))
+        .route("/stats/library", get(library_stats))
+        .route("/version", get(version_info))
+        .route("/openapi.json", get(|| async { Json(ApiDoc::openapi()) }))
+        .route("/block", post(block_item).delete(unblock_item))
+        .route("/preferences", get(client_preferences))
+        .route("/feedback", post(submit_feedback))
         .route("/search", get(search_tracks))
-        .route("/health", get(|| async { "OK" }))
-        .with_state(state);
+        .route("/tracks", get(browse_tracks))
+        .route("/artists", get(browse_artists))
+        .route("/albums", get(browse_albums))
+        .route("/random", get(random_track))
+        .route("/track", get(track_info))
+        .route("/admin/refresh", post(refresh_library))
+        .route("/admin/stop-all", post(stop_all_streams))
+        .route("/admin/debug/transcode", get(debug_transcode))
+        .route("/health", get(health))
+        .route("/robots.txt", get(|| async { "User-agent: *\nDisallow: /\n" }))
+        .layer(CompressionLayer::new());
+
+    let mut app = streaming.merge(api).fallback(route_not_found).with_state(state);
+    if strict_params {
+        app = app.layer(axum::middleware::from_fn(reject_malformed_params));
+    }
 
     // 6. Start Server
-    let addr: SocketAddr = format!("0.0.0.0:{}", port).parse()?;
     info!("Radio server listening on http://{}", addr);
     
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    let listener = bind_listener(addr, dual_stack)?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
 
-/// Fetches all music track keys from the specified library section.
+/// Opens the listening socket. For IPv6 addresses `IPV6_V6ONLY` is set explicitly, since
+/// platforms disagree on the default: with `dual_stack`, `::` takes IPv4 clients as well.
+fn bind_listener(addr: SocketAddr, dual_stack: bool) -> anyhow::Result<tokio::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    // Like tokio's own `bind`, so a restart doesn't wait out TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())
+        .map_err(|e| anyhow::anyhow!("Could not listen on {}: {}", addr, e))?;
+    socket.listen(1024)?;
+    Ok(tokio::net::TcpListener::from_std(socket.into())?)
+}
+
+/// Fetches all items of `track_type` from the specified library section.
 /// Uses the Plex API endpoint: /library/sections/{id}/all?type=10 (Type 10 = Track)
 async fn fetch_library_tracks(
     client: &Client,
     base_url: &str,
-    token: &str,
+    token: &PlexToken,
     section_id: &str,
+    track_type: &str,
 ) -> anyhow::Result<Vec<Track>> {
-    let url = format!("{}/library/sections/{}/all", base_url, section_id);
+    let url = format!("{}/library/sections/{}/all", base_url, url_encode(section_id));
     
     let resp = client
         .get(&url)
-        .header("X-Plex-Token", token)
+        .plex_token(token)
         .header("Accept", "application/json")
-        .query(&[("type", "10")]) // 10 is the Plex type ID for audio tracks
+        .query(&[("type", track_type)])
         .send()
         .await?
-        .error_for_status()?
-        .json::<PlexContainer>()
-        .await?;
+        .error_for_status()?;
+    let resp: PlexContainer = read_plex_body(resp).await?;
 
     let tracks: Vec<Track> = resp
         .media_container
         .metadata
         .into_iter()
-        .map(|m| Track {
-            key: m.rating_key,
-            title: m.title,
-            artist: m.artist,
-            duration: m.duration,
-        })
+        .map(Track::from)
+        .map(|t| Track { section_id: section_id.to_string(), ..t })
         .collect();
 
     Ok(tracks)
 }
 
-/// Detects the first available music library (type="artist") on the Plex server.
+/// Fetches the items of a playlist, in playlist order.
+/// Uses the Plex API endpoint: /playlists/{id}/items
+async fn fetch_playlist_tracks(
+    client: &Client,
+    base_url: &str,
+    token: &PlexToken,
+    playlist_id: &str,
+) -> anyhow::Result<Vec<Track>> {
+    let url = format!("{}/playlists/{}/items", base_url, url_encode(playlist_id));
+
+    let resp = client
+        .get(&url)
+        .plex_token(token)
+        .header("Accept", "application/json")
+        .send()
+        .await?
+        .error_for_status()?;
+    let resp: PlexContainer = read_plex_body(resp).await?;
+
+    Ok(resp.media_container.metadata.into_iter().map(Track::from).collect())
+}
+
+/// Loads the configured playlist, or else every configured section. A section that
+/// fails to load is logged and skipped, so one misconfigured library doesn't take
+/// the whole radio down.
+async fn load_library(
+    client: &Client,
+    base_url: &str,
+    token: &PlexToken,
+    source: &LibrarySource,
+) -> Vec<Track> {
+    if let Some(playlist_id) = &source.playlist_id {
+        info!("Fetching track list from Plex Playlist ID: {}", playlist_id);
+        return match fetch_playlist_tracks(client, base_url, token, playlist_id).await {
+            Ok(tracks) => {
+                info!("Playlist {} returned {} tracks.", playlist_id, tracks.len());
+                tracks
+            }
+            Err(e) => {
+                warn!("Failed to load playlist {}: {}", playlist_id, e);
+                Vec::new()
+            }
+        };
+    }
+
+    let mut tracks = Vec::new();
+    for section_id in &source.section_ids {
+        info!("Fetching track list from Plex Library ID: {}", section_id);
+        match fetch_library_tracks(client, base_url, token, section_id, &source.track_type).await {
+            Ok(section_tracks) => {
+                info!("Library {} returned {} tracks.", section_id, section_tracks.len());
+                tracks.extend(section_tracks);
+            }
+            Err(e) => warn!("Failed to load library {}: {}. Continuing without it.", section_id, e),
+        }
+    }
+    tracks
+}
+
+/// Fetches every configured source once and prints a report for `--check`.
+/// Unlike `load_library`, any source that fails (or an empty library) is an error.
+async fn check_library(
+    client: &Client,
+    base_url: &str,
+    token: &PlexToken,
+    source: &LibrarySource,
+) -> anyhow::Result<()> {
+    println!("Plex server: {}", base_url);
+
+    let mut total = 0;
+    let mut failed = 0;
+    let results = match &source.playlist_id {
+        Some(playlist_id) => vec![(
+            format!("Playlist {}", playlist_id),
+            fetch_playlist_tracks(client, base_url, token, playlist_id).await,
+        )],
+        None => {
+            let mut results = Vec::new();
+            for section_id in &source.section_ids {
+                results.push((
+                    format!("Section {}", section_id),
+                    fetch_library_tracks(client, base_url, token, section_id, &source.track_type).await,
+                ));
+            }
+            results
+        }
+    };
+    for (name, result) in results {
+        match result {
+            Ok(tracks) => {
+                println!("{}: {} tracks", name, tracks.len());
+                total += tracks.len();
+            }
+            Err(e) => {
+                println!("{}: FAILED ({})", name, e);
+                failed += 1;
+            }
+        }
+    }
+    println!("Total: {} tracks", total);
+
+    if failed > 0 {
+        anyhow::bail!("{} library source(s) could not be loaded", failed);
+    }
+    if total == 0 {
+        anyhow::bail!("no tracks found");
+    }
+    println!("Configuration OK");
+    Ok(())
+}
+
+/// Logs how much of the library BLOCK_EXPLICIT leaves out, warning when that's all of it.
+fn report_explicit(tracks: &[Track]) {
+    let explicit = tracks.iter().filter(|t| t.is_explicit()).count();
+    if explicit == tracks.len() {
+        warn!("BLOCK_EXPLICIT leaves out all {} tracks; stations will have nothing to play", explicit);
+    } else {
+        info!("BLOCK_EXPLICIT leaves out {} of {} tracks", explicit, tracks.len());
+    }
+}
+
+/// Collapses tracks sharing the same normalized (artist, title), keeping the first seen.
+/// Stops songs that appear on both an album and a compilation from playing twice as often.
+fn dedup_library(tracks: Vec<Track>) -> Vec<Track> {
+    let normalize = |s: &str| s.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+
+    let before = tracks.len();
+    let mut seen = std::collections::HashSet::new();
+    let unique: Vec<Track> = tracks
+        .into_iter()
+        .filter(|t| seen.insert((normalize(&t.artist), normalize(&t.title))))
+        .collect();
+
+    info!("Collapsed {} duplicate tracks.", before - unique.len());
+    unique
+}
+
+/// Fetches the tracks of an album in play order (disc, then track number).
+/// Uses the Plex API endpoint: /library/metadata/{key}/children
+async fn fetch_album_tracks(
+    client: &Client,
+    base_url: &str,
+    token: &PlexToken,
+    album_key: &str,
+) -> anyhow::Result<Vec<Track>> {
+    let url = format!("{}/library/metadata/{}/children", base_url, url_encode(album_key));
+
+    let resp = client
+        .get(&url)
+        .plex_token(token)
+        .header("Accept", "application/json")
+        .send()
+        .await?
+        .error_for_status()?;
+    let resp: PlexContainer = read_plex_body(resp).await?;
+
+    let mut metadata = resp.media_container.metadata;
+    metadata.sort_by_key(|m| (m.parent_index, m.index));
+
+    Ok(metadata.into_iter().map(Track::from).collect())
+}
+
+/// Polls the server until it answers, so a Plex that is still starting doesn't stop the
+/// radio. Connection errors and 5xx answers are retried (see `retry_startup`); any other
+/// error (e.g. a rejected token) fails at once.
+async fn wait_for_plex(
+    client: &Client,
+    base_url: &str,
+    token: &PlexToken,
+    retries: u32,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let url = format!("{}/library/sections", base_url);
+
+    retry_startup("Reaching Plex", retries, interval, || async {
+        let resp = client
+            .get(&url)
+            .plex_token(token)
+            .header("Accept", "application/json")
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+        if resp.status().is_server_error() {
+            return Err(resp.error_for_status().unwrap_err().without_url().into());
+        }
+        if !resp.status().is_success() {
+            anyhow::bail!("Plex answered {}; check PLEX_URL and PLEX_TOKEN", resp.status());
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Runs a startup step that talks to Plex or plex.tv, so a server that is still booting
+/// (e.g. in docker-compose) doesn't stop the radio: while the failure looks temporary
+/// (`is_transient`), the step is tried up to `retries` more times, `interval` apart.
+/// Any other error fails at once.
+async fn retry_startup<T, F, Fut>(what: &str, retries: u32, interval: Duration, mut step: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let error = match step().await {
+            Ok(value) => return Ok(value),
+            Err(e) if !is_transient(&e) => return Err(e),
+            Err(e) => e,
+        };
+        if attempt > retries {
+            return Err(error.context(format!("{} still failed after {} attempts", what, attempt)));
+        }
+        warn!(
+            "{} failed (attempt {}/{}): {:#}. Retrying in {}s...",
+            what, attempt, retries + 1, error, interval.as_secs()
+        );
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Whether a failure looks like the other end isn't up yet: no connection, a timeout or a
+/// 5xx answer.
+fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain()
+        .filter_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .any(|e| e.is_connect() || e.is_timeout() || e.status().is_some_and(|s| s.is_server_error()))
+}
+
+/// Detects the music library (type="artist") to play from. With several candidates,
+/// `name_hint` (PLEX_SECTION_NAME) picks one by title; otherwise the first one wins.
 async fn detect_music_section(
     client: &Client,
     base_url: &str,
-    token: &str,
+    token: &PlexToken,
+    name_hint: Option<&str>,
 ) -> anyhow::Result<String> {
     let url = format!("{}/library/sections", base_url);
 
     let resp = client
         .get(&url)
-        .header("X-Plex-Token", token)
+        .plex_token(token)
         .header("Accept", "application/json")
         .send()
         .await?
-        .error_for_status()?
-        .json::<PlexContainer>()
-        .await?;
+        .error_for_status()?;
+    let resp: PlexContainer = read_plex_body(resp).await?;
 
-    let section = resp
+    let mut candidates: Vec<PlexDirectory> = resp
         .media_container
         .directories
         .into_iter()
-        .find(|d| d.section_type == "artist")
-        .ok_or_else(|| anyhow::anyhow!("No music library (type='artist') found on this Plex server."))?;
+        .filter(|d| d.section_type == "artist")
+        .collect();
+
+    if candidates.is_empty() {
+        anyhow::bail!("No music library (type='artist') found on this Plex server.");
+    }
+    if candidates.len() > 1 {
+        for d in &candidates {
+            info!("Found music library: '{}' (ID: {})", d.title, d.key);
+        }
+    }
+
+    let section = match name_hint {
+        Some(name) => {
+            let idx = candidates
+                .iter()
+                .position(|d| d.title.eq_ignore_ascii_case(name))
+                .ok_or_else(|| anyhow::anyhow!("No music library named '{}' found on this Plex server.", name))?;
+            candidates.swap_remove(idx)
+        }
+        None => {
+            let section = candidates.remove(0);
+            if !candidates.is_empty() {
+                let others: Vec<String> = candidates
+                    .iter()
+                    .map(|d| format!("'{}' (ID: {})", d.title, d.key))
+                    .collect();
+                warn!(
+                    "Multiple music libraries found; using the first one. Set PLEX_SECTION_NAME or PLEX_SECTION_ID to pick another: {}",
+                    others.join(", ")
+                );
+            }
+            section
+        }
+    };
 
     info!("Auto-detected Music Library: '{}' (ID: {})", section.title, section.key);
     Ok(section.key)
 }
 
-// --- Web Interface ---
+/// Picks the token the radio acts with. `PLEX_USER_TOKEN` is used as-is; otherwise
+/// `PLEX_HOME_USER_ID` (plus `PLEX_HOME_USER_PIN` if the user has one) is exchanged for a
+/// user-scoped token via plex.tv, so playback is attributed to that Home user.
+/// A plex.tv that can't be reached is retried like the other startup steps; any other
+/// failure falls back to the admin `PLEX_TOKEN` with a warning.
+async fn resolve_user_token(
+    client: &Client,
+    plex_client: &PlexClientInfo,
+    plex_tv: &str,
+    admin_token: String,
+    retries: u32,
+    interval: Duration,
+) -> String {
+    if let Some(token) = std::env::var("PLEX_USER_TOKEN").ok().filter(|v| !v.is_empty()) {
+        info!("Streaming with PLEX_USER_TOKEN");
+        return token;
+    }
+    let Some(user_id) = std::env::var("PLEX_HOME_USER_ID").ok().filter(|v| !v.is_empty()) else {
+        return admin_token;
+    };
+    let pin = std::env::var("PLEX_HOME_USER_PIN").ok().filter(|v| !v.is_empty());
 
-async fn web_interface() -> Html<&'static str> {
-    Html(r#"
-<!DOCTYPE html>
-<html lang="en">
-<head>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>Plex Radio</title>
-    <style>
-        :root {
-            --primary: #e5a00d;
-            --bg: #1e1e1e;
-            --surface: #2d2d2d;
-            --text: #e0e0e0;
-        }
-        body {
-            background-color: var(--bg);
-            color: var(--text);
-            font-family: system-ui, -apple-system, sans-serif;
-            display: flex;
-            flex-direction: column;
-            align-items: center;
-            justify-content: center;
-            height: 100vh;
-            margin: 0;
-            overflow: hidden;
-        }
-        .player-container {
-            background: var(--surface);
-            padding: 2rem;
-            border-radius: 1rem;
-            box-shadow: 0 10px 30px rgba(0,0,0,0.5);
-            text-align: center;
-            width: 90%;
-            max-width: 400px;
-            position: relative;
-            z-index: 10;
-        }
-        .meta { margin-bottom: 1.5rem; }
-        .meta h2 { margin: 0; font-size: 1.2rem; color: #fff; }
-        .meta p { margin: 0.5rem 0 0; color: var(--primary); font-size: 1rem; font-weight: 500; }
-        
-        .progress-container {
-            width: 100%;
-            display: flex;
-            align-items: center;
-            gap: 10px;
-            margin-bottom: 1.5rem;
-            font-size: 0.8rem;
-            color: #aaa;
-        }
-        .progress-bar {
-            flex-grow: 1;
-            height: 4px;
-            background: #444;
-            border-radius: 2px;
-            overflow: hidden;
-            cursor: pointer;
-        }
-        .progress-fill {
-            height: 100%;
-            background: var(--primary);
-            width: 0%;
-            transition: width 0.1s linear;
-        }
-
-        h1 { margin: 0 0 1.5rem 0; color: var(--primary); font-weight: 700; letter-spacing: -0.5px; }
-        
-        /* Visualizer Canvas */
-        canvas {
-            width: 100%;
-            height: 100px;
-            background: #000;
-            border-radius: 0.5rem;
-            margin-bottom: 1.5rem;
-        }
-
-        /* Custom Controls */
-        .controls {
-            display: flex;
-            gap: 1rem;
-            justify-content: center;
-            margin-top: 1rem;
+    let switch = retry_startup("Switching to the Plex Home user", retries, interval, || {
+        switch_home_user(client, plex_client, plex_tv, &admin_token, &user_id, pin.as_deref())
+    });
+    match switch.await {
+        Ok(token) => {
+            info!("Switched to Plex Home user {}", user_id);
+            token
         }
-        
-        button {
-            background: var(--primary);
-            border: none;
-            border-radius: 50%;
-            width: 50px;
-            height: 50px;
-            cursor: pointer;
-            color: #000;
-            display: flex;
-            align-items: center;
-            justify-content: center;
-            transition: transform 0.1s, filter 0.1s;
-        }
-        button:hover { filter: brightness(1.1); transform: scale(1.05); }
-        button:active { transform: scale(0.95); }
-        button svg { width: 24px; height: 24px; fill: currentColor; }
-
-        audio { width: 100%; margin-top: 1rem; display: none; } /* Hidden, using custom controls */
-        
-        .status { font-size: 0.9rem; opacity: 0.7; margin-bottom: 1rem; min-height: 1.2em;}
-
-        .volume-container {
-            display: flex;
-            align-items: center;
-            gap: 10px;
-            width: 100%;
-            margin-top: 1.5rem;
-            color: var(--primary);
-        }
-        .volume-container svg { width: 24px; height: 24px; fill: currentColor; }
-        input[type=range] { flex-grow: 1; accent-color: var(--primary); cursor: pointer; }
-
-        /* Search Modal */
-        .search-modal {
-            position: fixed;
-            top: 0; left: 0; width: 100%; height: 100%;
-            background: rgba(0,0,0,0.95);
-            z-index: 100;
-            display: none;
-            flex-direction: column;
-            padding: 2rem;
-            box-sizing: border-box;
-            backdrop-filter: blur(5px);
-        }
-        .search-modal.open { display: flex; }
-        .search-header { display: flex; justify-content: flex-end; width: 100%; max-width: 600px; margin: 0 auto 1rem auto; }
-        .close-btn { background: none; font-size: 2rem; color: #fff; padding: 0; width: auto; height: auto; cursor: pointer; }
-        #searchInput {
-            width: 100%; max-width: 600px; margin: 0 auto;
-            padding: 1rem; font-size: 1.2rem;
-            background: #333; border: 1px solid #555; color: #fff; border-radius: 0.5rem;
-            outline: none;
-        }
-        #searchInput:focus { border-color: var(--primary); }
-        #searchResults {
-            flex-grow: 1; overflow-y: auto; width: 100%; max-width: 600px; margin: 1rem auto 0 auto;
-        }
-        .result-item {
-            display: flex; justify-content: space-between; align-items: center;
-            padding: 1rem; border-bottom: 1px solid #333; cursor: pointer;
-            transition: background 0.2s;
-            border-radius: 0.5rem;
-        }
-        .result-item:hover { background: #333; }
-        .result-info { text-align: left; }
-        .result-title { font-weight: bold; color: #fff; margin-bottom: 0.2rem; }
-        .result-artist { color: var(--primary); font-size: 0.9rem; }
-        .result-duration { font-size: 0.8rem; color: #888; }
-        
-        ::-webkit-scrollbar { width: 8px; }
-        ::-webkit-scrollbar-track { background: #222; }
-        ::-webkit-scrollbar-thumb { background: #555; border-radius: 4px; }
-        ::-webkit-scrollbar-thumb:hover { background: var(--primary); }
-
-        /* History */
-        .history-container { width: 100%; margin-top: 2rem; text-align: left; border-top: 1px solid #333; padding-top: 1rem; }
-        .history-title { color: #888; font-size: 0.8rem; margin-bottom: 0.5rem; text-transform: uppercase; letter-spacing: 1px; font-weight: bold; }
-        .history-list { list-style: none; padding: 0; margin: 0; }
-        .history-item { 
-            display: flex; justify-content: space-between; align-items: center; 
-            padding: 0.5rem 0; border-bottom: 1px solid #333; font-size: 0.9rem; color: #ccc; cursor: pointer; 
-            transition: color 0.2s;
-        }
-        .history-item:hover { color: var(--primary); }
-        .history-item:last-child { border-bottom: none; }
-        .hist-title { font-weight: 500; }
-        .hist-artist { font-size: 0.8rem; opacity: 0.6; }
-    </style>
-</head>
-<body>
-    <div class="player-container">
-        <h1>Plex Radio</h1>
-        <div class="meta">
-            <h2 id="trackTitle">Waiting...</h2>
-            <p id="trackArtist">...</p>
-        </div>
-        <div class="progress-container">
-            <span id="currentTime">0:00</span>
-            <div class="progress-bar"><div class="progress-fill" id="progressFill"></div></div>
-            <span id="totalTime">0:00</span>
-        </div>
-        <canvas id="visualizer"></canvas>
-        <div class="status" id="status">Ready to play</div>
-        
-        <div class="controls">
-            <button id="playBtn" title="Play/Pause">
-                <svg viewBox="0 0 24 24"><path d="M8 5v14l11-7z"/></svg>
-            </button>
-            <button id="stopBtn" title="Stop">
-                <svg viewBox="0 0 24 24"><path d="M6 6h12v12H6z"/></svg>
-            </button>
-            <button id="skipBtn" title="Skip Track">
-                <svg viewBox="0 0 24 24"><path d="M6 18l8.5-6L6 6v12zM16 6v12h2V6h-2z"/></svg>
-            </button>
-            <button id="shuffleBtn" title="Toggle Shuffle">
-                <svg viewBox="0 0 24 24"><path d="M10.59 9.17L5.41 4 4 5.41l5.17 5.17 1.42-1.41zM14.5 4l2.04 2.04L4 18.59 5.41 20 17.96 7.46 20 9.5V4h-5.5zm.33 9.41l-1.41 1.41 3.13 3.13L14.5 20H20v-5.5l-2.04 2.04-3.13-3.13z"/></svg>
-            </button>
-            <button id="searchBtn" title="Search Library">
-                <svg viewBox="0 0 24 24"><path d="M15.5 14h-.79l-.28-.27C15.41 12.59 16 11.11 16 9.5 16 5.91 13.09 3 9.5 3S3 5.91 3 9.5 5.91 16 9.5 16c1.61 0 3.09-.59 4.23-1.57l.27.28v.79l5 4.99L20.49 19l-4.99-5zm-6 0C7.01 14 5 11.99 5 9.5S7.01 5 9.5 5 14 7.01 14 9.5 11.99 14 9.5 14z"/></svg>
-            </button>
-        </div>
-
-        <div class="volume-container">
-            <button id="muteBtn" style="width: 30px; height: 30px; background: none; margin: 0; padding: 0;">
-                <svg id="muteIcon" viewBox="0 0 24 24"><path d="M3 9v6h4l5 5V4L7 9H3zm13.5 3c0-1.77-1.02-3.29-2.5-4.03v8.05c1.48-.73 2.5-2.25 2.5-4.02zM14 3.23v2.06c2.89.86 5 3.54 5 6.71s-2.11 5.85-5 6.71v2.06c4.01-.91 7-4.49 7-8.77s-2.99-7.86-7-8.77z"/></svg>
-            </button>
-            <input type="range" id="volumeSlider" min="0" max="1" step="0.01" value="1">
-        </div>
-
-        <div class="history-container">
-            <div class="history-title">Recently Played</div>
-            <ul class="history-list" id="historyList"></ul>
-        </div>
-        
-        <audio id="audio" crossorigin="anonymous" src="/radio"></audio>
-    </div>
-
-    <div class="search-modal" id="searchModal">
-        <div class="search-header">
-            <button class="close-btn" id="closeSearchBtn">&times;</button>
-        </div>
-        <input type="text" id="searchInput" placeholder="Search artist, title...">
-        <div id="searchResults"></div>
-    </div>
-
-    <script>
-        const audio = document.getElementById('audio');
-        const playBtn = document.getElementById('playBtn');
-        const stopBtn = document.getElementById('stopBtn');
-        const skipBtn = document.getElementById('skipBtn');
-        const shuffleBtn = document.getElementById('shuffleBtn');
-        const muteBtn = document.getElementById('muteBtn');
-        const muteIcon = document.getElementById('muteIcon');
-        const status = document.getElementById('status');
-        const canvas = document.getElementById('visualizer');
-        const trackTitle = document.getElementById('trackTitle');
-        const trackArtist = document.getElementById('trackArtist');
-        const currentTime = document.getElementById('currentTime');
-        const totalTime = document.getElementById('totalTime');
-        const progressFill = document.getElementById('progressFill');
-        const volumeSlider = document.getElementById('volumeSlider');
-        const searchBtn = document.getElementById('searchBtn');
-        const searchModal = document.getElementById('searchModal');
-        const closeSearchBtn = document.getElementById('closeSearchBtn');
-        const searchInput = document.getElementById('searchInput');
-        const searchResults = document.getElementById('searchResults');
-        const ctx = canvas.getContext('2d');
-        const historyList = document.getElementById('historyList');
-
-        // Icons
-        const playIcon = '<svg viewBox="0 0 24 24"><path d="M8 5v14l11-7z"/></svg>';
-        const pauseIcon = '<svg viewBox="0 0 24 24"><path d="M6 19h4V5H6v14zm8-14v14h4V5h-4z"/></svg>';
-        const volOnIcon = '<path d="M3 9v6h4l5 5V4L7 9H3zm13.5 3c0-1.77-1.02-3.29-2.5-4.03v8.05c1.48-.73 2.5-2.25 2.5-4.02zM14 3.23v2.06c2.89.86 5 3.54 5 6.71s-2.11 5.85-5 6.71v2.06c4.01-.91 7-4.49 7-8.77s-2.99-7.86-7-8.77z"/>';
-        const volOffIcon = '<path d="M16.5 12c0-1.77-1.02-3.29-2.5-4.03v2.21l2.45 2.45c.03-.2.05-.41.05-.63zm2.5 0c0 .94-.2 1.82-.54 2.64l1.51 1.51C20.63 14.91 21 13.5 21 12c0-4.28-2.99-7.86-7-8.77v2.06c2.89.86 5 3.54 5 6.71zM4.27 3L3 4.27 7.73 9H3v6h4l5 5v-6.73l4.25 4.25c-.67.52-1.42.93-2.25 1.18v2.06c1.38-.31 2.63-.95 3.69-1.81L19.73 21 21 19.73l-9-9L4.27 3zM12 4L9.91 6.09 12 8.18V4z"/>';
-
-        // Audio Context for Visualizer
-        let audioCtx, analyser, source;
-        let isInit = false;
-        
-        let trackDuration = 0;
-        let trackStartLocal = 0;
-        let currentTrackKey = null;
-        let isShuffle = true;
-
-        // Client ID (Stable across sessions/skips)
-        const clientId = localStorage.getItem('plex_radio_client_id') || Math.random().toString(36).substring(2, 15);
-        localStorage.setItem('plex_radio_client_id', clientId);
-        // Session ID for this client
-        let sessionId = Math.random().toString(36).substring(2, 15);
-        audio.src = `/radio?session=${sessionId}&client_id=${clientId}`;
-
-        function initAudio() {
-            if (isInit) return;
-            isInit = true;
-            
-            const AudioContext = window.AudioContext || window.webkitAudioContext;
-            audioCtx = new AudioContext();
-            analyser = audioCtx.createAnalyser();
-            analyser.fftSize = 256;
-            
-            source = audioCtx.createMediaElementSource(audio);
-            source.connect(analyser);
-            analyser.connect(audioCtx.destination);
-            
-            drawVisualizer();
+        Err(e) => {
+            warn!("Could not switch to Plex Home user {}: {}. Using PLEX_TOKEN instead.", user_id, e);
+            admin_token
         }
+    }
+}
 
-        function drawVisualizer() {
-            requestAnimationFrame(drawVisualizer);
-            
-            const bufferLength = analyser.frequencyBinCount;
-            const dataArray = new Uint8Array(bufferLength);
-            analyser.getByteFrequencyData(dataArray);
+/// A server (or other device) on the account, from plex.tv's resources list.
+#[derive(Deserialize, Debug)]
+struct PlexResource {
+    name: String,
+    #[serde(default)]
+    provides: String,
+    #[serde(default)]
+    connections: Vec<PlexConnection>,
+}
 
-            ctx.fillStyle = '#000';
-            ctx.fillRect(0, 0, canvas.width, canvas.height);
+#[derive(Deserialize, Debug)]
+struct PlexConnection {
+    uri: String,
+    #[serde(default)]
+    local: bool,
+    #[serde(default)]
+    relay: bool,
+}
 
-            const barWidth = (canvas.width / bufferLength) * 2.5;
-            let barHeight;
-            let x = 0;
+/// Finds the URL of the server named `server_name` (PLEX_SERVER_NAME) on the account:
+/// GET {plex.tv}/api/v2/resources, then probes each connection's `/identity`, trying
+/// local addresses first, then remote ones, and the plex.tv relay last. The probes go
+/// through `server_client`, so they carry the same headers (PLEX_EXTRA_HEADERS) as every
+/// other request to the server.
+async fn discover_server_url(
+    client: &Client,
+    server_client: &Client,
+    plex_client: &PlexClientInfo,
+    plex_tv: &str,
+    token: &PlexToken,
+    server_name: &str,
+) -> anyhow::Result<String> {
+    let url = format!("{}/api/v2/resources", plex_tv.trim_end_matches('/'));
 
-            for(let i = 0; i < bufferLength; i++) {
-                barHeight = dataArray[i] / 2;
-                ctx.fillStyle = `rgb(${barHeight + 100}, 160, 13)`; // Plex Orange-ish
-                ctx.fillRect(x, canvas.height - barHeight, barWidth, barHeight);
-                x += barWidth + 1;
-            }
-        }
+    let resources = client
+        .get(&url)
+        .header("X-Plex-Token", &token.value)
+        .header("X-Plex-Client-Identifier", &plex_client.identifier)
+        .header("X-Plex-Product", &plex_client.product)
+        .header("Accept", "application/json")
+        .query(&[("includeHttps", "1"), ("includeRelay", "1")])
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Vec<PlexResource>>()
+        .await?;
 
-        // Controls
-        playBtn.addEventListener('click', () => {
-            initAudio();
-            if (audioCtx && audioCtx.state === 'suspended') audioCtx.resume();
+    let server = resources
+        .into_iter()
+        .filter(|r| r.provides.split(',').any(|p| p == "server"))
+        .find(|r| r.name.eq_ignore_ascii_case(server_name))
+        .ok_or_else(|| anyhow::anyhow!("no server named '{}' on this Plex account", server_name))?;
 
-            if (!audio.src || audio.src === window.location.href) {
-                playStream();
-                return;
+    let mut connections = server.connections;
+    connections.sort_by_key(|c| (c.relay, !c.local));
+    // Kept so a server that is still booting fails as unreachable, which is retried
+    let mut last_error = None;
+    for connection in &connections {
+        let uri = connection.uri.trim_end_matches('/');
+        let probe = server_client
+            .get(format!("{}/identity", uri))
+            .plex_token(token)
+            .header("X-Plex-Client-Identifier", &plex_client.identifier)
+            .header("X-Plex-Product", &plex_client.product)
+            .timeout(Duration::from_secs(3))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        match probe {
+            Ok(_) => {
+                info!("Reached '{}' at {}", server.name, uri);
+                return Ok(uri.to_string());
             }
-
-            if (audio.paused) {
-                audio.play().catch(e => {
-                    status.textContent = "Error: " + e.message;
-                });
-            } else {
-                audio.pause();
+            Err(e) => {
+                let e = e.without_url();
+                debug!("Server '{}' is not reachable at {}: {}", server.name, uri, e);
+                last_error = Some(e);
             }
-        });
+        }
+    }
+    let message = format!("none of the {} connections of '{}' answered", connections.len(), server.name);
+    Err(match last_error {
+        Some(e) => anyhow::Error::new(e).context(message),
+        None => anyhow::anyhow!(message),
+    })
+}
 
-        stopBtn.addEventListener('click', () => {
-            audio.pause();
-            audio.removeAttribute('src');
-            status.textContent = "Stopped";
-            playBtn.innerHTML = playIcon;
-            trackTitle.textContent = "Stopped";
-            trackArtist.textContent = "";
-            trackDuration = 0;
-            currentTrackKey = null;
-            updateProgressUI(0, 0);
-            
-            ctx.fillStyle = '#000';
-            ctx.fillRect(0, 0, canvas.width, canvas.height);
-        });
+/// Exchanges the admin token for a Home user's token:
+/// POST {plex.tv}/api/home/users/{id}/switch
+async fn switch_home_user(
+    client: &Client,
+    plex_client: &PlexClientInfo,
+    plex_tv: &str,
+    admin_token: &str,
+    user_id: &str,
+    pin: Option<&str>,
+) -> anyhow::Result<String> {
+    let url = format!("{}/api/home/users/{}/switch", plex_tv.trim_end_matches('/'), url_encode(user_id));
 
-        skipBtn.addEventListener('click', () => {
-            status.textContent = "Skipping...";
-            // Reload the source to trigger a new stream connection (new random song)
-            playStream();
-        });
+    let mut request = client
+        .post(&url)
+        .header("X-Plex-Token", admin_token)
+        .header("X-Plex-Client-Identifier", &plex_client.identifier)
+        .header("X-Plex-Product", &plex_client.product)
+        .header("Accept", "application/json");
+    if let Some(pin) = pin {
+        request = request.query(&[("pin", pin)]);
+    }
 
-        shuffleBtn.addEventListener('click', () => {
-            isShuffle = !isShuffle;
-            shuffleBtn.style.opacity = isShuffle ? '1' : '0.5';
-            playStream();
-        });
+    let body = request
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<serde_json::Value>()
+        .await?;
 
-        document.querySelector('.progress-bar').addEventListener('click', (e) => {
-            if (!trackDuration || !currentTrackKey) return;
-            const rect = e.currentTarget.getBoundingClientRect();
-            const x = e.clientX - rect.left;
-            const pct = Math.max(0, Math.min(1, x / rect.width));
-            const seekTime = Math.floor(pct * trackDuration);
-            
-            status.textContent = "Seeking...";
-            playStream(`&track=${currentTrackKey}&offset=${seekTime}`);
-        });
+    // Older responses use `authenticationToken`, newer ones `authToken`
+    ["authenticationToken", "authToken"]
+        .iter()
+        .find_map(|field| body.get(field).and_then(|v| v.as_str()))
+        .map(|token| token.to_string())
+        .ok_or_else(|| anyhow::anyhow!("plex.tv response did not include a token"))
+}
 
-        volumeSlider.addEventListener('input', (e) => {
-            audio.volume = e.target.value;
-        });
+/// A client for audio streams: no total timeout, which would cut songs off mid-play,
+/// only limits on connecting and on how long Plex may go without sending anything.
+fn stream_client_builder(connect_timeout: Duration, read_timeout: Duration) -> reqwest::ClientBuilder {
+    Client::builder()
+        .connect_timeout(connect_timeout)
+        .read_timeout(read_timeout)
+}
 
-        muteBtn.addEventListener('click', () => {
-            audio.muted = !audio.muted;
-            muteIcon.innerHTML = audio.muted ? volOffIcon : volOnIcon;
-            muteBtn.style.opacity = audio.muted ? '0.5' : '1';
-        });
+/// The token requests to the Plex server are made with, and how it is sent.
+#[derive(Clone, Debug)]
+struct PlexToken {
+    value: String,
+    // Also send it as an `X-Plex-Token` query param (TOKEN_IN_QUERY), for proxies
+    // that strip custom headers
+    in_query: bool,
+}
 
-        // Search Logic
-        searchBtn.addEventListener('click', () => {
-            searchModal.classList.add('open');
-            searchInput.focus();
-        });
+/// Authenticates a request to the Plex server.
+trait PlexAuth {
+    fn plex_token(self, token: &PlexToken) -> Self;
+}
 
-        closeSearchBtn.addEventListener('click', () => {
-            searchModal.classList.remove('open');
-        });
+impl PlexAuth for reqwest::RequestBuilder {
+    fn plex_token(self, token: &PlexToken) -> Self {
+        let request = self.header("X-Plex-Token", &token.value);
+        if token.in_query {
+            request.query(&[("X-Plex-Token", &token.value)])
+        } else {
+            request
+        }
+    }
+}
 
-        let searchTimeout;
-        searchInput.addEventListener('input', (e) => {
-            clearTimeout(searchTimeout);
-            searchTimeout = setTimeout(() => {
-                const q = e.target.value;
-                if (q.length < 2) {
-                    searchResults.innerHTML = '';
-                    return;
-                }
-                fetch(`/search?q=${encodeURIComponent(q)}`)
-                    .then(r => r.json())
-                    .then(tracks => {
-                        searchResults.innerHTML = tracks.map(t => `
-                            <div class="result-item" onclick="playTrack('${t.key}')">
-                                <div class="result-info">
-                                    <div class="result-title">${t.title}</div>
-                                    <div class="result-artist">${t.artist}</div>
-                                </div>
-                                <div class="result-duration">${formatTime(t.duration)}</div>
-                            </div>
-                        `).join('');
-                    });
-            }, 300);
-        });
+/// Consumes session IDs from torn-down streams and tells Plex to stop their transcodes.
+/// Without this, the transcoder keeps running server-side until Plex times it out.
+async fn stop_transcode_sessions(
+    client: Client,
+    base_url: String,
+    token: PlexToken,
+    mut rx: mpsc::UnboundedReceiver<String>,
+) {
+    while let Some(session_id) = rx.recv().await {
+        stop_transcode(&client, &base_url, &token, &session_id).await;
+    }
+}
 
-        window.playTrack = function(key) {
-            searchModal.classList.remove('open');
-            playStream(`&track=${key}`);
-        };
+/// Asks Plex to end a session's transcode.
+async fn stop_transcode(client: &Client, base_url: &str, token: &PlexToken, session_id: &str) {
+    let result = client
+        .get(format!("{}/music/:/transcode/universal/stop", base_url))
+        .plex_token(token)
+        .query(&[("session", session_id)])
+        .send()
+        .await;
 
-        function playStream(params = '') {
-            // Generate new session ID for every request to avoid race conditions
-            sessionId = Math.random().toString(36).substring(2, 15);
-            audio.src = `/radio?session=${sessionId}&client_id=${clientId}&shuffle=${isShuffle}${params}&t=${Date.now()}`;
-            audio.play();
+    match result {
+        Ok(resp) if resp.status().is_success() => {
+            info!("Stopped Plex transcode session {}", session_id);
         }
+        Ok(resp) => warn!("Plex refused to stop transcode session {}: {}", session_id, resp.status()),
+        Err(e) => warn!("Failed to stop transcode session {}: {}", session_id, e),
+    }
+}
 
-        // Events
-        audio.addEventListener('play', () => {
-            playBtn.innerHTML = pauseIcon;
-            status.textContent = "Streaming...";
-        });
-        
-        audio.addEventListener('pause', () => {
-            playBtn.innerHTML = playIcon;
-            status.textContent = "Paused";
-        });
+// --- Disk Cache ---
 
-        audio.addEventListener('error', (e) => {
-            if (!audio.getAttribute('src')) return;
-            status.textContent = "Stream Error. Retrying...";
-            setTimeout(() => skipBtn.click(), 2000);
-        });
+/// Read size when serving a track from the disk cache.
+const DISK_CACHE_READ_CHUNK: usize = 64 * 1024;
 
-        // Canvas sizing
-        function resizeCanvas() {
-            canvas.width = canvas.offsetWidth;
-            canvas.height = canvas.offsetHeight;
-        }
-        window.addEventListener('resize', resizeCanvas);
-        resizeCanvas();
-
-        // Poll Metadata
-        setInterval(() => {
-            if (!audio.paused) {
-                fetch(`/now-playing?session=${sessionId}&client_id=${clientId}`)
-                    .then(r => {
-                        if (r.ok) return r.json();
-                        throw new Error('No track');
-                    })
-                    .then(data => {
-                        trackTitle.textContent = data.title;
-                        trackArtist.textContent = data.artist;
-                        trackDuration = data.duration || 0;
-                        currentTrackKey = data.key;
-                        // Sync local time based on server elapsed
-                        trackStartLocal = Date.now() - (data.elapsed || 0);
-                        totalTime.textContent = formatTime(trackDuration);
-                        
-                        // Update History
-                        if (data.history) {
-                            historyList.innerHTML = data.history.map(t => `
-                                <li class="history-item" onclick="playTrack('${t.key}')">
-                                    <span class="hist-title">${t.title}</span>
-                                    <span class="hist-artist">${t.artist}</span>
-                                </li>
-                            `).join('');
-                        }
-                    }).catch(() => {});
+/// Finished transcodes kept on disk (DISK_CACHE_DIR), so the tracks a small station
+/// plays over and over are only transcoded once. When the files outgrow `max_bytes`,
+/// the least recently played ones are deleted.
+struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: std::sync::Mutex<DiskCacheIndex>,
+}
+
+#[derive(Default)]
+struct DiskCacheIndex {
+    // File name -> (size in bytes, last use); a higher tick is a more recent use
+    entries: HashMap<String, (u64, u64)>,
+    total_bytes: u64,
+    tick: u64,
+}
+
+impl DiskCache {
+    /// Creates the directory if needed and indexes the files already in it, oldest
+    /// first. Partial files left behind by an earlier run are deleted.
+    async fn open(dir: PathBuf, max_bytes: u64) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(&dir).await?;
+        let mut files = Vec::new();
+        let mut listing = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = listing.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".part") {
+                let _ = tokio::fs::remove_file(entry.path()).await;
+                continue;
+            }
+            let meta = entry.metadata().await?;
+            if meta.is_file() && name.ends_with(".mp3") {
+                files.push((meta.modified().unwrap_or(SystemTime::UNIX_EPOCH), name, meta.len()));
             }
-        }, 2000);
+        }
+        files.sort();
 
-        function updateProgressBar() {
-            requestAnimationFrame(updateProgressBar);
-            if (!trackDuration || audio.paused) return;
-            const elapsed = Date.now() - trackStartLocal;
-            updateProgressUI(elapsed, trackDuration);
+        let cache = DiskCache { dir, max_bytes, index: Default::default() };
+        if let Ok(mut index) = cache.index.lock() {
+            for (_, name, size) in files {
+                index.tick += 1;
+                let tick = index.tick;
+                index.entries.insert(name, (size, tick));
+                index.total_bytes += size;
+            }
         }
-        updateProgressBar();
+        // The limit may have been lowered since the last run
+        for path in cache.evict() {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        Ok(cache)
+    }
+
+    fn used_bytes(&self) -> u64 {
+        self.index.lock().map(|index| index.total_bytes).unwrap_or(0)
+    }
+
+    /// Cache files are named by an FNV-1a hash of the transcode key.
+    fn file_name(key: &str) -> String {
+        let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+        format!("{:016x}.mp3", hash)
+    }
+
+    /// Path of the cached transcode for `key`, if there is one, marking it as used.
+    fn lookup(&self, key: &str) -> Option<PathBuf> {
+        let name = Self::file_name(key);
+        let mut index = self.index.lock().ok()?;
+        index.tick += 1;
+        let tick = index.tick;
+        index.entries.get_mut(&name)?.1 = tick;
+        Some(self.dir.join(name))
+    }
+
+    /// Starts writing a transcode to a temporary file. Each writer gets its own file, so
+    /// sessions playing the same track at once don't interfere.
+    async fn writer(&self, key: &str) -> std::io::Result<CacheWriter> {
+        let name = Self::file_name(key);
+        let part = self.dir.join(format!("{}.{:08x}.part", name, rand::thread_rng().gen::<u32>()));
+        let file = tokio::fs::File::create(&part).await?;
+        Ok(CacheWriter { file, part: Some(part), name, bytes: 0 })
+    }
+
+    /// Moves a complete transcode into the cache, then evicts whatever no longer fits.
+    async fn commit(&self, mut writer: CacheWriter) -> std::io::Result<()> {
+        writer.file.flush().await?;
+        let Some(part) = writer.part.take() else { return Ok(()) };
+        tokio::fs::rename(&part, self.dir.join(&writer.name)).await?;
 
-        function updateProgressUI(elapsed, duration) {
-            const pct = duration > 0 ? Math.min(100, (elapsed / duration) * 100) : 0;
-            progressFill.style.width = `${pct}%`;
-            currentTime.textContent = formatTime(elapsed);
+        if let Ok(mut index) = self.index.lock() {
+            index.tick += 1;
+            let tick = index.tick;
+            if let Some((old_size, _)) = index.entries.insert(writer.name.clone(), (writer.bytes, tick)) {
+                index.total_bytes -= old_size;
+            }
+            index.total_bytes += writer.bytes;
+        }
+        for path in self.evict() {
+            debug!("Evicting {} from the disk cache", path.display());
+            let _ = tokio::fs::remove_file(path).await;
         }
+        Ok(())
+    }
 
-        function formatTime(ms) {
-            if (!ms || ms < 0) return "0:00";
-            const s = Math.floor(ms / 1000);
-            return `${Math.floor(s / 60)}:${(s % 60).toString().padStart(2, '0')}`;
+    /// Drops the least recently used entries from the index until the cache fits, and
+    /// returns their files for the caller to delete.
+    fn evict(&self) -> Vec<PathBuf> {
+        let Ok(mut index) = self.index.lock() else { return Vec::new() };
+        let mut evicted = Vec::new();
+        while index.total_bytes > self.max_bytes {
+            let Some(name) = index.entries.iter().min_by_key(|(_, (_, used))| *used).map(|(name, _)| name.clone()) else {
+                break;
+            };
+            if let Some((size, _)) = index.entries.remove(&name) {
+                index.total_bytes -= size;
+            }
+            evicted.push(self.dir.join(name));
         }
-    </script>
-</body>
-</html>
-    "#)
+        evicted
+    }
 }
 
-// --- Streaming Handler ---
+/// A transcode being written to the disk cache. Unless it is committed, the partial
+/// file is deleted on drop (failed tracks, skips, disconnects).
+struct CacheWriter {
+    file: tokio::fs::File,
+    // None once the file has been moved into the cache
+    part: Option<PathBuf>,
+    name: String,
+    bytes: u64,
+}
 
-struct SessionGuard {
-    id: String,
-    sessions: Arc<std::sync::Mutex<HashMap<String, (Track, SystemTime)>>>,
+impl CacheWriter {
+    async fn write(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(chunk).await?;
+        self.bytes += chunk.len() as u64;
+        Ok(())
+    }
 }
 
-impl Drop for SessionGuard {
+impl Drop for CacheWriter {
     fn drop(&mut self) {
-        if let Ok(mut map) = self.sessions.lock() {
-            map.remove(&self.id);
+        if let Some(part) = self.part.take() {
+            let _ = std::fs::remove_file(part);
         }
     }
 }
 
-/// Helper to build the Plex request (Passthrough or Transcode)
-/// Separating this logic helps avoid compiler bugs with async-stream macros
-async fn prepare_track_request(
-    state: &AppState,
-    track_key: &str,
-    session_id: &str,
-    offset_ms: u64,
-) -> Option<reqwest::RequestBuilder> {
-    if state.passthrough {
-        // Passthrough: Fetch track metadata to get the actual file path
-        let meta_url = format!("{}/library/metadata/{}", state.plex_url, track_key);
-        let meta_resp = state.client.get(&meta_url)
-            .header("X-Plex-Token", &state.plex_token)
-            .header("Accept", "application/json")
-            .send()
-            .await;
-
-        let part_key = match meta_resp {
-            Ok(r) => match r.json::<TrackContainer>().await {
-                Ok(c) => c.media_container.metadata.first()
-                    .and_then(|m| m.media.first())
-                    .and_then(|media| media.parts.first())
-                    .map(|p| p.key.clone()),
-                Err(_) => None,
-            },
-            Err(_) => None,
-        };
+/// Everything that shapes a transcode's output, so a cached file is only reused for
+/// an identical request.
+fn transcode_cache_key(state: &AppState, track_key: &str, profile: &AudioProfile, media: MediaIndexes) -> String {
+    format!(
+        "{}|{}|{}|{}|{:?}|{:?}|{:?}|{:?}",
+        track_key,
+        profile.bitrate,
+        profile.audio_boost,
+        state.media_version.as_str(),
+        media.media_index,
+        media.part_index,
+        profile.output_format.sample_rate,
+        profile.output_format.channels
+    )
+}
 
-        if let Some(pk) = part_key {
-            let stream_url = format!("{}{}", state.plex_url, pk);
-            Some(state.client.get(&stream_url)
-                .header("X-Plex-Token", &state.plex_token))
-        } else {
-            error!("Failed to resolve file path for passthrough. Skipping.");
-            None
+/// Reads a cached transcode back in chunks.
+fn cached_file_stream(mut file: tokio::fs::File) -> impl Stream<Item = std::io::Result<Bytes>> {
+    async_stream::try_stream! {
+        loop {
+            let mut buf = vec![0u8; DISK_CACHE_READ_CHUNK];
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            buf.truncate(n);
+            yield Bytes::from(buf);
         }
-    } else {
-        // Transcode: Use universal transcoder
-        let base_url = state.plex_url.trim_end_matches('/');
-        let transcode_url = format!("{}/music/:/transcode/universal/start.mp3", base_url);
-        let path_param = format!("{}/library/metadata/{}?X-Plex-Token={}", base_url, track_key, state.plex_token);
-        
-        Some(state.client
-            .get(&transcode_url)
-            .header("X-Plex-Token", &state.plex_token)
-            .header("X-Plex-Client-Identifier", "plex-radio-rust")
-            .header("X-Plex-Product", "Plex Radio")
-            .header("X-Plex-Version", "1.0")
-            .header("X-Plex-Platform", "Generic")
-            .header("X-Plex-Device", "Plex Radio")
-            .header("X-Plex-Session-Id", session_id)
-            .query(&[
-                ("path", path_param),
-                ("mediaIndex", "0".to_string()),
-                ("partIndex", "0".to_string()),
-                ("protocol", "http".to_string()),
-                ("offset", (offset_ms / 1000).to_string()),
-                ("fastSeek", "1".to_string()),
-                ("directPlay", "0".to_string()),
-                ("directStream", "1".to_string()),
-                ("audioBoost", state.audio_boost.to_string()),
-                ("maxAudioBitrate", state.bitrate.to_string()),
-                ("context", "static".to_string()), 
-                ("session", session_id.to_string()),
-            ]))
     }
 }
 
-/// The main handler for the /radio endpoint.
-/// Returns a continuous stream of MP3 data.
-async fn stream_radio(
+// --- Web Interface ---
+
+/// The UI as it was at compile time; always available as a fallback.
+const EMBEDDED_INDEX_HTML: &str = include_str!("../templates/index.html");
+
+/// Controls where the web UI is loaded from.
+#[derive(Clone, Debug)]
+struct WebConfig {
+    /// Template read from disk when hot reload is enabled
+    template_path: PathBuf,
+    /// Re-read the template on every request (default: on in debug builds)
+    hot_reload: bool,
+}
+
+impl WebConfig {
+    fn from_env() -> Self {
+        let template_path = std::env::var("WEB_TEMPLATE_PATH")
+            .unwrap_or_else(|_| "templates/index.html".to_string())
+            .into();
+        let hot_reload = std::env::var("WEB_HOT_RELOAD")
+            .map(|v| v == "true")
+            .unwrap_or(cfg!(debug_assertions));
+        WebConfig { template_path, hot_reload }
+    }
+
+    /// Returns the page, preferring the on-disk template when hot reload is on.
+    async fn index_html(&self) -> Cow<'static, str> {
+        if self.hot_reload {
+            match tokio::fs::read_to_string(&self.template_path).await {
+                Ok(html) => return Cow::Owned(html),
+                Err(e) => warn!(
+                    "Could not read {} ({}), serving embedded UI",
+                    self.template_path.display(),
+                    e
+                ),
+            }
+        }
+        Cow::Borrowed(EMBEDDED_INDEX_HTML)
+    }
+}
+
+/// Serves the UI with an `ETag` of the rendered page, answering `304` when the browser
+/// already has it. The tag is taken per request, so hot-reloaded edits show up at once.
+async fn web_interface(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let html = state.web.index_html().await;
+    let html = html.replace("{{ENABLE_VISUALIZER}}", &state.enable_visualizer.to_string());
+
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    std::hash::Hash::hash(&html, &mut hasher);
+    // Weak, since the compression layer may re-encode the body
+    let etag = format!("W/\"{:016x}\"", std::hash::Hasher::finish(&hasher));
+    let cache_headers = [(header::ETAG, etag.clone()), (header::CACHE_CONTROL, "no-cache".to_string())];
+
+    let fresh = headers.get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"));
+    if fresh {
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+    (cache_headers, Html(html)).into_response()
+}
+
+/// Streaming overlay page, see `overlay`.
+const OVERLAY_HTML: &str = include_str!("../templates/overlay.html");
+
+/// How often (ms) the overlay polls `/now-playing` for track changes.
+const OVERLAY_REFRESH_MS: u32 = 3000;
+
+/// Escapes text for use in HTML content and attribute values.
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Encodes a value as a JS literal that is safe inside a `<script>` block.
+fn script_literal(value: &str) -> String {
+    serde_json::to_string(value).unwrap_or_default().replace('<', "\\u003c")
+}
+
+/// `GET /overlay?session=...&client_id=...`: a transparent "now playing" card (art, title, artist)
+/// for OBS browser sources. It is rendered with the session's current track and then
+/// keeps itself up to date from `/now-playing`.
+#[utoipa::path(
+    get,
+    path = "/overlay",
+    params(
+        ("session" = String, Query, description = "Session id of the stream to follow"),
+        ("client_id" = Option<String>, Query, description = "client_id the stream was opened with"),
+    ),
+    responses(
+        (status = 200, description = "Overlay page", content_type = "text/html"),
+        (status = 400, description = "Missing session", body = ErrorBody),
+    ),
+)]
+async fn overlay(
     State(state): State<AppState>,
     Query(params): Query<HashMap<String, String>>,
-) -> impl IntoResponse {
-    // Create an async stream that yields Bytes
-    let stream = async_stream::try_stream! {
-        // Use provided session ID or generate one
-        let session_id = params.get("session").cloned().unwrap_or_else(|| {
-            format!("radio-{:x}", rand::thread_rng().gen::<u64>())
-        });
-        let client_id = params.get("client_id").cloned().unwrap_or_else(|| "anon".to_string());
-        
-        let mut initial_track_key = params.get("track").cloned();
-        let mut initial_offset_ms = params.get("offset")
-            .and_then(|s| s.parse::<u64>().ok())
-            .unwrap_or(0);
-        
-        let shuffle_mode = params.get("shuffle").map(|s| s != "false").unwrap_or(true);
+) -> Result<Html<String>, ApiError> {
+    let session_id = params.get("session").filter(|s| !s.is_empty())
+        .ok_or_else(|| ApiError::bad_request("Missing `session`"))?;
+    let client_id = params.get("client_id").map(|s| s.as_str()).unwrap_or("anon");
+    let track = owned_session(&state.sessions.lock().unwrap(), session_id, client_id)
+        .map(|s| s.track.clone());
 
-        // RAII Guard to clean up session on disconnect
-        let _guard = SessionGuard {
-            id: session_id.clone(),
-            sessions: state.sessions.clone(),
+    // A session that hasn't started yet renders hidden and shows up on the first poll
+    let (title, artist, key) = match &track {
+        Some(t) => (t.title.as_str(), t.artist.as_str(), Some(t.key.as_str())),
+        None => ("", "", None),
+    };
+    let art_url = key.map(|k| format!("/track/art?key={}", url_encode(k))).unwrap_or_default();
+    let html = OVERLAY_HTML
+        .replace("{{HIDDEN}}", if track.is_some() { "" } else { "hidden" })
+        .replace("{{ART_URL}}", &html_escape(&art_url))
+        .replace("{{TITLE}}", &html_escape(title))
+        .replace("{{ARTIST}}", &html_escape(artist))
+        .replace("{{SESSION}}", &script_literal(session_id))
+        .replace("{{CLIENT_ID}}", &script_literal(client_id))
+        .replace("{{KEY}}", &key.map(script_literal).unwrap_or_else(|| "null".to_string()))
+        .replace("{{REFRESH_MS}}", &OVERLAY_REFRESH_MS.to_string());
+    Ok(Html(html))
+}
+
+/// Lets pages on other origins (e.g. a custom overlay) read the response.
+async fn allow_any_origin(mut response: Response) -> Response {
+    response.headers_mut().insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        header::HeaderValue::from_static("*"),
+    );
+    response
+}
+
+/// What STRICT_PARAMS expects of a query param that handlers otherwise fall back on a
+/// default for. Params that are already rejected when malformed aren't listed.
+enum ParamRule {
+    Number,
+    Bitrate,
+    OneOf(&'static [&'static str]),
+}
+
+const STRICT_PARAM_RULES: &[(&str, ParamRule)] = &[
+    ("offset", ParamRule::Number),
+    ("count", ParamRule::Number),
+    ("limit", ParamRule::Number),
+    ("bitrate", ParamRule::Bitrate),
+    ("shuffle", ParamRule::OneOf(&["true", "false"])),
+    ("shuffle_mode", ParamRule::OneOf(&["bag"])),
+    ("loop", ParamRule::OneOf(&["true", "false"])),
+    ("continue_on_error", ParamRule::OneOf(&["true", "false"])),
+    ("clean", ParamRule::OneOf(&["true", "false", "1", "0"])),
+    ("lenient", ParamRule::OneOf(&["true", "false", "1", "0"])),
+    ("start_paused", ParamRule::OneOf(&["true", "false", "1", "0"])),
+    ("skip", ParamRule::OneOf(&["true", "false", "1", "0"])),
+    ("format", ParamRule::OneOf(&["json", "csv", "text", "txt", "tsv"])),
+];
+
+/// Checks one query param against `STRICT_PARAM_RULES`; unlisted params always pass.
+fn check_strict_param(name: &str, value: &str) -> Result<(), String> {
+    let Some((_, rule)) = STRICT_PARAM_RULES.iter().find(|(n, _)| *n == name) else {
+        return Ok(());
+    };
+    match rule {
+        ParamRule::Number if value.parse::<u64>().is_err() => {
+            Err(format!("`{}` must be a non-negative number, got '{}'", name, value))
+        }
+        ParamRule::Bitrate if !value.parse::<u32>().is_ok_and(|b| ALLOWED_BITRATES.contains(&b)) => {
+            let allowed: Vec<String> = ALLOWED_BITRATES.iter().map(|b| b.to_string()).collect();
+            Err(format!("`{}` must be one of {}, got '{}'", name, allowed.join(", "), value))
+        }
+        ParamRule::OneOf(allowed) if !allowed.contains(&value) => {
+            Err(format!("`{}` must be one of {}, got '{}'", name, allowed.join(", "), value))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// STRICT_PARAMS: answers `400` for a query string handlers would otherwise quietly
+/// make the best of (an unparsable `offset` playing from the start, and so on).
+async fn reject_malformed_params(request: axum::extract::Request, next: axum::middleware::Next) -> Response {
+    let pairs = match Query::<Vec<(String, String)>>::try_from_uri(request.uri()) {
+        Ok(Query(pairs)) => pairs,
+        Err(_) => return ApiError::bad_request("Malformed query string").into_response(),
+    };
+    for (name, value) in &pairs {
+        if let Err(message) = check_strict_param(name, value) {
+            return ApiError::bad_request(message).into_response();
+        }
+    }
+    next.run(request).await
+}
+
+// --- API Errors ---
+
+/// Error returned by the HTTP API, serialized as `{ "error", "code", "message" }`.
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    error: &'static str,
+    message: String,
+    // Seconds for a `Retry-After` header, for temporary failures
+    retry_after: Option<u64>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, error: &'static str, message: impl Into<String>) -> Self {
+        ApiError { status, error, message: message.into(), retry_after: None }
+    }
+
+    /// The track cache is still loading; clients should try again shortly.
+    fn warming_up() -> Self {
+        ApiError {
+            retry_after: Some(5),
+            ..Self::new(StatusCode::SERVICE_UNAVAILABLE, "warming_up", "The library is still loading")
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message)
+    }
+
+    fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", message)
+    }
+
+    fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    fn conflict(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, "conflict", message)
+    }
+
+    fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, "unavailable", message)
+    }
+
+    fn bad_gateway(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, "upstream_error", message)
+    }
+}
+
+/// The JSON body of an `ApiError`.
+#[derive(Serialize, ToSchema)]
+struct ErrorBody {
+    /// Machine-readable error kind, e.g. `not_found`
+    error: &'static str,
+    /// The HTTP status code
+    code: u16,
+    message: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.error,
+            code: self.status.as_u16(),
+            message: self.message,
         };
+        let mut resp = (self.status, Json(body)).into_response();
+        if let Some(secs) = self.retry_after {
+            resp.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(secs));
+        }
+        resp
+    }
+}
 
-        let mut current_track_index: Option<usize> = None;
+// --- API Description ---
 
-        // Infinite loop: Pick a song, stream it, repeat.
-        loop {
-            // 1. Pick a random track
-            let mut is_specific_request = false;
-            let track = if let Some(key) = initial_track_key.take() {
-                is_specific_request = true;
-                if let Some(idx) = state.tracks.iter().position(|t| t.key == *key) {
-                    current_track_index = Some(idx);
-                    state.tracks[idx].clone()
-                } else {
-                    // Fallback if key not found
-                    let mut rng = rand::thread_rng();
-                    let idx = rng.gen_range(0..state.tracks.len());
-                    current_track_index = Some(idx);
-                    state.tracks[idx].clone()
-                }
-            } else {
-                if shuffle_mode {
-                    let mut rng = rand::thread_rng();
-                    let idx = rng.gen_range(0..state.tracks.len());
-                    current_track_index = Some(idx);
-                    state.tracks[idx].clone()
-                } else {
-                    let next_idx = match current_track_index {
-                        Some(i) => (i + 1) % state.tracks.len(),
-                        None => rand::thread_rng().gen_range(0..state.tracks.len()),
-                    };
-                    current_track_index = Some(next_idx);
-                    state.tracks[next_idx].clone()
-                }
-            };
+/// The OpenAPI description served at `/openapi.json`, for third-party clients.
+/// Each handler carries its own `#[utoipa::path]`, so keep those in step with the routes.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Plex Radio", description = "Endless radio streams from a Plex music library"),
+    paths(
+        stream_radio, stream_album, stream_playlist, stream_auto,
+        now_playing, now_playing_text, now_playing_events, overlay, pause_session, resume_session, resume_info, upcoming_tracks,
+        queue_info, enqueue_track, clear_queue,
+        search_tracks, browse_tracks, browse_artists, browse_albums, random_track, track_info, track_art,
+        block_item, unblock_item, submit_feedback, client_preferences,
+        client_config, library_stats, version_info, refresh_library, stop_all_streams, debug_transcode,
+    ),
+    components(schemas(Track, TrackDetails, ArtistSummary, AlbumSummary, StationPreferences, ErrorBody)),
+    modifiers(&AdminTokenScheme),
+)]
+struct ApiDoc;
 
-            let track_key = track.key.clone();
-            info!("Now Playing: {} - {}", track.artist, track.title);
+/// Declares the `Authorization: Bearer <ADMIN_TOKEN>` scheme the /admin endpoints use.
+struct AdminTokenScheme;
 
-            // 2. Determine Stream URL (Passthrough vs Transcode)
-            let request_opt = prepare_track_request(&state, &track_key, &session_id, initial_offset_ms).await;
-            
-            let request = match request_opt {
-                Some(req) => req,
-                None => {
-                    if is_specific_request { break; }
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                    continue;
-                }
-            };
-            
-            // 3. Execute Request
+impl utoipa::Modify for AdminTokenScheme {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+        openapi.components.get_or_insert_with(Default::default).add_security_scheme(
+            "admin_token",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
 
-            // Execute request
-            let response = match request.send().await {
-                Ok(resp) => resp,
-                Err(e) => {
-                    error!("Failed to fetch track from Plex: {}", e);
-                    if is_specific_request { break; } // Don't fallback to random if specific track failed
-                    tokio::time::sleep(Duration::from_secs(5)).await;
-                    continue; // Skip to next track on error
-                }
-            };
+// --- Streaming Handler ---
 
-            if !response.status().is_success() {
-                warn!("Plex returned non-success status: {}", response.status());
-                if is_specific_request { break; } // Don't fallback to random if specific track failed
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                continue;
-            }
+struct SessionGuard {
+    id: String,
+    // Matches `SessionControl::stream_id` while this stream still holds the session
+    stream_id: u64,
+    sessions: Arc<std::sync::Mutex<HashMap<String, SessionInfo>>>,
+    session_controls: Arc<std::sync::Mutex<HashMap<String, SessionControl>>>,
+    queues: Arc<std::sync::Mutex<HashMap<String, VecDeque<Track>>>>,
+    track_changes: broadcast::Sender<String>,
+    // Set when the session uses the transcoder, so Plex can be told to stop it
+    transcode_stop_tx: Option<mpsc::UnboundedSender<String>>,
+    // Totals for the access log line written on teardown
+    client_id: String,
+    opened_at: Instant,
+    bytes_sent: u64,
+    tracks_played: u32,
+}
 
-            // Update session state (Metadata) only after successful connection
-            if let Ok(mut map) = state.sessions.lock() {
-                // If seeking, adjust start time so elapsed calculation is correct
-                let start_time = SystemTime::now() - Duration::from_millis(initial_offset_ms);
-                map.insert(session_id.clone(), (track.clone(), start_time));
-            }
-            
-            // Update History (Add current track to history list)
-            if let Ok(mut history_map) = state.history.lock() {
-                let list = history_map.entry(client_id.clone()).or_default();
-                list.insert(0, track.clone());
-                if list.len() > 10 {
-                    list.pop();
-                }
-            }
+impl SessionGuard {
+    /// Whether a newer stream of the same client took the session over. Its entries
+    /// (and its Plex transcode, which shares the session id) are then not ours to touch.
+    fn superseded(&self) -> bool {
+        self.session_controls.lock()
+            .is_ok_and(|controls| controls.get(&self.id).is_some_and(|c| c.stream_id != self.stream_id))
+    }
+}
 
-            // 4. Pipe the bytes to the listener
-            let mut byte_stream = response.bytes_stream();
-            let mut bytes_sent = 0;
-            let stream_start = SystemTime::now();
-            while let Some(chunk) = futures::StreamExt::next(&mut byte_stream).await {
-                match chunk {
-                    Ok(bytes) => { bytes_sent += bytes.len(); yield bytes; },
-                    Err(e) => {
-                        error!("Error reading bytes from Plex: {}", e);
-                        break; // Break inner loop to pick new song (or disconnect)
-                    }
-                }
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        // The guard is created before any Plex request, so by the time it drops the
+        // response body (declared later in the stream) has already been released.
+        let superseded = self.superseded();
+        if superseded {
+            info!("Session {} was taken over by a newer stream of {}", self.id, self.client_id);
+        } else {
+            let track = self.sessions.lock().ok().and_then(|mut map| map.remove(&self.id));
+            if let Ok(mut controls) = self.session_controls.lock() {
+                controls.remove(&self.id);
             }
-            
-            // Check for rapid failure (empty stream or very short duration)
-            if bytes_sent < 1024 || stream_start.elapsed().unwrap_or(Duration::from_secs(0)) < Duration::from_secs(2) {
-                warn!("Track finished too quickly ({} bytes). Possible transcoding error or empty file.", bytes_sent);
-                tokio::time::sleep(Duration::from_secs(5)).await;
+            if let Ok(mut queues) = self.queues.lock() {
+                queues.remove(&self.id);
             }
-            
-            // Reset offset for subsequent tracks in the playlist
-            initial_offset_ms = 0;
+            let _ = self.track_changes.send(self.id.clone());
+            match track {
+                Some(info) => info!("Session {} ended during {}; upstream connection closed", self.id, info.track.key),
+                None => debug!("Session {} ended before any track started", self.id),
+            }
+        }
+        info!(
+            "Access: session={} client_id={} bytes_sent={} duration_secs={} tracks_played={}",
+            self.id,
+            self.client_id,
+            self.bytes_sent,
+            self.opened_at.elapsed().as_secs(),
+            self.tracks_played
+        );
+        // Drop isn't async, so hand the session off to the cleanup task
+        if let Some(tx) = self.transcode_stop_tx.as_ref().filter(|_| !superseded) {
+            let _ = tx.send(self.id.clone());
+        }
+    }
+}
 
-            // Track finished, loop continues immediately to next track
+/// Lets other requests steer an open /radio stream.
+struct SessionControl {
+    // True while POST /pause holds the upstream
+    paused: watch::Sender<bool>,
+    // Ends the stream cleanly (POST /admin/stop-all)
+    stop: Arc<Notify>,
+    // Listener that opened the stream; only it may pause it or edit its queue
+    client_id: String,
+    // Tells this stream apart from a later one of the same client under the same session id
+    stream_id: u64,
+}
+
+/// How a new stream's claim on its session id went.
+#[derive(Debug, PartialEq)]
+enum SessionClaim {
+    /// Nothing was open under the id
+    New,
+    /// The same client's open stream was stopped to make way for this one
+    Replaced,
+    /// Another client has a stream open under the id, and keeps it
+    Refused,
+}
+
+/// Registers a stream's controls under its session id. A stream the same client still
+/// has open there is stopped and replaced; one of another client keeps the session.
+fn claim_session(controls: &mut HashMap<String, SessionControl>, session_id: &str, control: SessionControl) -> SessionClaim {
+    let claim = match controls.get(session_id) {
+        Some(open) if open.client_id != control.client_id => return SessionClaim::Refused,
+        Some(open) => {
+            open.stop.notify_one();
+            SessionClaim::Replaced
         }
+        None => SessionClaim::New,
     };
+    controls.insert(session_id.to_string(), control);
+    claim
+}
 
-    // Return the stream as the HTTP body with correct headers
-    PlexStreamResponse(Box::pin(stream))
+/// Whether a new stream is a skip: it replaces the client's open stream of the session,
+/// or says so with `skip=true` (the web UI opens every stream under a new session id).
+/// A stream that asks for a `track` jumps to it rather than skipping.
+fn is_skip(params: &HashMap<String, String>, claim: &SessionClaim) -> bool {
+    let marked = params.get("skip").is_some_and(|v| v == "true" || v == "1");
+    !params.contains_key("track") && (marked || *claim == SessionClaim::Replaced)
 }
 
-/// Returns the current track metadata for a given session.
-async fn now_playing(
-    State(state): State<AppState>,
-    Query(params): Query<HashMap<String, String>>,
-) -> impl IntoResponse {
-    let session_id = params.get("session").map(|s| s.as_str()).unwrap_or("");
-    let client_id = params.get("client_id").map(|s| s.as_str()).unwrap_or("anon");
-    
-    let sessions = state.sessions.lock().unwrap();
-    match sessions.get(session_id) {
-        Some((track, started_at)) => {
-            let elapsed = started_at.elapsed().unwrap_or(Duration::from_secs(0)).as_millis() as u64;
-            let history_map = state.history.lock().unwrap();
-            let history = history_map.get(client_id).cloned().unwrap_or_default();
-            // Skip the first element of history as it is the current track
-            let previous_tracks: Vec<Track> = history.into_iter().skip(1).collect();
-            
-            let body = serde_json::json!({
-                "title": track.title,
-                "key": track.key,
-                "artist": track.artist,
-                "duration": track.duration,
-                "elapsed": elapsed,
-                "history": previous_tracks
-            });
-            Json(Some(body)).into_response()
-        },
-        None => StatusCode::NOT_FOUND.into_response(),
+/// A session's controls, if `client_id` is the listener that opened it.
+fn owned_control<'a>(
+    controls: &'a HashMap<String, SessionControl>,
+    session_id: &str,
+    client_id: &str,
+) -> Option<&'a SessionControl> {
+    controls.get(session_id).filter(|control| control.client_id == client_id)
+}
+
+/// Per-stream track selection state: play mode, position and RNG.
+/// A seeded selector draws from a deterministic RNG, so two streams with the same
+/// seed and mode play the same sequence (every skip or reconnect advances it).
+/// Indexes are positions in the station's candidate list, not in `library.tracks`.
+#[derive(Clone, Debug)]
+struct Selector {
+    order: PlayOrder,
+    filter: StationFilter,
+    current_index: Option<usize>,
+    rng: StdRng,
+    // Seeded selectors survive reconnects, so their future is predictable
+    seeded: bool,
+    // Bag mode: indexes still to play this cycle (popped from the end)
+    deck: Vec<usize>,
+}
+
+/// How a station moves through its tracks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlayOrder {
+    /// Library order, wrapping around (`shuffle=false`)
+    Sequential,
+    /// Independent random picks; a track can come up again soon (default)
+    Shuffle,
+    /// Every track once per cycle in random order, then reshuffle (`shuffle_mode=bag`)
+    Bag,
+}
+
+impl PlayOrder {
+    fn from_params(params: &HashMap<String, String>) -> Self {
+        if params.get("shuffle_mode").is_some_and(|m| m == "bag") {
+            PlayOrder::Bag
+        } else if params.get("shuffle").is_some_and(|s| s == "false") {
+            PlayOrder::Sequential
+        } else {
+            PlayOrder::Shuffle
+        }
     }
 }
 
-/// Searches the cached track list for titles or artists matching the query.
-async fn search_tracks(
-    State(state): State<AppState>,
-    Query(params): Query<HashMap<String, String>>,
-) -> impl IntoResponse {
-    let query = params.get("q").map(|s| s.to_lowercase()).unwrap_or_default();
-    if query.len() < 2 {
-        return Json(Vec::<Track>::new()).into_response();
+impl Selector {
+    fn new(order: PlayOrder, filter: StationFilter, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Selector { order, filter, current_index: None, rng, seeded: seed.is_some(), deck: Vec::new() }
     }
 
-    let results: Vec<Track> = state.tracks.iter()
-        .filter(|t| t.title.to_lowercase().contains(&query) || t.artist.to_lowercase().contains(&query))
-        .take(50)
-        .cloned()
-        .collect();
+    /// Whether the upcoming sequence is known in advance (sequential or seeded).
+    fn is_predictable(&self) -> bool {
+        self.order == PlayOrder::Sequential || self.seeded
+    }
+
+    /// Returns the next `n` indexes without advancing this selector.
+    fn peek(&self, count: usize, n: usize) -> Vec<usize> {
+        let mut preview = self.clone();
+        (0..n).map_while(|_| preview.next_index(count)).collect()
+    }
+
+    /// Advances to the next track out of `count` candidates and returns its index, or
+    /// `None` when there are no candidates (e.g. a refresh emptied the station).
+    fn next_index(&mut self, count: usize) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+        let idx = match (self.order, self.current_index) {
+            (PlayOrder::Sequential, Some(i)) => (i + 1) % count,
+            (PlayOrder::Bag, _) => self.draw_from_deck(count),
+            _ => self.rng.gen_range(0..count),
+        };
+        self.current_index = Some(idx);
+        Some(idx)
+    }
+
+    /// Like `next_index`, but plain (unseeded) shuffle draws in proportion to `weights`
+    /// (one per candidate). Seeded stations ignore them so they stay reproducible.
+    fn next_index_weighted(&mut self, count: usize, weights: Option<&[f64]>) -> Option<usize> {
+        let weighted = weights
+            .filter(|w| w.len() == count && self.order == PlayOrder::Shuffle && !self.seeded)
+            .and_then(|w| WeightedIndex::new(w).ok());
+        match weighted {
+            Some(dist) => {
+                let idx = dist.sample(&mut self.rng);
+                self.current_index = Some(idx);
+                Some(idx)
+            }
+            None => self.next_index(count),
+        }
+    }
+
+    /// Pops the next index from the bag, dealing a freshly shuffled deck when it runs out.
+    fn draw_from_deck(&mut self, count: usize) -> usize {
+        // Entries can be stale if the candidate list shrank since the deck was dealt
+        while let Some(idx) = self.deck.pop() {
+            if idx < count {
+                return idx;
+            }
+        }
 
-    Json(results).into_response()
+        self.deck = (0..count).collect();
+        self.deck.shuffle(&mut self.rng);
+        // Don't let the last track of one cycle open the next
+        if count > 1 && self.deck.last() == self.current_index.as_ref() {
+            self.deck.swap(0, count - 1);
+        }
+        self.deck.pop().unwrap_or(0)
+    }
+
+    /// Draws a random index without moving the current position (`None` if `count` is 0).
+    fn random_index(&mut self, count: usize) -> Option<usize> {
+        (count > 0).then(|| self.rng.gen_range(0..count))
+    }
+
+    /// Moves the current position, e.g. when a specific track was requested.
+    fn jump_to(&mut self, idx: usize) {
+        self.current_index = Some(idx);
+        // A requested track counts as played for this cycle
+        self.deck.retain(|&i| i != idx);
+    }
 }
 
-/// Implement IntoResponse for our stream to set headers manually
-impl IntoResponse for PlexStreamResponse {
-    fn into_response(self) -> Response {
-        let body = Body::from_stream(self.0);
-        
-        Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "audio/mpeg")
-            .header(header::CACHE_CONTROL, "no-cache")
-            .header(header::CONNECTION, "keep-alive")
-            .body(body)
-            .unwrap()
+/// Narrows a station down to part of the library
+/// (`/radio?genre=...&artist=...&year_min=...&year_max=...&dur_min=...&dur_max=...`).
+/// Text matching is case-insensitive; an empty filter matches every track.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct StationFilter {
+    genre: Option<String>,
+    artist: Option<String>,
+    year_min: Option<u32>,
+    year_max: Option<u32>,
+    // Track length bounds in ms (`dur_min`/`dur_max` are given in seconds)
+    dur_min: Option<u64>,
+    dur_max: Option<u64>,
+    // Lowercased artists left out of this session (`exclude_artist`, repeatable)
+    excluded_artists: Vec<String>,
+    // `clean=true`: leave out tracks rated explicit
+    clean: bool,
+}
+
+impl StationFilter {
+    fn from_params(params: &HashMap<String, String>) -> Result<Self, String> {
+        let param = |name: &str| {
+            params.get(name)
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+        };
+        let year = |name: &str| {
+            param(name)
+                .map(|s| s.parse::<u32>().map_err(|_| format!("`{}` must be a year", name)))
+                .transpose()
+        };
+        let secs = |name: &str| {
+            param(name)
+                .map(|s| s.parse::<u64>().ok()
+                    .and_then(|secs| secs.checked_mul(1000))
+                    .ok_or_else(|| format!("`{}` must be a number of seconds", name)))
+                .transpose()
+        };
+
+        let filter = StationFilter {
+            genre: param("genre"),
+            artist: param("artist"),
+            year_min: year("year_min")?,
+            year_max: year("year_max")?,
+            dur_min: secs("dur_min")?,
+            dur_max: secs("dur_max")?,
+            excluded_artists: Vec::new(),
+            clean: param("clean").is_some_and(|v| v == "true" || v == "1"),
+        };
+        if let (Some(min), Some(max)) = (filter.year_min, filter.year_max) {
+            if min > max {
+                return Err(format!("year_min ({}) is after year_max ({})", min, max));
+            }
+        }
+        if let (Some(min), Some(max)) = (filter.dur_min, filter.dur_max) {
+            if min > max {
+                return Err(format!("dur_min ({}s) is longer than dur_max ({}s)", min / 1000, max / 1000));
+            }
+        }
+        Ok(filter)
+    }
+
+    fn matches(&self, track: &Track) -> bool {
+        let genre_ok = self.genre.as_ref()
+            .is_none_or(|g| track.genres.iter().any(|tg| tg.to_lowercase() == *g));
+        let artist_ok = self.artist.as_ref()
+            .is_none_or(|a| track.artist.to_lowercase() == *a);
+        // Tracks without a known year never match a year range
+        let year_ok = match (self.year_min, self.year_max) {
+            (None, None) => true,
+            (min, max) => track.year.is_some_and(|y| {
+                min.is_none_or(|min| y >= min) && max.is_none_or(|max| y <= max)
+            }),
+        };
+        let duration_ok = self.dur_min.is_none_or(|min| track.duration >= min)
+            && self.dur_max.is_none_or(|max| track.duration <= max);
+        let not_excluded = self.excluded_artists.is_empty()
+            || !self.excluded_artists.contains(&track.artist.to_lowercase());
+        let clean_ok = !self.clean || !track.is_explicit();
+        genre_ok && artist_ok && year_ok && duration_ok && not_excluded && clean_ok
+    }
+
+    /// Adds every `exclude_artist` from the raw query pairs. `Query<HashMap>` keeps only
+    /// one value per name, so the repeated param has to be read from the full list.
+    fn exclude_artists(mut self, pairs: &[(String, String)]) -> Self {
+        self.excluded_artists = pairs.iter()
+            .filter(|(name, _)| name == "exclude_artist")
+            .map(|(_, value)| value.trim().to_lowercase())
+            .filter(|value| !value.is_empty())
+            .collect();
+        // Sorted so the same exclusions in any order make an equal filter (seeded stations)
+        self.excluded_artists.sort();
+        self.excluded_artists.dedup();
+        self
+    }
+
+    /// Positions in `tracks` that belong to this station, in library order.
+    fn candidates(&self, tracks: &[Track]) -> Vec<usize> {
+        tracks.iter()
+            .enumerate()
+            .filter(|(_, t)| self.matches(t))
+            .map(|(i, _)| i)
+            .collect()
     }
 }
 
-struct PlexStreamResponse(std::pin::Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>>);
+/// Turns a `seed` query param into an RNG seed. Numbers are used as-is; any other
+/// string (e.g. `?seed=90s-party`) is hashed with FNV-1a, which is stable across builds.
+fn parse_seed(raw: &str) -> u64 {
+    raw.parse::<u64>().unwrap_or_else(|_| {
+        raw.bytes().fold(0xcbf29ce484222325, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        })
+    })
+}
+
+/// Parses an `offset` query param (milliseconds), rounded down to whole seconds.
+/// The transcoder only seeks in whole seconds, so rounding here keeps the
+/// elapsed time we report (and `/resume`) in line with what is actually heard.
+fn parse_offset_ms(raw: Option<&String>) -> u64 {
+    let ms = raw.and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+    ms - ms % 1000
+}
+
+/// Parses `PLEX_EXTRA_HEADERS`: `Name:value` pairs separated by `;`, e.g.
+/// `CF-Access-Client-Id:abc;CF-Access-Client-Secret:xyz`. Values are marked sensitive
+/// so they stay out of debug output.
+fn parse_extra_headers(raw: &str) -> anyhow::Result<reqwest::header::HeaderMap> {
+    use reqwest::header::{HeaderName, HeaderValue};
+
+    let mut headers = reqwest::header::HeaderMap::new();
+    for pair in raw.split(';').map(str::trim).filter(|p| !p.is_empty()) {
+        let (name, value) = pair.split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("PLEX_EXTRA_HEADERS entry '{}' must look like Name:value", pair))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| anyhow::anyhow!("PLEX_EXTRA_HEADERS: '{}' is not a valid header name", name.trim()))?;
+        let mut value = HeaderValue::from_str(value.trim())
+            .map_err(|_| anyhow::anyhow!("PLEX_EXTRA_HEADERS: the value of {} is not a valid header value", name))?;
+        value.set_sensitive(true);
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+/// Keeps `audioBoost` within the range the Plex transcoder accepts (100-300%).
+/// Out-of-range values are clamped with a warning, naming the setting they came from,
+/// instead of being sent to Plex.
+fn clamp_audio_boost(boost: u32, source: &str) -> u32 {
+    let clamped = boost.clamp(AUDIO_BOOST_RANGE.0, AUDIO_BOOST_RANGE.1);
+    if clamped != boost {
+        warn!(
+            "{}={} is outside {}-{}; using {}",
+            source, boost, AUDIO_BOOST_RANGE.0, AUDIO_BOOST_RANGE.1, clamped
+        );
+    }
+    clamped
+}
+
+/// Picks the transcode settings for a request: the `?profile=` (or DEFAULT_PROFILE)
+/// audio profile, else the global settings. A `?bitrate=` in `ALLOWED_BITRATES`
+/// still overrides the profile's bitrate.
+fn requested_profile(state: &AppState, params: &HashMap<String, String>) -> Result<AudioProfile, String> {
+    let profiles = &state.audio_profiles;
+    let name = params.get("profile")
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .or_else(|| profiles.default.clone());
+    let mut profile = match name {
+        Some(name) => *profiles.named.get(&name).ok_or_else(|| if profiles.named.is_empty() {
+            format!("Unknown profile '{}'; no AUDIO_PROFILES are configured", name)
+        } else {
+            format!("Unknown profile '{}'; expected one of {}", name, profiles.names().join(", "))
+        })?,
+        None => AudioProfile {
+            bitrate: state.bitrate,
+            audio_boost: state.audio_boost,
+            output_format: state.output_format,
+        },
+    };
+    if let Some(bitrate) = params.get("bitrate")
+        .and_then(|s| s.parse::<u32>().ok())
+        .filter(|b| ALLOWED_BITRATES.contains(b))
+    {
+        profile.bitrate = bitrate;
+    }
+    Ok(profile)
+}
+
+/// Keeps a seek offset inside the track. Tracks with an unknown (zero) duration are left alone.
+fn clamp_offset(offset_ms: u64, duration_ms: u64) -> u64 {
+    if duration_ms == 0 {
+        offset_ms
+    } else {
+        offset_ms.min(duration_ms)
+    }
+}
+
+/// Rejects a seek past the end of an explicitly requested track.
+fn validate_seek(library: &Library, params: &HashMap<String, String>) -> Result<(), String> {
+    let (Some(key), Some(_)) = (params.get("track"), params.get("offset")) else {
+        return Ok(());
+    };
+    let offset_ms = parse_offset_ms(params.get("offset"));
+    match library.tracks.iter().find(|t| t.key == *key) {
+        Some(track) if track.duration > 0 && offset_ms > track.duration => Err(format!(
+            "Offset {}ms is beyond the track length ({}ms)",
+            offset_ms, track.duration
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, so a client-supplied key (or a
+/// token) can't break out of the URL path segment or query value it is put in.
+fn url_encode(value: &str) -> Cow<'_, str> {
+    let unreserved = |b: u8| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~');
+    if value.bytes().all(unreserved) {
+        return Cow::Borrowed(value);
+    }
+    Cow::Owned(value.bytes().map(|b| {
+        if unreserved(b) { (b as char).to_string() } else { format!("%{:02X}", b) }
+    }).collect())
+}
+
+/// `/radio` URL that plays the given track, optionally starting `offset` ms in.
+fn track_url(key: &str, offset: Option<u64>) -> String {
+    match offset {
+        Some(offset) => format!("/radio?track={}&offset={}", url_encode(key), offset),
+        None => format!("/radio?track={}", url_encode(key)),
+    }
+}
+
+/// Fetches a track's full metadata (media, parts, album details) from Plex.
+async fn fetch_track_metadata(state: &AppState, track_key: &str) -> Option<TrackMetadata> {
+    let meta_url = format!("{}/library/metadata/{}", state.plex_url, url_encode(track_key));
+    let container = state.client.get(&meta_url)
+        .plex_token(&state.plex_token)
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .ok()?;
+    let container: TrackContainer = read_plex_body(container).await.ok()?;
+
+    container.media_container.metadata.into_iter().next()
+}
+
+/// Reads a track's duration from its full metadata (track, then media, then part),
+/// for tracks the library listing reported as 0.
+async fn fetch_track_duration(state: &AppState, track_key: &str) -> Option<u64> {
+    let meta = fetch_track_metadata(state, track_key).await?;
+    let media = meta.media.first();
+    [
+        meta.duration,
+        media.map_or(0, |m| m.duration),
+        media.and_then(|m| m.parts.first()).map_or(0, |p| p.duration),
+    ]
+    .into_iter()
+    .find(|d| *d > 0)
+}
+
+/// The Plex request(s) for a track. A file Plex keeps in several parts takes one
+/// request per part, played back to back: `rest` follows `first`.
+struct TrackRequest {
+    first: reqwest::RequestBuilder,
+    rest: Vec<reqwest::RequestBuilder>,
+    mode: StreamMode,
+}
+
+/// Which part a track offset falls in, and how far into that part it is. Parts of
+/// unknown length are taken to hold the rest of the offset.
+fn locate_part(part_durations: &[u64], offset_ms: u64) -> (usize, u64) {
+    let mut left = offset_ms;
+    for (index, &duration) in part_durations.iter().enumerate() {
+        if duration == 0 || left < duration || index + 1 == part_durations.len() {
+            return (index, left);
+        }
+        left -= duration;
+    }
+    (0, offset_ms)
+}
+
+/// Plex's explanation when a failed transcode shows the transcoder can't run at all
+/// (disabled, missing or unlicensed), or `None` for failures worth retrying.
+fn transcoder_refusal(status: StatusCode, body: &str) -> Option<String> {
+    let lower = body.to_lowercase();
+    if !TRANSCODER_UNAVAILABLE_MARKERS.iter().any(|marker| lower.contains(marker)) {
+        return None;
+    }
+    let message: String = body.split_whitespace().collect::<Vec<_>>().join(" ").chars().take(200).collect();
+    Some(format!("{}: {}", status, message))
+}
+
+/// Counts a transcode Plex refused. After `TRANSCODER_REFUSAL_THRESHOLD` refusals in a
+/// row that name the transcoder, says so in the log and /health, and switches to
+/// passthrough if AUTO_PASSTHROUGH_ON_TRANSCODE_FAIL is set.
+fn record_transcode_failure(state: &AppState, status: StatusCode, body: &str) {
+    let Some(reason) = transcoder_refusal(status, body) else { return };
+    let refusals = state.transcoder.refusals.fetch_add(1, Ordering::Relaxed) + 1;
+    if refusals < TRANSCODER_REFUSAL_THRESHOLD {
+        warn!("Plex refused to transcode ({})", reason);
+        return;
+    }
+    if let Ok(mut unavailable) = state.transcoder.unavailable.lock() {
+        if unavailable.is_none() {
+            error!(
+                "Plex's transcoder looks unavailable: {} refusals in a row ({}). Transcoding may be \
+                 disabled on the server or need Plex Pass; set PLEX_PASSTHROUGH=true to play the files directly",
+                refusals, reason
+            );
+        }
+        *unavailable = Some(reason);
+    }
+    if state.auto_passthrough && !state.transcoder.fallen_back.swap(true, Ordering::Relaxed) {
+        warn!("AUTO_PASSTHROUGH_ON_TRANSCODE_FAIL is set; playing files directly until restart");
+    }
+}
+
+/// Resets the refusal count once a transcode starts, clearing an earlier diagnosis.
+fn record_transcode_success(state: &AppState) {
+    state.transcoder.refusals.store(0, Ordering::Relaxed);
+    if let Ok(mut unavailable) = state.transcoder.unavailable.lock() {
+        if unavailable.take().is_some() {
+            info!("Plex's transcoder is working again");
+        }
+    }
+}
+
+/// Helper to build the Plex request (Passthrough or Transcode)
+/// Separating this logic helps avoid compiler bugs with async-stream macros
+async fn prepare_track_request(
+    state: &AppState,
+    track_key: &str,
+    session_id: &str,
+    offset_ms: u64,
+    profile: &AudioProfile,
+    requested: MediaIndexes,
+) -> Option<TrackRequest> {
+    if state.plays_passthrough() {
+        // Passthrough: Fetch track metadata to get the actual file path(s)
+        let media = fetch_track_metadata(state, track_key).await
+            .and_then(|m| {
+                let media_index = requested.media_index.unwrap_or_else(|| state.media_version.pick(&m.media));
+                m.media.into_iter().nth(media_index)
+            });
+        // An explicit `part_index` plays just that part; otherwise every part in turn
+        let part_keys: Vec<String> = match (media, requested.part_index) {
+            (Some(media), Some(index)) => media.parts.into_iter().nth(index).map(|p| p.key).into_iter().collect(),
+            (Some(media), None) => media.parts.into_iter().map(|p| p.key).collect(),
+            (None, _) => Vec::new(),
+        };
+
+        let mut requests = part_keys.iter().map(|pk| {
+            let stream_url = format!("{}{}", state.plex_url, pk);
+            state.stream_client.get(&stream_url).plex_token(&state.plex_token)
+        });
+        let Some(first) = requests.next() else {
+            error!("Failed to resolve file path for passthrough. Skipping.");
+            return None;
+        };
+        Some(TrackRequest { first, rest: requests.collect(), mode: StreamMode::Passthrough })
+    } else {
+        // Transcode: Use universal transcoder
+        let (endpoint, protocol) = match state.protocol {
+            StreamProtocol::Http => ("start.mp3", "http"),
+            #[cfg(feature = "hls")]
+            StreamProtocol::Hls => ("start.m3u8", "hls"),
+        };
+        let base_url = state.plex_url.trim_end_matches('/');
+        let transcode_url = format!("{}/music/:/transcode/universal/{}", base_url, endpoint);
+        // A URL inside a query value: its own parts are encoded here, the whole by `query`
+        let path_param = format!(
+            "{}/library/metadata/{}?X-Plex-Token={}",
+            base_url,
+            url_encode(track_key),
+            url_encode(&state.plex_token.value)
+        );
+        // Comparing versions, or finding a split file's parts, needs the track's media
+        // list; the common case (first version, one file) doesn't
+        let multi_part = requested.part_index.is_none()
+            && state.library().tracks.iter().any(|t| t.key == track_key && t.multi_part);
+        let metadata = if multi_part || (requested.media_index.is_none() && state.media_version != MediaVersion::First) {
+            fetch_track_metadata(state, track_key).await
+        } else {
+            None
+        };
+        let media_index = match (requested.media_index, &metadata) {
+            (Some(index), _) => index,
+            (None, Some(m)) => state.media_version.pick(&m.media),
+            (None, None) => 0,
+        };
+        // The transcoder plays one part per request; without an explicit `part_index`
+        // a split file is played from the part the offset falls in to the last
+        let part_durations: Vec<u64> = metadata.as_ref()
+            .filter(|_| multi_part)
+            .and_then(|m| m.media.get(media_index))
+            .map(|media| media.parts.iter().map(|p| p.duration).collect())
+            .unwrap_or_default();
+        let (first_part, part_offset_ms) = match requested.part_index {
+            Some(index) => (index, offset_ms),
+            None if part_durations.len() > 1 => locate_part(&part_durations, offset_ms),
+            None => (0, offset_ms),
+        };
+        let last_part = first_part.max(part_durations.len().saturating_sub(1));
+
+        let build = |part_index: usize, offset_ms: u64| {
+            let mut request = state.stream_client
+                .get(&transcode_url)
+                .plex_token(&state.plex_token)
+                .header("X-Plex-Client-Identifier", &state.plex_client.identifier)
+                .header("X-Plex-Product", &state.plex_client.product)
+                .header("X-Plex-Version", &state.plex_client.version)
+                .header("X-Plex-Platform", "Generic")
+                .header("X-Plex-Device", &state.plex_client.device)
+                .header("X-Plex-Session-Id", session_id)
+                .query(&[
+                    ("path", path_param.clone()),
+                    ("mediaIndex", media_index.to_string()),
+                    ("partIndex", part_index.to_string()),
+                    ("protocol", protocol.to_string()),
+                    ("offset", (offset_ms / 1000).to_string()),
+                    ("fastSeek", "1".to_string()),
+                    ("directPlay", "0".to_string()),
+                    ("directStream", "1".to_string()),
+                    ("audioBoost", profile.audio_boost.to_string()),
+                    ("maxAudioBitrate", profile.bitrate.to_string()),
+                    ("context", state.transcode_context.clone()),
+                    ("session", session_id.to_string()),
+                ]);
+            if let Some(extra) = profile.output_format.profile_extra() {
+                request = request.header("X-Plex-Client-Profile-Extra", extra);
+            }
+            request
+        };
+        Some(TrackRequest {
+            first: build(first_part, part_offset_ms),
+            rest: (first_part + 1..=last_part).map(|index| build(index, 0)).collect(),
+            mode: StreamMode::Transcode,
+        })
+    }
+}
+
+/// Reads the remaining parts of a split file once the first has been read to the end,
+/// each fetched only when its turn comes.
+fn chain_parts(first: ByteStream, rest: Vec<reqwest::RequestBuilder>) -> impl Stream<Item = std::io::Result<Bytes>> {
+    async_stream::try_stream! {
+        let mut current = first;
+        for next in std::iter::once(None).chain(rest.into_iter().map(Some)) {
+            if let Some(request) = next {
+                let resp = request.send().await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(std::io::Error::other)?;
+                current = Box::pin(futures::TryStreamExt::map_err(resp.bytes_stream(), std::io::Error::other));
+            }
+            while let Some(chunk) = futures::StreamExt::next(&mut current).await {
+                yield chunk?;
+            }
+        }
+    }
+}
+
+/// MIME type for a Plex media container. Unknown containers are sent as MP3, which is
+/// what the transcoder (and most music libraries) produce anyway.
+fn container_mime(container: Option<&str>) -> &'static str {
+    match container.map(|c| c.to_ascii_lowercase()).as_deref() {
+        Some("flac") => "audio/flac",
+        Some("ogg") | Some("opus") => "audio/ogg",
+        Some("mp4") | Some("m4a") => "audio/mp4",
+        Some("aac") => "audio/aac",
+        Some("wav") => "audio/wav",
+        _ => "audio/mpeg",
+    }
+}
+
+/// The Content-Type a passthrough stream is announced with. Headers go out before the
+/// first track is picked, so this uses the requested track if there is one, otherwise the
+/// station's format when all of its files share a container.
+fn passthrough_content_type(library: &Library, params: &HashMap<String, String>, candidates: &[usize]) -> &'static str {
+    if let Some(track) = params.get("track").and_then(|key| library.tracks.iter().find(|t| t.key == *key)) {
+        return container_mime(track.container.as_deref());
+    }
+
+    let mut types = candidates.iter().map(|&idx| container_mime(library.tracks[idx].container.as_deref()));
+    let first = types.next().unwrap_or("audio/mpeg");
+    if types.all(|t| t == first) {
+        first
+    } else {
+        debug!("Station mixes file formats; announcing the stream as audio/mpeg");
+        "audio/mpeg"
+    }
+}
+
+/// What a stream request asks for, checked against the cached library. Nothing here
+/// asks Plex, so `HEAD` probes get the same answer without opening anything.
+struct StationRequest {
+    library: Arc<Library>,
+    requested_media: MediaIndexes,
+    filter: StationFilter,
+    candidates: Vec<usize>,
+    profile: AudioProfile,
+}
+
+fn resolve_station(
+    state: &AppState,
+    params: &HashMap<String, String>,
+    pairs: &[(String, String)],
+) -> Result<StationRequest, ApiError> {
+    if !state.is_ready() {
+        return Err(ApiError::warming_up());
+    }
+    let library = state.library();
+    validate_seek(&library, params).map_err(ApiError::bad_request)?;
+    // Explicit media/part indexes only make sense for the requested track
+    let requested_media = MediaIndexes::from_params(params).map_err(ApiError::bad_request)?;
+    let profile = requested_profile(state, params).map_err(ApiError::bad_request)?;
+
+    // Resolve the station's tracks before committing to a stream
+    let filter = StationFilter::from_params(params).map_err(ApiError::bad_request)?.exclude_artists(pairs);
+    let candidates = state.station_candidates(&library, &filter);
+    if candidates.is_empty() {
+        if !filter.excluded_artists.is_empty() {
+            return Err(ApiError::not_found("Excluding these artists leaves no tracks"));
+        }
+        return Err(ApiError::not_found("No tracks match this station"));
+    }
+
+    // Kiosk instances: requested tracks/albums must come from a playable section
+    if let Some(key) = params.get("track") {
+        if library.tracks.iter().any(|t| t.key == *key && !state.is_playable(t)) {
+            return Err(ApiError::forbidden("This track is not available here"));
+        }
+    }
+    if let Some(key) = params.get("album") {
+        let mut album_tracks = library.tracks.iter().filter(|t| t.album_key == *key).peekable();
+        if album_tracks.peek().is_some() && !album_tracks.any(|t| state.is_playable(t)) {
+            return Err(ApiError::forbidden("This album is not available here"));
+        }
+    }
+
+    Ok(StationRequest { library, requested_media, filter, candidates, profile })
+}
+
+/// The answer to `HEAD` on the stream endpoints: the headers a stream would start with,
+/// without starting one. Probes and health checks read no body, so they shouldn't hold
+/// a stream slot or (for playlists and HLS) have Plex do any work.
+fn probe_response(state: &AppState, library: &Library, params: &HashMap<String, String>, candidates: &[usize]) -> Response {
+    #[cfg(feature = "hls")]
+    if state.protocol == StreamProtocol::Hls && !state.plays_passthrough() {
+        return hls_response(String::new());
+    }
+    let content_type = if state.plays_passthrough() {
+        passthrough_content_type(library, params, candidates)
+    } else {
+        "audio/mpeg"
+    };
+    PlexStreamResponse {
+        stream: Box::pin(futures::stream::empty()),
+        content_type,
+        icy_name: Some(state.plex_client.product.clone()),
+    }
+    .into_response()
+}
+
+/// The main handler for the /radio endpoint.
+/// Returns a continuous stream of MP3 data.
+#[utoipa::path(
+    get,
+    path = "/radio",
+    params(
+        ("genre" = Option<String>, Query, description = "Only tracks with this genre (case-insensitive)"),
+        ("artist" = Option<String>, Query, description = "Only tracks by this artist (case-insensitive)"),
+        ("year_min" = Option<u32>, Query, description = "Earliest release year"),
+        ("year_max" = Option<u32>, Query, description = "Latest release year"),
+        ("dur_min" = Option<u64>, Query, description = "Shortest track length in seconds"),
+        ("dur_max" = Option<u64>, Query, description = "Longest track length in seconds"),
+        ("exclude_artist" = Option<Vec<String>>, Query, description = "Artist to leave out; repeat for several"),
+        ("clean" = Option<bool>, Query, description = "`true` leaves out tracks rated explicit"),
+        ("session" = Option<String>, Query, description = "Session id for /now-playing, /pause etc.; generated if missing"),
+        ("client_id" = Option<String>, Query, description = "Stable listener id (history, blocks, seeded stations)"),
+        ("bitrate" = Option<u32>, Query, description = "Max transcode bitrate: 128, 192, 256 or 320"),
+        ("profile" = Option<String>, Query, description = "Audio profile from AUDIO_PROFILES (default DEFAULT_PROFILE); `bitrate` still overrides its bitrate"),
+        ("loop" = Option<bool>, Query, description = "Keep going after the album/playlist ends (default true)"),
+        ("track" = Option<String>, Query, description = "Start with this track (ratingKey)"),
+        ("offset" = Option<u64>, Query, description = "Start this far into `track` (ms)"),
+        ("media_index" = Option<usize>, Query, description = "Media version of `track` to play (default: MEDIA_VERSION)"),
+        ("part_index" = Option<usize>, Query, description = "File part of that version to play (default 0)"),
+        ("shuffle" = Option<bool>, Query, description = "`false` plays the station in library order"),
+        ("shuffle_mode" = Option<String>, Query, description = "`bag` plays every track once before reshuffling"),
+        ("seed" = Option<String>, Query, description = "Reproducible shuffle order"),
+        ("continue_on_error" = Option<bool>, Query, description = "Fall back to random tracks if `track` keeps failing"),
+        ("start_paused" = Option<bool>, Query, description = "`true` holds the stream, without contacting Plex, until POST /resume"),
+        ("skip" = Option<bool>, Query, description = "`true` marks the stream as a skip, so its first shuffle pick leaves out recent tracks"),
+    ),
+    responses(
+        (status = 200, description = "Continuous audio stream", content_type = "audio/mpeg"),
+        (status = 404, description = "Nothing to play", body = ErrorBody),
+        (status = 503, description = "Library still loading, or too many streams", body = ErrorBody),
+        (status = 400, description = "Invalid filter or seek", body = ErrorBody),
+        (status = 403, description = "Track or album outside the playable sections", body = ErrorBody),
+        (status = 409, description = "The session id is in use by another listener", body = ErrorBody),
+    ),
+)]
+async fn stream_radio(
+    method: Method,
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    Query(pairs): Query<Vec<(String, String)>>,
+) -> impl IntoResponse {
+    let StationRequest { library, requested_media, filter, candidates, profile } = match resolve_station(&state, &params, &pairs) {
+        Ok(station) => station,
+        Err(e) => return e.into_response(),
+    };
+    if method == Method::HEAD {
+        return probe_response(&state, &library, &params, &candidates);
+    }
+
+    // Playlist stations: fetch the playlist now, so a bad id fails before the stream starts
+    let playlist: Option<Vec<Track>> = match params.get("playlist") {
+        Some(id) => match fetch_playlist_tracks(&state.client, &state.plex_url, &state.plex_token, id).await {
+            Ok(tracks) => {
+                let tracks: Vec<Track> = tracks.into_iter().filter(|t| state.is_playable(t)).collect();
+                if tracks.is_empty() {
+                    return ApiError::not_found("This playlist has no playable tracks").into_response();
+                }
+                Some(tracks)
+            }
+            Err(e) => {
+                warn!("Failed to fetch playlist {} from Plex: {}", id, e);
+                let not_found = e.downcast_ref::<reqwest::Error>()
+                    .is_some_and(|e| e.status() == Some(StatusCode::NOT_FOUND));
+                if not_found {
+                    return ApiError::not_found(format!("Unknown playlist '{}'", id)).into_response();
+                }
+                return ApiError::bad_gateway("Failed to fetch the playlist from Plex").into_response();
+            }
+        },
+        None => None,
+    };
+
+    // Use provided session ID or generate one
+    let session_id = params.get("session").cloned().unwrap_or_else(|| {
+        format!("radio-{:x}", rand::thread_rng().gen::<u64>())
+    });
+    let client_id = params.get("client_id").cloned().unwrap_or_else(|| "anon".to_string());
+    if session_taken(&state, &session_id, &client_id) {
+        return ApiError::conflict("This session id is in use by another listener").into_response();
+    }
+
+    state.record_preferences(&params, &pairs);
+
+    // HLS clients get a playlist for one track and come back for the next
+    #[cfg(feature = "hls")]
+    if state.protocol == StreamProtocol::Hls && !state.plays_passthrough() {
+        return hls_playlist(state, library, params, candidates, requested_media, profile).await;
+    }
+
+    // Reserve a stream slot up front; the permit lives as long as the stream itself
+    let permit = match state.stream_permits.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            warn!("Rejecting /radio connection: MAX_CONCURRENT_STREAMS reached");
+            return ApiError::unavailable("Too many active streams").into_response();
+        }
+    };
+
+    // Passthrough sends the original files, so advertise their format rather than MP3
+    let content_type = if state.plays_passthrough() {
+        passthrough_content_type(&library, &params, &candidates)
+    } else {
+        "audio/mpeg"
+    };
+    let station_name = state.plex_client.product.clone();
+    let stop = Arc::new(Notify::new());
+    let stop_signal = stop.clone();
+    let idle_watch = state.stream_health.idle_timeout.map(|_| Arc::new(IdleWatch::default()));
+    let idle_signal = idle_watch.clone();
+
+    // Create an async stream that yields Bytes.
+    // Nothing below runs until hyper first polls the body, which it does as soon as the
+    // response headers are sent, so the first transcode starts right away rather than when
+    // the client reads. A connection that closes before the headers go out never reaches Plex;
+    // `start_paused` holds off Plex until the listener explicitly resumes.
+    let stream = async_stream::try_stream! {
+        let _permit = permit;
+
+        debug!("Starting stream for session {} of {}", session_id, client_id);
+        if let (Some(watch), Some(timeout)) = (&idle_signal, state.stream_health.idle_timeout) {
+            tokio::spawn(watch_idle(state.clone(), session_id.clone(), Arc::downgrade(watch), timeout));
+        }
+        
+        let mut initial_track_key = params.get("track").cloned();
+        let mut initial_offset_ms = parse_offset_ms(params.get("offset"));
+        let bitrate = profile.bitrate;
+        
+        let play_order = PlayOrder::from_params(&params);
+        // Fall back to random tracks if a requested track keeps failing (instead of ending the stream)
+        let continue_on_error = params.get("continue_on_error").map(|s| s == "true").unwrap_or(false);
+        let mut specific_attempts = 0;
+
+        // Album mode: walk an album's tracks in order, then pick another album
+        let album_mode = params.contains_key("album");
+        let mut next_album_key = params.get("album").cloned();
+        let loop_albums = params.get("loop").map(|s| s != "false").unwrap_or(true);
+        // Tracks lined up next: the rest of the current album or pass through the playlist
+        let mut track_queue: VecDeque<Track> = VecDeque::new();
+        let mut playlist_passes = 0;
+        // Startup buffer: hold back the first bytes until there's enough to play smoothly
+        let mut prebuffer = Prebuffer::new(state.prebuffer_bytes);
+
+        // RAII Guard to clean up session on disconnect
+        let stream_id = rand::thread_rng().gen::<u64>();
+        let mut guard = SessionGuard {
+            id: session_id.clone(),
+            stream_id,
+            sessions: state.sessions.clone(),
+            session_controls: state.session_controls.clone(),
+            queues: state.queues.clone(),
+            track_changes: state.track_changes.clone(),
+            transcode_stop_tx: (!state.plays_passthrough()).then(|| state.transcode_stop_tx.clone()),
+            client_id: client_id.clone(),
+            opened_at: Instant::now(),
+            bytes_sent: 0,
+            tracks_played: 0,
+        };
+
+        // Lets POST /pause and /resume hold and release the upstream
+        let start_paused = params.get("start_paused").is_some_and(|v| v == "true" || v == "1");
+        let (pause_tx, mut pause_rx) = watch::channel(start_paused);
+        let control = SessionControl { paused: pause_tx, stop: stop_signal, client_id: client_id.clone(), stream_id };
+        // Another listener may have opened the same session id since the request was checked
+        let claim = state.session_controls.lock()
+            .map(|mut controls| claim_session(&mut controls, &session_id, control))
+            .unwrap_or(SessionClaim::Refused);
+        if claim == SessionClaim::Refused {
+            warn!("Session {} was opened by another listener first; ending this stream", session_id);
+            return;
+        }
+        // Embedded players whose autoplay may be blocked: no transcode until /resume
+        if start_paused {
+            info!("Session {} opened paused; waiting for /resume before contacting Plex", session_id);
+            if pause_rx.wait_for(|paused| !paused).await.is_err() {
+                return;
+            }
+            info!("Session {} started", session_id);
+        }
+        // The first shuffle pick after a skip leaves out what this client just heard
+        let mut after_skip = is_skip(&params, &claim);
+        // Set when Plex dropped a paused track and it is being reopened at the same spot
+        let mut reconnecting = false;
+        // The track to retry or reopen; it may come from a playlist or album and not be in `library`
+        let mut reopen_track: Option<Track> = None;
+
+        // Seeded stations pick up where this client's last connection left off
+        let seed = params.get("seed").map(|s| parse_seed(s));
+        let selector_key = seed.map(|seed| format!("{}:{}", client_id, seed));
+        let base_filter = filter.clone();
+        let mut selector = selector_key.as_ref()
+            .and_then(|key| state.seeded_selectors.lock().ok()?.get(key).cloned())
+            .filter(|s| s.order == play_order && s.filter == filter)
+            .unwrap_or_else(|| Selector::new(play_order, filter, seed));
+
+        // Auto stations switch genre with the daypart schedule, checked before every track
+        let auto_mode = params.contains_key("auto");
+        let mut candidates = candidates;
+        let mut daypart_genre: Option<String> = None;
+
+        // Infinite loop: Pick a song, stream it, repeat.
+        loop {
+            if auto_mode {
+                let genre = state.dayparts.current_genre().map(str::to_string);
+                if genre != daypart_genre {
+                    let filter = StationFilter { genre: genre.clone().or(base_filter.genre.clone()), ..base_filter.clone() };
+                    let daypart_candidates = state.station_candidates(&library, &filter);
+                    if daypart_candidates.is_empty() {
+                        warn!("No tracks for daypart genre {:?}; staying on the current selection", genre);
+                    } else {
+                        info!("Daypart changed: now playing {}", genre.as_deref().unwrap_or("the whole station"));
+                        candidates = daypart_candidates;
+                        selector = Selector::new(play_order, filter, None);
+                    }
+                    daypart_genre = genre;
+                }
+            }
+
+            // 1. Pick a random track
+            let mut is_specific_request = false;
+            let mut track = if let Some(track) = reopen_track.take() {
+                is_specific_request = true;
+                track
+            } else if let Some(key) = initial_track_key.take() {
+                is_specific_request = true;
+                if let Some(idx) = library.tracks.iter().position(|t| t.key == *key) {
+                    // Continue from here if the track is part of the station
+                    if let Some(pos) = candidates.iter().position(|&c| c == idx) {
+                        selector.jump_to(pos);
+                    }
+                    library.tracks[idx].clone()
+                } else {
+                    // Fallback if key not found
+                    let Some(pos) = selector.random_index(candidates.len()) else {
+                        warn!("Station for session {} has no tracks left; ending the stream", session_id);
+                        break;
+                    };
+                    selector.jump_to(pos);
+                    library.tracks[candidates[pos]].clone()
+                }
+            } else if let Some(track) = state.next_queued(&session_id) {
+                // Requests from POST /queue go first; the station carries on afterwards
+                if state.is_blocked(&client_id, &track) {
+                    info!("Dropping queued request {}: blocked by {}", track.key, client_id);
+                    continue;
+                }
+                info!("Playing queued request {}", track.key);
+                track
+            } else if let Some(tracks) = &playlist {
+                // Playlist mode: walk the playlist in order, then start over (unless `loop=false`)
+                if track_queue.is_empty() {
+                    if playlist_passes > 0 && !loop_albums {
+                        break;
+                    }
+                    playlist_passes += 1;
+                    track_queue = tracks.iter().filter(|t| !state.is_blocked(&client_id, t)).cloned().collect();
+                    if track_queue.is_empty() {
+                        warn!("Everything in the playlist is blocked for {}; ending the stream", client_id);
+                        break;
+                    }
+                }
+                // Blocks made since the pass started count too
+                match track_queue.pop_front() {
+                    Some(track) if state.is_blocked(&client_id, &track) => continue,
+                    Some(track) => track,
+                    None => continue,
+                }
+            } else if album_mode {
+                if track_queue.is_empty() {
+                    let is_requested_album = next_album_key.is_some();
+                    let album_key = match next_album_key.take() {
+                        Some(key) => key,
+                        None if loop_albums => {
+                            let Some(pos) = selector.random_index(candidates.len()) else {
+                                warn!("Station for session {} has no tracks left; ending the stream", session_id);
+                                break;
+                            };
+                            library.tracks[candidates[pos]].album_key.clone()
+                        }
+                        None => break, // Album finished and looping is disabled
+                    };
+
+                    let album = fetch_album_tracks(&state.client, &state.plex_url, &state.plex_token, &album_key).await
+                        .map(|tracks| tracks.into_iter()
+                            .filter(|t| state.is_playable(t) && !state.is_blocked(&client_id, t))
+                            .collect::<Vec<_>>());
+                    match album {
+                        Ok(tracks) if !tracks.is_empty() => {
+                            info!("Playing album {} ({} tracks)", album_key, tracks.len());
+                            track_queue = tracks.into();
+                        }
+                        Ok(_) => {
+                            warn!("Album {} has no tracks", album_key);
+                            if is_requested_album { break; }
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("Failed to fetch album {} from Plex: {}", album_key, e);
+                            if is_requested_album { break; }
+                            tokio::time::sleep(state.stream_health.error_backoff).await;
+                            continue;
+                        }
+                    }
+                }
+                match track_queue.pop_front() {
+                    Some(track) if state.is_blocked(&client_id, &track) => continue,
+                    Some(track) => track,
+                    None => continue,
+                }
+            } else {
+                // Liked/disliked tracks come up more/less often in plain shuffle, and
+                // recently heard ones not at all right after a skip
+                let weights = state.shuffle_weights(&library, &candidates, &client_id, std::mem::take(&mut after_skip));
+                let Some(mut pos) = selector.next_index_weighted(candidates.len(), weights.as_deref()) else {
+                    warn!("Station for session {} has no tracks left; ending the stream", session_id);
+                    break;
+                };
+                // Skip anything this client has blocked (bounded, in case everything is)
+                for _ in 0..candidates.len() {
+                    if !state.is_blocked(&client_id, &library.tracks[candidates[pos]]) {
+                        break;
+                    }
+                    pos = selector.next_index_weighted(candidates.len(), weights.as_deref()).unwrap_or(pos);
+                }
+                library.tracks[candidates[pos]].clone()
+            };
+
+            if let Some(key) = &selector_key {
+                if let Ok(mut map) = state.seeded_selectors.lock() {
+                    map.insert(key.clone(), selector.clone());
+                }
+            }
+
+            // Unanalyzed tracks report a zero duration; ask Plex for the file's real length
+            if track.duration == 0 {
+                if let Some(duration) = fetch_track_duration(&state, &track.key).await {
+                    info!("Corrected duration of {} to {}ms", track.key, duration);
+                    track.duration = duration;
+                }
+            }
+
+            let track_key = track.key.clone();
+            info!("Now Playing: {} - {}", track.artist, track.title);
+            if state.plays_passthrough() {
+                let track_type = container_mime(track.container.as_deref());
+                if track_type != content_type {
+                    warn!(
+                        "{} is {} but this stream was announced as {}; strict clients may stop here",
+                        track_key, track_type, content_type
+                    );
+                }
+            }
+            initial_offset_ms = clamp_offset(initial_offset_ms, track.duration);
+
+            // 2. Determine Stream URL (Passthrough vs Transcode)
+            let media = if is_specific_request && params.get("track") == Some(&track_key) {
+                requested_media
+            } else {
+                MediaIndexes::default()
+            };
+            // Whole transcodes can come from (and go to) the disk cache
+            let cache_key = state.disk_cache.as_ref()
+                .filter(|_| initial_offset_ms == 0 && matches!(state.protocol, StreamProtocol::Http))
+                .map(|_| transcode_cache_key(&state, &track_key, &profile, media));
+            let cached_file = match cache_key.as_deref().and_then(|key| state.disk_cache.as_ref()?.lookup(key)) {
+                Some(path) => match tokio::fs::File::open(&path).await {
+                    Ok(file) => Some(file),
+                    Err(e) => {
+                        warn!("Could not open cached {} ({}); asking Plex instead", path.display(), e);
+                        None
+                    }
+                },
+                None => None,
+            };
+
+            // 3. Execute Request
+            let response = if let Some(file) = cached_file {
+                debug!("Serving {} from the disk cache", track_key);
+                let byte_stream: ByteStream = Box::pin(cached_file_stream(file));
+                Some((byte_stream, true, StreamMode::Cached))
+            } else {
+                match prepare_track_request(&state, &track_key, &session_id, initial_offset_ms, &profile, media).await {
+                    Some(TrackRequest { first, rest, mode }) => match first.send().await {
+                        Ok(resp) if resp.status().is_success() => {
+                            // MP3 output starts on a frame boundary so track changes don't pop
+                            let is_mp3 = resp.headers()
+                                .get(header::CONTENT_TYPE)
+                                .map(|v| v.as_bytes().starts_with(b"audio/mpeg"))
+                                .unwrap_or(!state.plays_passthrough());
+                            let mut byte_stream: ByteStream = Box::pin(
+                                futures::TryStreamExt::map_err(resp.bytes_stream(), std::io::Error::other),
+                            );
+                            if matches!(mode, StreamMode::Transcode) {
+                                record_transcode_success(&state);
+                            }
+                            if !rest.is_empty() {
+                                debug!("{} is split in {} parts; playing them in turn", track_key, rest.len() + 1);
+                                byte_stream = Box::pin(chain_parts(byte_stream, rest));
+                            }
+                            Some((byte_stream, is_mp3, mode))
+                        }
+                        Ok(resp) => {
+                            let status = resp.status();
+                            warn!("Plex returned non-success status: {}", status);
+                            if matches!(mode, StreamMode::Transcode) {
+                                let body = resp.text().await.unwrap_or_default();
+                                record_transcode_failure(&state, status, &body);
+                            }
+                            None
+                        }
+                        Err(e) => {
+                            error!("Failed to fetch track from Plex: {}", e);
+                            None
+                        }
+                    },
+                    None => None,
+                }
+            };
+
+            let (mut byte_stream, is_mp3, mode) = match response {
+                Some(connected) => {
+                    specific_attempts = 0;
+                    connected
+                }
+                None if is_specific_request => {
+                    // Retry the requested track with backoff before giving up on it
+                    specific_attempts += 1;
+                    if specific_attempts <= SPECIFIC_TRACK_RETRIES {
+                        let backoff = Duration::from_secs(1 << (specific_attempts - 1));
+                        warn!(
+                            "Retrying requested track {} in {:?} (attempt {}/{})",
+                            track_key, backoff, specific_attempts, SPECIFIC_TRACK_RETRIES
+                        );
+                        reopen_track = Some(track.clone());
+                        tokio::time::sleep(backoff).await;
+                        continue;
+                    }
+
+                    specific_attempts = 0;
+                    reconnecting = false;
+                    if !continue_on_error {
+                        warn!("Giving up on requested track {}; ending stream", track_key);
+                        break;
+                    }
+                    warn!("Giving up on requested track {}; continuing with random tracks", track_key);
+                    initial_offset_ms = 0;
+                    continue;
+                }
+                None => {
+                    tokio::time::sleep(state.stream_health.error_backoff).await;
+                    continue; // Skip to next track on error
+                }
+            };
+
+            // Dead transcodes answer 200 and then send nothing; only wait so long for them.
+            // The track only counts as playing once its first bytes are in.
+            if let Some(limit) = state.stream_health.first_byte_timeout.filter(|_| !is_specific_request) {
+                match tokio::time::timeout(limit, futures::StreamExt::next(&mut byte_stream)).await {
+                    Ok(Some(first)) => {
+                        byte_stream = Box::pin(futures::StreamExt::chain(futures::stream::once(async { first }), byte_stream));
+                    }
+                    Ok(None) => byte_stream = Box::pin(futures::stream::empty()),
+                    Err(_) => {
+                        warn!("No data from Plex for {} within {:?}; skipping it", track_key, limit);
+                        drop(byte_stream);
+                        if matches!(mode, StreamMode::Transcode) {
+                            // Awaited, so it can't reach Plex after the next track's transcode started
+                            stop_transcode(&state.client, &state.plex_url, &state.plex_token, &session_id).await;
+                        }
+                        continue;
+                    }
+                }
+            }
+
+            if guard.superseded() {
+                break;
+            }
+            // Update session state (Metadata) only after successful connection
+            if let Ok(mut map) = state.sessions.lock() {
+                // If seeking, adjust start time so elapsed calculation is correct
+                let start_time = SystemTime::now() - Duration::from_millis(initial_offset_ms);
+                map.insert(session_id.clone(), SessionInfo {
+                    track: track.clone(),
+                    client_id: client_id.clone(),
+                    started_at: start_time,
+                    bitrate,
+                    mode,
+                    // Album mode moves on to a random album, so only `queued` is predictable
+                    selector: (!album_mode && playlist.is_none()).then(|| selector.clone()),
+                    // A looping playlist's last track is followed by the next pass
+                    queued: match &playlist {
+                        Some(tracks) if track_queue.is_empty() && loop_albums => tracks.clone(),
+                        _ => track_queue.iter().cloned().collect(),
+                    },
+                });
+            }
+            
+            // Update History (Add current track to history list, unless it's already there)
+            if std::mem::take(&mut reconnecting) {
+                debug!("Reopened {} after a pause", track_key);
+            } else {
+                guard.tracks_played += 1;
+                state.record_history(&client_id, &track);
+            }
+            let _ = state.track_changes.send(session_id.clone());
+
+            // 4. Pipe the bytes to the listener
+            let mut aligner = FrameAligner::new(is_mp3);
+            let mut bytes_sent = 0;
+            // A fresh full transcode is saved as it streams; it only counts once complete
+            let mut cache_writer = match (&state.disk_cache, &cache_key, mode) {
+                (Some(cache), Some(key), StreamMode::Transcode) => match cache.writer(key).await {
+                    Ok(writer) => Some(writer),
+                    Err(e) => {
+                        warn!("Could not start caching {}: {}", track_key, e);
+                        None
+                    }
+                },
+                _ => None,
+            };
+            let mut ended_cleanly = false;
+            let stream_start = SystemTime::now();
+            // Keep half-open mobile connections honest while Plex stalls (MP3 only)
+            let heartbeat = state.stream_health.heartbeat.filter(|_| is_mp3);
+            let mut resumed = false;
+            // Position (ms) to reopen the track at, if Plex gave up on it during a pause
+            let mut reopen_at = None;
+            loop {
+                if *pause_rx.borrow() {
+                    info!("Session {} paused during {}", session_id, track_key);
+                    let paused_at = Instant::now();
+                    wait_for_resume(&state, &session_id, mode, &mut pause_rx).await;
+                    let paused_for = paused_at.elapsed();
+                    // Keep /now-playing's elapsed time from counting the pause
+                    if let Ok(mut map) = state.sessions.lock() {
+                        if let Some(info) = map.get_mut(&session_id) {
+                            info.started_at += paused_for;
+                        }
+                    }
+                    info!("Session {} resumed after {:.0}s", session_id, paused_for.as_secs_f32());
+                    let _ = state.track_changes.send(session_id.clone());
+                    resumed = true;
+                }
+                let next = match heartbeat {
+                    Some(interval) => match tokio::time::timeout(interval, futures::StreamExt::next(&mut byte_stream)).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            // Nothing to send until the track's format is known
+                            if let Some(frame) = aligner.silent_frame() {
+                                debug!("No data from Plex for {:?}; sending a silent frame", interval);
+                                if let Some(bytes) = prebuffer.push(frame) {
+                                    yield bytes;
+                                }
+                            }
+                            continue;
+                        }
+                    },
+                    None => futures::StreamExt::next(&mut byte_stream).await,
+                };
+                // The transcode was stopped while the listener wasn't reading; now that it
+                // is again, pick the track up at what it was last sent
+                if !matches!(next, Some(Ok(_)))
+                    && idle_signal.as_ref().is_some_and(|watch| watch.stopped.swap(false, Ordering::AcqRel))
+                {
+                    let position = initial_offset_ms + bytes_sent as u64 * 8 / u64::from(bitrate.max(1));
+                    info!("Session {} is reading again; reopening {} at {}s", session_id, track_key, position / 1000);
+                    reopen_at = Some(position);
+                    break;
+                }
+                let Some(chunk) = next else {
+                    ended_cleanly = true;
+                    break;
+                };
+                match chunk {
+                    Ok(bytes) => {
+                        // Plex is sending again, so a later error is a real one
+                        resumed = false;
+                        if let Some(writer) = cache_writer.as_mut() {
+                            if let Err(e) = writer.write(&bytes).await {
+                                warn!("Could not write {} to the disk cache: {}", track_key, e);
+                                cache_writer = None;
+                            }
+                        }
+                        if let Some(bytes) = aligner.push(bytes) {
+                            bytes_sent += bytes.len();
+                            guard.bytes_sent += bytes.len() as u64;
+                            if let Some(bytes) = prebuffer.push(bytes) {
+                                yield bytes;
+                            }
+                        }
+                    },
+                    Err(e) if resumed => {
+                        warn!("Plex dropped {} while paused ({}); reopening it where it left off", track_key, e);
+                        reopen_at = state.sessions.lock().ok()
+                            .and_then(|map| map.get(&session_id).map(|info| info.started_at))
+                            .and_then(|started_at| started_at.elapsed().ok())
+                            .map(|elapsed| elapsed.as_millis() as u64);
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Error reading bytes from Plex: {}", e);
+                        break; // Break inner loop to pick new song (or disconnect)
+                    }
+                }
+            }
+            // A track shorter than the prebuffer target is sent as soon as it ends
+            if let Some(bytes) = prebuffer.flush() {
+                yield bytes;
+            }
+            if let Some(offset) = reopen_at {
+                reopen_track = Some(track.clone());
+                initial_offset_ms = offset;
+                reconnecting = true;
+                continue;
+            }
+            
+            // Check for rapid failure (empty stream or very short duration)
+            let health = state.stream_health;
+            let streamed_for = stream_start.elapsed().unwrap_or(Duration::from_secs(0));
+            let reason = health.quick_end(&track, bytes_sent, streamed_for, is_specific_request);
+            // Only whole, healthy transcodes are kept; dropping the writer discards the rest
+            if let (Some(cache), Some(writer)) = (&state.disk_cache, cache_writer.take()) {
+                if ended_cleanly && reason.is_none() {
+                    match cache.commit(writer).await {
+                        Ok(()) => debug!("Cached the transcode of {}", track_key),
+                        Err(e) => warn!("Could not cache {}: {}", track_key, e),
+                    }
+                }
+            }
+            if let (Some(reason), Some(backoff)) = (&reason, health.backoff_after(&track, reason.as_deref())) {
+                warn!(
+                    "Track finished too quickly: {}. Possible transcoding error or empty file; backing off {}s.",
+                    reason,
+                    backoff.as_secs()
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            
+            // Reset offset for subsequent tracks in the playlist
+            initial_offset_ms = 0;
+
+            // Track finished, loop continues immediately to next track
+        }
+    };
+
+    // Group this listener's logs (track changes, errors, teardown) under one id
+    let request_id = format!("{:08x}", rand::thread_rng().gen::<u32>());
+    let span = tracing::info_span!("listener", request_id = %request_id);
+    // Ends the body when the session is stopped, wherever the stream happens to be waiting
+    let stream = futures::StreamExt::take_until(stream, async move { stop.notified().await });
+    let stream = IdleWatchedStream { watch: idle_watch, inner: Box::pin(stream) };
+    let stream = SpannedStream { span, inner: Some(Box::pin(stream)) };
+
+    // Return the stream as the HTTP body with correct headers
+    let mut response = PlexStreamResponse {
+        stream: Box::pin(stream),
+        content_type,
+        icy_name: Some(station_name),
+    }
+    .into_response();
+    if let Ok(value) = header::HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    response
+}
+
+/// Whether a /radio listener has stopped reading (IDLE_STREAM_TIMEOUT), e.g. a paused
+/// player in a background tab that keeps the connection open.
+#[derive(Debug, Default)]
+struct IdleWatch {
+    // When the last chunk was handed over, while the listener hasn't asked for the next
+    waiting_since: std::sync::Mutex<Option<Instant>>,
+    // Set once the transcode was stopped for it; the stream reopens the track later
+    stopped: AtomicBool,
+}
+
+/// Notes in an `IdleWatch` when the listener takes a chunk and when it comes back for more.
+/// hyper only polls the body while the socket takes data, so a listener that doesn't read
+/// leaves the stream parked after its last chunk.
+struct IdleWatchedStream<S> {
+    watch: Option<Arc<IdleWatch>>,
+    inner: std::pin::Pin<Box<S>>,
+}
+
+impl<S: Stream> Stream for IdleWatchedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<S::Item>> {
+        let this = self.get_mut();
+        let poll = this.inner.as_mut().poll_next(cx);
+        if let Some(watch) = &this.watch {
+            let handed_over = matches!(poll, std::task::Poll::Ready(Some(_)));
+            *watch.waiting_since.lock().unwrap() = handed_over.then(Instant::now);
+        }
+        poll
+    }
+}
+
+/// Stops the transcode of a session whose listener has left a chunk unread for `timeout`,
+/// so Plex isn't busy for nobody. Ends with the stream.
+async fn watch_idle(state: AppState, session_id: String, watch: std::sync::Weak<IdleWatch>, timeout: Duration) {
+    let mut ticks = tokio::time::interval((timeout / 4).max(Duration::from_secs(1)));
+    loop {
+        ticks.tick().await;
+        let Some(watch) = watch.upgrade() else {
+            return;
+        };
+        let idle = watch.waiting_since.lock().unwrap().is_some_and(|since| since.elapsed() >= timeout);
+        if !idle || watch.stopped.load(Ordering::Acquire) {
+            continue;
+        }
+        // Passthrough and cached tracks cost Plex next to nothing to hold
+        let transcoding = state.sessions.lock().unwrap()
+            .get(&session_id)
+            .is_some_and(|info| matches!(info.mode, StreamMode::Transcode));
+        if transcoding {
+            info!("Session {} hasn't read for {:?}; stopping its transcode until it does", session_id, timeout);
+            watch.stopped.store(true, Ordering::Release);
+            let _ = state.transcode_stop_tx.send(session_id.clone());
+        }
+    }
+}
+
+/// Holds a paused stream until its session is resumed, pinging Plex meanwhile so the
+/// transcode isn't timed out (passthrough has no transcode to keep alive).
+async fn wait_for_resume(state: &AppState, session_id: &str, mode: StreamMode, pause_rx: &mut watch::Receiver<bool>) {
+    let ping_url = format!("{}/music/:/transcode/universal/ping", state.plex_url);
+    let mut keepalive = tokio::time::interval(PAUSE_KEEPALIVE);
+    keepalive.tick().await; // The first tick completes immediately
+
+    loop {
+        tokio::select! {
+            // The guard `wait_for` returns isn't `Send`, so drop it inside the branch future
+            _ = async { pause_rx.wait_for(|paused| !paused).await.is_ok() } => return,
+            _ = keepalive.tick() => {
+                if !matches!(mode, StreamMode::Transcode) {
+                    continue;
+                }
+                let result = state.client.get(&ping_url)
+                    .plex_token(&state.plex_token)
+                    .query(&[("session", session_id)])
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status());
+                if let Err(e) = result {
+                    warn!("Keepalive ping for paused session {} failed: {}", session_id, e);
+                }
+            }
+        }
+    }
+}
+
+// --- HLS Proxy ---
+
+/// Starts an HLS transcode for the next track and returns its playlist,
+/// rewritten so every URL points back through /radio/hls (the token stays server-side).
+#[cfg(feature = "hls")]
+async fn hls_playlist(
+    state: AppState,
+    library: Arc<Library>,
+    params: HashMap<String, String>,
+    candidates: Vec<usize>,
+    requested_media: MediaIndexes,
+    profile: AudioProfile,
+) -> Response {
+    let session_id = params.get("session").cloned().unwrap_or_else(|| {
+        format!("radio-{:x}", rand::thread_rng().gen::<u64>())
+    });
+    let client_id = params.get("client_id").cloned().unwrap_or_else(|| "anon".to_string());
+    let offset_ms = parse_offset_ms(params.get("offset"));
+
+    let track = params.get("track")
+        .and_then(|key| library.tracks.iter().find(|t| t.key == *key))
+        .cloned()
+        .unwrap_or_else(|| {
+            let idx = candidates[rand::thread_rng().gen_range(0..candidates.len())];
+            library.tracks[idx].clone()
+        });
+    let offset_ms = clamp_offset(offset_ms, track.duration);
+
+    // The session's previous transcode is replaced by this one, so its slot is freed first
+    state.hls_permits.lock().unwrap().remove(&session_id);
+    let permit = match state.stream_permits.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            warn!("Rejecting HLS playlist request: MAX_CONCURRENT_STREAMS reached");
+            return ApiError::unavailable("Too many active streams").into_response();
+        }
+    };
+    info!("Now Playing (HLS): {} - {}", track.artist, track.title);
+
+    // Explicit indexes only apply to the requested track, not a random fallback
+    let media = if params.get("track") == Some(&track.key) { requested_media } else { MediaIndexes::default() };
+    // A playlist covers one part, so a split file plays only as far as its first part here
+    let Some(TrackRequest { first: request, mode, .. }) = prepare_track_request(&state, &track.key, &session_id, offset_ms, &profile, media).await else {
+        return ApiError::bad_gateway("Could not prepare the transcode request").into_response();
+    };
+    let playlist = match request.send().await {
+        Ok(resp) if !resp.status().is_success() => {
+            let status = resp.status();
+            error!("Failed to start HLS transcode: Plex returned {}", status);
+            record_transcode_failure(&state, status, &resp.text().await.unwrap_or_default());
+            return ApiError::bad_gateway("Failed to start the HLS transcode").into_response();
+        }
+        Ok(resp) => match resp.text().await {
+            Ok(text) => {
+                record_transcode_success(&state);
+                text
+            }
+            Err(e) => {
+                error!("Failed to read HLS playlist from Plex: {}", e);
+                return ApiError::bad_gateway("Failed to read the HLS playlist from Plex").into_response();
+            }
+        },
+        Err(e) => {
+            error!("Failed to start HLS transcode: {}", e);
+            return ApiError::bad_gateway("Failed to start the HLS transcode").into_response();
+        }
+    };
+
+    if let Ok(mut map) = state.sessions.lock() {
+        let start_time = SystemTime::now() - Duration::from_millis(offset_ms);
+        map.insert(session_id.clone(), SessionInfo {
+            track: track.clone(),
+            client_id: client_id.clone(),
+            started_at: start_time,
+            bitrate: profile.bitrate,
+            mode,
+            selector: None,
+            queued: Vec::new(),
+        });
+    }
+    state.record_history(&client_id, &track);
+    let _ = state.track_changes.send(session_id.clone());
+    hold_hls_permit(&state, &session_id, permit, Duration::from_millis(track.duration.saturating_sub(offset_ms)));
+
+    hls_response(rewrite_hls_playlist(&playlist, &state.plex_url, "/radio/hls/"))
+}
+
+/// An HLS transcode has no connection to outlive, so its stream slot is held until the
+/// session asks for its next track, or until the track would have finished playing (plus
+/// a grace period for the player's buffer) if it never does.
+#[cfg(feature = "hls")]
+fn hold_hls_permit(state: &AppState, session_id: &str, permit: tokio::sync::OwnedSemaphorePermit, remaining: Duration) {
+    let playlist_id = rand::thread_rng().gen::<u64>();
+    state.hls_permits.lock().unwrap().insert(session_id.to_string(), (playlist_id, permit));
+
+    let permits = state.hls_permits.clone();
+    let session_id = session_id.to_string();
+    tokio::spawn(async move {
+        tokio::time::sleep(remaining + Duration::from_secs(30)).await;
+        let mut permits = permits.lock().unwrap();
+        if permits.get(&session_id).is_some_and(|(id, _)| *id == playlist_id) {
+            permits.remove(&session_id);
+        }
+    });
+}
+
+/// Proxies nested playlists and media segments from the Plex transcoder.
+#[cfg(feature = "hls")]
+async fn hls_proxy(
+    State(state): State<AppState>,
+    axum::extract::Path(path): axum::extract::Path<String>,
+) -> Response {
+    let url = format!("{}/music/:/transcode/universal/{}", state.plex_url, path);
+    let resp = match state.client.get(&url)
+        .plex_token(&state.plex_token)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+    {
+        Ok(resp) => resp,
+        Err(e) => {
+            warn!("HLS proxy request for {} failed: {}", path, e);
+            return ApiError::bad_gateway("HLS proxy request to Plex failed").into_response();
+        }
+    };
+
+    if path.ends_with(".m3u8") {
+        // Relative entries resolve against the directory of this playlist
+        let dir = path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("");
+        let proxy_base = if dir.is_empty() {
+            "/radio/hls/".to_string()
+        } else {
+            format!("/radio/hls/{}/", dir)
+        };
+        return match resp.text().await {
+            Ok(text) => hls_response(rewrite_hls_playlist(&text, &state.plex_url, &proxy_base)),
+            Err(_) => ApiError::bad_gateway("Failed to read the HLS playlist from Plex").into_response(),
+        };
+    }
+
+    let content_type = resp.headers()
+        .get(header::CONTENT_TYPE)
+        .cloned()
+        .unwrap_or_else(|| header::HeaderValue::from_static("video/mp2t"));
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from_stream(resp.bytes_stream()))
+        .unwrap()
+}
+
+#[cfg(feature = "hls")]
+fn hls_response(body: String) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "application/vnd.apple.mpegurl"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Rewrites every URI line of an m3u8 playlist to go through our proxy,
+/// dropping any `X-Plex-Token` Plex embedded in it.
+#[cfg(feature = "hls")]
+fn rewrite_hls_playlist(playlist: &str, plex_url: &str, proxy_base: &str) -> String {
+    let transcoder_abs = format!("{}/music/:/transcode/universal/", plex_url);
+    let transcoder_path = "/music/:/transcode/universal/";
+
+    playlist
+        .lines()
+        .map(|line| {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                return line.to_string();
+            }
+            let uri = strip_token_param(line);
+            if let Some(rest) = uri.strip_prefix(&transcoder_abs).or_else(|| uri.strip_prefix(transcoder_path)) {
+                format!("/radio/hls/{}", rest)
+            } else {
+                format!("{}{}", proxy_base, uri)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(feature = "hls")]
+fn strip_token_param(uri: &str) -> String {
+    let Some((path, query)) = uri.split_once('?') else {
+        return uri.to_string();
+    };
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|pair| !pair.starts_with("X-Plex-Token="))
+        .collect();
+    if kept.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, kept.join("&"))
+    }
+}
+
+/// Handler for /radio/album?key=<album_ratingKey>.
+/// Plays the album in order, then moves on to random albums (unless `loop=false`).
+#[utoipa::path(
+    get,
+    path = "/radio/album",
+    params(
+        ("key" = String, Query, description = "ratingKey of the album"),
+        ("session" = Option<String>, Query, description = "Session id for /now-playing, /pause etc.; generated if missing"),
+        ("client_id" = Option<String>, Query, description = "Stable listener id (history, blocks, seeded stations)"),
+        ("bitrate" = Option<u32>, Query, description = "Max transcode bitrate: 128, 192, 256 or 320"),
+        ("profile" = Option<String>, Query, description = "Audio profile from AUDIO_PROFILES (default DEFAULT_PROFILE); `bitrate` still overrides its bitrate"),
+        ("loop" = Option<bool>, Query, description = "Keep going after the album/playlist ends (default true)"),
+    ),
+    responses(
+        (status = 200, description = "Continuous audio stream", content_type = "audio/mpeg"),
+        (status = 404, description = "Nothing to play", body = ErrorBody),
+        (status = 503, description = "Library still loading, or too many streams", body = ErrorBody),
+        (status = 400, description = "Missing album key", body = ErrorBody),
+    ),
+)]
+async fn stream_album(
+    method: Method,
+    state: State<AppState>,
+    Query(mut params): Query<HashMap<String, String>>,
+    pairs: Query<Vec<(String, String)>>,
+) -> Response {
+    match params.remove("key") {
+        Some(key) if !key.is_empty() => {
+            params.insert("album".to_string(), key);
+            stream_radio(method, state, Query(params), pairs).await.into_response()
+        }
+        _ => ApiError::bad_request("Missing album key").into_response(),
+    }
+}
+
+/// Handler for /radio/playlist?id=<playlist_ratingKey>.
+/// Plays the Plex playlist in order, then starts it over (unless `loop=false`).
+#[utoipa::path(
+    get,
+    path = "/radio/playlist",
+    params(
+        ("id" = String, Query, description = "ratingKey of the Plex playlist"),
+        ("session" = Option<String>, Query, description = "Session id for /now-playing, /pause etc.; generated if missing"),
+        ("client_id" = Option<String>, Query, description = "Stable listener id (history, blocks, seeded stations)"),
+        ("bitrate" = Option<u32>, Query, description = "Max transcode bitrate: 128, 192, 256 or 320"),
+        ("profile" = Option<String>, Query, description = "Audio profile from AUDIO_PROFILES (default DEFAULT_PROFILE); `bitrate` still overrides its bitrate"),
+        ("loop" = Option<bool>, Query, description = "Keep going after the album/playlist ends (default true)"),
+    ),
+    responses(
+        (status = 200, description = "Continuous audio stream", content_type = "audio/mpeg"),
+        (status = 404, description = "Nothing to play", body = ErrorBody),
+        (status = 503, description = "Library still loading, or too many streams", body = ErrorBody),
+        (status = 400, description = "Missing playlist id", body = ErrorBody),
+        (status = 502, description = "Plex could not return the playlist", body = ErrorBody),
+    ),
+)]
+async fn stream_playlist(
+    method: Method,
+    state: State<AppState>,
+    Query(mut params): Query<HashMap<String, String>>,
+    pairs: Query<Vec<(String, String)>>,
+) -> Response {
+    match params.remove("id") {
+        Some(id) if !id.is_empty() => {
+            params.insert("playlist".to_string(), id);
+            stream_radio(method, state, Query(params), pairs).await.into_response()
+        }
+        _ => ApiError::bad_request("Missing playlist id").into_response(),
+    }
+}
+
+/// Handler for /radio/auto: a station whose genre follows the time of day (DAYPARTS).
+/// Hours no daypart covers play the whole station (or its `genre`, if given).
+#[utoipa::path(
+    get,
+    path = "/radio/auto",
+    params(
+        ("session" = Option<String>, Query, description = "Session id for /now-playing, /pause etc.; generated if missing"),
+        ("client_id" = Option<String>, Query, description = "Stable listener id (history, blocks)"),
+        ("bitrate" = Option<u32>, Query, description = "Max transcode bitrate: 128, 192, 256 or 320"),
+        ("profile" = Option<String>, Query, description = "Audio profile from AUDIO_PROFILES (default DEFAULT_PROFILE); `bitrate` still overrides its bitrate"),
+    ),
+    responses(
+        (status = 200, description = "Continuous audio stream", content_type = "audio/mpeg"),
+        (status = 404, description = "No dayparts are configured", body = ErrorBody),
+    ),
+)]
+async fn stream_auto(
+    method: Method,
+    state: State<AppState>,
+    Query(mut params): Query<HashMap<String, String>>,
+    pairs: Query<Vec<(String, String)>>,
+) -> Response {
+    if state.dayparts.parts.is_empty() {
+        return ApiError::not_found("No dayparts are configured (DAYPARTS)").into_response();
+    }
+    params.insert("auto".to_string(), "true".to_string());
+    stream_radio(method, state, Query(params), pairs).await.into_response()
+}
+
+/// Returns the current track metadata for a given session.
+#[utoipa::path(
+    get,
+    path = "/now-playing",
+    params(
+        ("session" = String, Query, description = "Session id the stream was opened with"),
+        ("client_id" = Option<String>, Query, description = "Listener whose history to include"),
+        ("lenient" = Option<bool>, Query, description = "Answer `{\"playing\": false}` instead of 404 when nothing is playing"),
+    ),
+    responses(
+        (status = 200, description = "Current track, elapsed time (ms), previous tracks and the `next` track when known", body = serde_json::Value),
+        (status = 404, description = "No track is playing for this session", body = ErrorBody),
+    ),
+)]
+async fn now_playing(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let session_id = params.get("session").map(|s| s.as_str()).unwrap_or("");
+    let client_id = params.get("client_id").map(|s| s.as_str()).unwrap_or("anon");
+    
+    match now_playing_body(&state, session_id, client_id) {
+        Some(body) => Ok(Json(body)),
+        // Pollers can ask for a plain "stopped" answer instead of an error
+        None if params.get("lenient").is_some_and(|v| v == "1" || v == "true") => {
+            Ok(Json(serde_json::json!({ "playing": false })))
+        }
+        None => Err(ApiError::not_found("No track is playing for this session")),
+    }
+}
+
+/// `GET /np?session=...`: what a session plays as one line of text,
+/// `Artist - Title (mm:ss/mm:ss)`, for terminals and status bars.
+#[utoipa::path(
+    get,
+    path = "/np",
+    params(
+        ("session" = String, Query, description = "Session id the stream was opened with"),
+        ("client_id" = Option<String>, Query, description = "Listener that opened the stream"),
+    ),
+    responses(
+        (status = 200, description = "`Artist - Title (elapsed/duration)`", content_type = "text/plain"),
+        (status = 404, description = "No track is playing for this session", content_type = "text/plain"),
+    ),
+)]
+async fn now_playing_text(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let session_id = params.get("session").map(|s| s.as_str()).unwrap_or("");
+    let client_id = params.get("client_id").map(|s| s.as_str()).unwrap_or("anon");
+    let text_plain = [(header::CONTENT_TYPE, "text/plain; charset=utf-8")];
+
+    let sessions = state.sessions.lock().unwrap();
+    let Some(info) = owned_session(&sessions, session_id, client_id) else {
+        return (StatusCode::NOT_FOUND, text_plain, "Nothing is playing for this session\n").into_response();
+    };
+    let elapsed = info.started_at.elapsed().unwrap_or(Duration::from_secs(0)).as_millis() as u64;
+    (text_plain, format!("{}\n", now_playing_line(&info.track, elapsed))).into_response()
+}
+
+/// `Artist - Title (elapsed/duration)`; just the elapsed time when the length is unknown.
+fn now_playing_line(track: &Track, elapsed_ms: u64) -> String {
+    let position = if track.duration > 0 {
+        format!("{}/{}", format_clock(elapsed_ms.min(track.duration)), format_clock(track.duration))
+    } else {
+        format_clock(elapsed_ms)
+    };
+    format!("{} - {} ({})", track.artist, track.title, position)
+}
+
+/// `mm:ss`, or `h:mm:ss` from an hour up.
+fn format_clock(ms: u64) -> String {
+    let secs = ms / 1000;
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+/// A session's state, if `client_id` is the listener that opened it. Session ids come
+/// from clients and can collide (or be guessed); only the owner gets to see what it plays.
+fn owned_session<'a>(
+    sessions: &'a HashMap<String, SessionInfo>,
+    session_id: &str,
+    client_id: &str,
+) -> Option<&'a SessionInfo> {
+    sessions.get(session_id).filter(|info| info.client_id == client_id)
+}
+
+/// The `/now-playing` answer for a session, or `None` when nothing is playing.
+fn now_playing_body(state: &AppState, session_id: &str, client_id: &str) -> Option<serde_json::Value> {
+    let sessions = state.sessions.lock().unwrap();
+    let info @ SessionInfo { track, started_at, bitrate, mode, .. } = owned_session(&sessions, session_id, client_id)?;
+
+    let elapsed = started_at.elapsed().unwrap_or(Duration::from_secs(0)).as_millis() as u64;
+    let history = state.history.as_ref()
+        .and_then(|history| history.lock().unwrap().get(client_id).cloned())
+        .unwrap_or_default();
+    // Skip the first element of history as it is the current track
+    let previous_tracks: Vec<Track> = history.into_iter().skip(1).collect();
+
+    let mut body = serde_json::json!({
+        "playing": true,
+        "title": track.title,
+        "key": track.key,
+        "artist": track.artist,
+        "duration": track.duration,
+        "elapsed": elapsed,
+        "bitrate": bitrate,
+        "mode": mode,
+        "history": previous_tracks
+    });
+    // "Up next", when the order is known (same rules as /upcoming)
+    if let Some(next) = upcoming_for(state, session_id, info, 1).ok().and_then(|tracks| tracks.into_iter().next()) {
+        body["next"] = serde_json::json!({
+            "key": next.key,
+            "title": next.title,
+            "artist": next.artist,
+            "art_url": format!("/track/art?key={}", url_encode(&next.key)),
+        });
+    }
+    Some(body)
+}
+
+/// `GET /events?session=...`: Server-Sent Events for a session. A `now-playing` event
+/// (the `/now-playing` JSON, or `{"playing": false}`) is sent on connect, on every track
+/// change, after a resume and when the stream ends. The stream may be opened before the
+/// session has started.
+#[utoipa::path(
+    get,
+    path = "/events",
+    params(
+        ("session" = String, Query, description = "Session id to follow"),
+        ("client_id" = Option<String>, Query, description = "Listener whose history to include"),
+    ),
+    responses(
+        (status = 200, description = "`now-playing` events", content_type = "text/event-stream"),
+        (status = 400, description = "Missing session", body = ErrorBody),
+    ),
+)]
+async fn now_playing_events(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, std::convert::Infallible>>>, ApiError> {
+    let session_id = params.get("session").filter(|s| !s.is_empty()).cloned()
+        .ok_or_else(|| ApiError::bad_request("Missing `session`"))?;
+    let client_id = params.get("client_id").cloned().unwrap_or_else(|| "anon".to_string());
+
+    // Subscribed before the first event, so no change can slip in between
+    let mut changes = state.track_changes.subscribe();
+    let watched = session_id.clone();
+    let event = move |state: &AppState| {
+        let body = now_playing_body(state, &session_id, &client_id)
+            .unwrap_or_else(|| serde_json::json!({ "playing": false }));
+        Event::default().event("now-playing").data(body.to_string())
+    };
+    let stream = async_stream::stream! {
+        yield Ok(event(&state));
+        loop {
+            match changes.recv().await {
+                Ok(changed) if changed == watched => yield Ok(event(&state)),
+                Ok(_) => {}
+                // Too slow to keep up; whatever was missed, the current state is what counts
+                Err(broadcast::error::RecvError::Lagged(_)) => yield Ok(event(&state)),
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    };
+    Ok(Sse::new(stream).keep_alive(KeepAlive::new().interval(SSE_KEEPALIVE)))
+}
+
+/// Shuffle weight for a feedback score (1.0 when unrated).
+fn feedback_weight(score: i32) -> f64 {
+    FEEDBACK_WEIGHT_STEP.powi(score)
+}
+
+/// Rates a track: `POST /feedback?key=...&rating=up|down`. Ratings from all clients add
+/// up to one score per track, which makes plain shuffle pick it more or less often.
+#[utoipa::path(
+    post,
+    path = "/feedback",
+    params(
+        ("key" = String, Query, description = "Track to rate"),
+        ("rating" = String, Query, description = "`up` or `down`"),
+    ),
+    responses(
+        (status = 200, description = "The track's new `score` and shuffle `weight`", body = serde_json::Value),
+        (status = 400, description = "Missing key or invalid rating", body = ErrorBody),
+        (status = 404, description = "Unknown track key", body = ErrorBody),
+    ),
+)]
+async fn submit_feedback(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let key = params.get("key").filter(|k| !k.is_empty())
+        .ok_or_else(|| ApiError::bad_request("Missing track `key`"))?;
+    let delta = match params.get("rating").map(|s| s.as_str()) {
+        Some("up") => 1,
+        Some("down") => -1,
+        _ => return Err(ApiError::bad_request("`rating` must be `up` or `down`")),
+    };
+    if !state.library().tracks.iter().any(|t| t.key == *key) {
+        return Err(ApiError::not_found(format!("Unknown track key {}", key)));
+    }
+
+    let mut feedback = state.feedback.lock().unwrap();
+    let score = feedback.entry(key.clone()).or_default();
+    *score = (*score + delta).clamp(-FEEDBACK_MAX_SCORE, FEEDBACK_MAX_SCORE);
+    let score = *score;
+    // Back at neutral is the same as never rated
+    if score == 0 {
+        feedback.remove(key);
+    }
+    debug!("Feedback for {}: score {}", key, score);
+
+    Ok(Json(serde_json::json!({ "key": key, "score": score, "weight": feedback_weight(score) })))
+}
+
+/// Blocks a track (`key`) or an artist (`artist`) for a client:
+/// `POST /block?client_id=...&key=...`. Blocked items are skipped by shuffle/sequential play.
+#[utoipa::path(
+    post,
+    path = "/block",
+    params(
+        ("client_id" = String, Query, description = "Listener the block applies to"),
+        ("key" = Option<String>, Query, description = "Track to block"),
+        ("artist" = Option<String>, Query, description = "Artist to block"),
+    ),
+    responses(
+        (status = 200, description = "The client's updated block list", body = serde_json::Value),
+        (status = 400, description = "Missing client_id, or neither key nor artist", body = ErrorBody),
+        (status = 404, description = "Unknown track or artist", body = ErrorBody),
+    ),
+)]
+async fn block_item(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    update_block_list(&state, &params, true)
+}
+
+/// The station a client last tuned in to, for `/preferences`.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+struct StationPreferences {
+    /// `radio`, `album`, `playlist` or `auto`
+    station: String,
+    genre: Option<String>,
+    profile: Option<String>,
+    /// Reopens the station: `/radio` with its filters, album or playlist, shuffle and profile
+    url: String,
+    /// When it was tuned in to (Unix seconds)
+    saved_at: u64,
+}
+
+/// `GET /preferences?client_id=...`: the station the client last tuned in to, so a
+/// web UI can pick it up again on reload. Kept in memory for 30 days.
+#[utoipa::path(
+    get,
+    path = "/preferences",
+    params(
+        ("client_id" = String, Query, description = "Listener whose station to return"),
+    ),
+    responses(
+        (status = 200, description = "The client's last station", body = StationPreferences),
+        (status = 400, description = "Missing client_id", body = ErrorBody),
+        (status = 404, description = "Nothing saved for this client (or DISABLE_HISTORY is set)", body = ErrorBody),
+    ),
+)]
+async fn client_preferences(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<StationPreferences>, ApiError> {
+    let client_id = params.get("client_id")
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| ApiError::bad_request("Missing client_id"))?;
+    state.preferences.as_ref()
+        .and_then(|preferences| {
+            let map = preferences.lock().ok()?;
+            let (saved_at, saved) = map.get(client_id)?;
+            (saved_at.elapsed() < PREFERENCES_TTL).then(|| saved.clone())
+        })
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("No station saved for this client"))
+}
+
+/// Removes a block again: `DELETE /block?client_id=...&key=...` (or `&artist=...`).
+#[utoipa::path(
+    delete,
+    path = "/block",
+    params(
+        ("client_id" = String, Query, description = "Listener the block applies to"),
+        ("key" = Option<String>, Query, description = "Track to unblock"),
+        ("artist" = Option<String>, Query, description = "Artist to unblock"),
+    ),
+    responses(
+        (status = 200, description = "The client's updated block list", body = serde_json::Value),
+        (status = 400, description = "Missing client_id, or neither key nor artist", body = ErrorBody),
+        (status = 404, description = "Unknown track or artist", body = ErrorBody),
+    ),
+)]
+async fn unblock_item(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    update_block_list(&state, &params, false)
+}
+
+fn update_block_list(
+    state: &AppState,
+    params: &HashMap<String, String>,
+    block: bool,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let client_id = params.get("client_id")
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| ApiError::bad_request("Missing client_id"))?;
+
+    let library = state.library();
+    let mut map = state.blocked.lock().unwrap();
+    let list = map.entry(client_id.clone()).or_default();
+
+    if let Some(key) = params.get("key") {
+        if !library.tracks.iter().any(|t| t.key == *key) {
+            return Err(ApiError::not_found(format!("Unknown track key {}", key)));
+        }
+        if block {
+            list.keys.insert(key.clone());
+        } else {
+            list.keys.remove(key);
+        }
+    } else if let Some(artist) = params.get("artist") {
+        let artist = artist.trim().to_lowercase();
+        if !library.tracks.iter().any(|t| t.artist.to_lowercase() == artist) {
+            return Err(ApiError::not_found(format!("Unknown artist {}", artist)));
+        }
+        if block {
+            list.artists.insert(artist);
+        } else {
+            list.artists.remove(&artist);
+        }
+    } else {
+        return Err(ApiError::bad_request("Provide a track `key` or an `artist`"));
+    }
+
+    Ok(Json(serde_json::json!({
+        "client_id": client_id,
+        "blocked_tracks": list.keys.len(),
+        "blocked_artists": list.artists.len(),
+    })))
+}
+
+/// Lines up a track to play next on a session: `POST /queue?session=...&key=...`.
+/// Requests play in order ahead of the station, which then carries on as before.
+#[utoipa::path(
+    post,
+    path = "/queue",
+    params(
+        ("session" = String, Query, description = "Session id of an open stream"),
+        ("client_id" = Option<String>, Query, description = "Listener that opened the stream"),
+        ("key" = String, Query, description = "Track to add (ratingKey)"),
+    ),
+    responses(
+        (status = 200, description = "The session's pending `tracks`", body = serde_json::Value),
+        (status = 400, description = "Missing key", body = ErrorBody),
+        (status = 404, description = "No open stream for this session, or unknown track", body = ErrorBody),
+        (status = 409, description = "The queue is full", body = ErrorBody),
+    ),
+)]
+async fn enqueue_track(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let session_id = open_session(&state, &params)?;
+    let key = params.get("key").filter(|k| !k.is_empty())
+        .ok_or_else(|| ApiError::bad_request("Missing track `key`"))?;
+    let track = state.library().tracks.iter()
+        .find(|t| t.key == *key && state.is_playable(t))
+        .cloned()
+        .ok_or_else(|| ApiError::not_found(format!("Unknown track key {}", key)))?;
+
+    let mut queues = state.queues.lock().unwrap();
+    let queue = queues.entry(session_id.clone()).or_default();
+    if queue.len() >= QUEUE_MAX_LEN {
+        return Err(ApiError::conflict(format!("The queue already holds {} tracks", QUEUE_MAX_LEN)));
+    }
+    queue.push_back(track);
+    debug!("Queued {} for session {} ({} pending)", key, session_id, queue.len());
+
+    Ok(Json(serde_json::json!({ "session": session_id, "tracks": queue })))
+}
+
+/// `GET /queue?session=...`: the tracks still lined up for a session.
+#[utoipa::path(
+    get,
+    path = "/queue",
+    params(
+        ("session" = String, Query, description = "Session id of an open stream"),
+        ("client_id" = Option<String>, Query, description = "Listener that opened the stream"),
+    ),
+    responses(
+        (status = 200, description = "The session's pending `tracks`", body = serde_json::Value),
+        (status = 404, description = "No open stream for this session", body = ErrorBody),
+    ),
+)]
+async fn queue_info(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let session_id = open_session(&state, &params)?;
+    let tracks = state.queued_requests(session_id);
+    Ok(Json(serde_json::json!({ "session": session_id, "tracks": tracks })))
+}
+
+/// `DELETE /queue?session=...`: drops everything lined up for a session.
+#[utoipa::path(
+    delete,
+    path = "/queue",
+    params(
+        ("session" = String, Query, description = "Session id of an open stream"),
+        ("client_id" = Option<String>, Query, description = "Listener that opened the stream"),
+    ),
+    responses(
+        (status = 200, description = "How many tracks were `cleared`", body = serde_json::Value),
+        (status = 404, description = "No open stream for this session", body = ErrorBody),
+    ),
+)]
+async fn clear_queue(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let session_id = open_session(&state, &params)?;
+    let cleared = state.queues.lock().unwrap().remove(session_id).map_or(0, |q| q.len());
+    Ok(Json(serde_json::json!({ "session": session_id, "cleared": cleared })))
+}
+
+/// The `session` param, if it names an open `/radio` stream of the `client_id` param.
+fn open_session<'a>(state: &AppState, params: &'a HashMap<String, String>) -> Result<&'a String, ApiError> {
+    let session_id = params.get("session").filter(|s| !s.is_empty())
+        .ok_or_else(|| ApiError::not_found("No open stream for this session"))?;
+    let client_id = params.get("client_id").map(|s| s.as_str()).unwrap_or("anon");
+    if owned_control(&state.session_controls.lock().unwrap(), session_id, client_id).is_none() {
+        return Err(ApiError::not_found("No open stream for this session"));
+    }
+    Ok(session_id)
+}
+
+/// Whether another client has `session_id` open, so a new stream can't use it.
+fn session_taken(state: &AppState, session_id: &str, client_id: &str) -> bool {
+    let other = |owner: &str| owner != client_id;
+    state.session_controls.lock().unwrap().get(session_id).is_some_and(|c| other(&c.client_id))
+        || state.sessions.lock().unwrap().get(session_id).is_some_and(|s| other(&s.client_id))
+}
+
+/// Previews the next tracks of a session without advancing playback.
+/// Only possible when the order is deterministic (album, sequential or seeded mode).
+#[utoipa::path(
+    get,
+    path = "/upcoming",
+    params(
+        ("session" = String, Query, description = "Session id the stream was opened with"),
+        ("client_id" = Option<String>, Query, description = "Listener that opened the stream"),
+        ("count" = Option<usize>, Query, description = "How many tracks to preview (default 5, at most 50)"),
+    ),
+    responses(
+        (status = 200, description = "`available` and the next `tracks`", body = serde_json::Value),
+        (status = 404, description = "No track is playing for this session", body = ErrorBody),
+    ),
+)]
+async fn upcoming_tracks(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let session_id = params.get("session").map(|s| s.as_str()).unwrap_or("");
+    let count = params.get("count")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(5)
+        .min(50);
+
+    let client_id = params.get("client_id").map(|s| s.as_str()).unwrap_or("anon");
+    let sessions = state.sessions.lock().unwrap();
+    let info = owned_session(&sessions, session_id, client_id)
+        .ok_or_else(|| ApiError::not_found("No track is playing for this session"))?;
+
+    let body = match upcoming_for(&state, session_id, info, count) {
+        Ok(tracks) => serde_json::json!({ "available": true, "tracks": tracks }),
+        Err(note) => serde_json::json!({ "available": false, "note": note, "tracks": [] }),
+    };
+    Ok(Json(body))
+}
+
+/// The next `count` tracks of a session, without advancing it: requests from
+/// `POST /queue`, then the rest of the album or playlist, else the station's own order.
+/// Errors (with a note) when nothing of that is known.
+fn upcoming_for(state: &AppState, session_id: &str, info: &SessionInfo, count: usize) -> Result<Vec<Track>, &'static str> {
+    let mut upcoming = state.queued_requests(session_id);
+    upcoming.truncate(count);
+    let rest = count - upcoming.len();
+    match station_upcoming(state, info, rest) {
+        Ok(tracks) => upcoming.extend(tracks),
+        Err(note) if upcoming.is_empty() => return Err(note),
+        // Requests are known even when the station's own order isn't
+        Err(_) => {}
+    }
+    Ok(upcoming)
+}
+
+fn station_upcoming(state: &AppState, info: &SessionInfo, count: usize) -> Result<Vec<Track>, &'static str> {
+    if !info.queued.is_empty() {
+        // Album mode: only the rest of the current album is known
+        return Ok(info.queued.iter().take(count).cloned().collect());
+    }
+
+    match &info.selector {
+        Some(selector) if selector.is_predictable() => {
+            let library = state.library();
+            let candidates = state.station_candidates(&library, &selector.filter);
+            if candidates.is_empty() {
+                return Err("The station no longer has any tracks");
+            }
+            Ok(selector.peek(candidates.len(), count)
+                .into_iter()
+                .map(|pos| library.tracks[candidates[pos]].clone())
+                .collect())
+        }
+        _ => Err("Upcoming tracks are unknown in random mode"),
+    }
+}
+
+/// Liveness check: `OK`, or why Plex's transcoder looks unavailable. Still a 200,
+/// since restarting this server won't fix Plex.
+async fn health(State(state): State<AppState>) -> String {
+    let Some(reason) = state.transcoder.unavailable.lock().ok().and_then(|u| u.clone()) else {
+        return "OK".to_string();
+    };
+    let action = if state.transcoder.fallen_back.load(Ordering::Relaxed) {
+        "playing files directly (AUTO_PASSTHROUGH_ON_TRANSCODE_FAIL)"
+    } else {
+        "set PLEX_PASSTHROUGH=true to play files directly"
+    };
+    format!("DEGRADED: Plex's transcoder looks unavailable ({}); {}\n", reason, action)
+}
+
+/// Non-sensitive settings the web UI uses to adapt its controls, plus the stations it
+/// can offer: the whole library, the playlist and daypart schedule when configured, and
+/// one per genre among the tracks the server will play.
+#[utoipa::path(
+    get,
+    path = "/config",
+    responses((status = 200, description = "Settings the web UI adapts to and the stations it can offer", body = serde_json::Value)),
+)]
+async fn client_config(State(state): State<AppState>) -> impl IntoResponse {
+    let library = state.library();
+    // Genre stations match case-insensitively, so spellings that differ only in case are
+    // one genre, named as it was first seen
+    let mut genres: Vec<(String, usize)> = Vec::new();
+    let mut genre_index: HashMap<String, usize> = HashMap::new();
+    for track in library.tracks.iter().filter(|t| state.is_playable(t)) {
+        for genre in &track.genres {
+            let i = *genre_index.entry(genre.to_lowercase()).or_insert_with(|| {
+                genres.push((genre.clone(), 0));
+                genres.len() - 1
+            });
+            genres[i].1 += 1;
+        }
+    }
+    genres.sort_by_cached_key(|(name, _)| name.to_lowercase());
+
+    let mut stations = vec![serde_json::json!({ "id": "library", "name": "Library Shuffle", "path": "/radio" })];
+    if let Some(id) = &state.library_source.playlist_id {
+        stations.push(serde_json::json!({ "id": "playlist", "name": "Playlist (in order)", "path": format!("/radio/playlist?id={}", url_encode(id)) }));
+    }
+    if !state.dayparts.parts.is_empty() {
+        stations.push(serde_json::json!({ "id": "auto", "name": "Time of Day", "path": "/radio/auto" }));
+    }
+    stations.extend(genres.iter().map(|(name, tracks)| serde_json::json!({
+        "id": format!("genre:{}", name.to_lowercase()),
+        "name": name,
+        "path": format!("/radio?genre={}", url_encode(name)),
+        "tracks": tracks,
+    })));
+
+    Json(serde_json::json!({
+        "bitrate": state.bitrate,
+        "allowed_bitrates": ALLOWED_BITRATES,
+        "passthrough": state.plays_passthrough(),
+        "protocol": state.protocol.as_str(),
+        "default_shuffle": state.default_shuffle,
+        "enable_visualizer": state.enable_visualizer,
+        "search_formats": ["json", "csv", "text"],
+        "genres": genres.iter()
+            .map(|(name, tracks)| serde_json::json!({ "name": name, "tracks": tracks }))
+            .collect::<Vec<_>>(),
+        "stations": stations,
+    }))
+}
+
+/// Unknown paths (mostly crawlers and probes) get a JSON 404 instead of an empty body.
+async fn route_not_found(uri: Uri) -> ApiError {
+    debug!("No route for {}", uri.path());
+    ApiError::not_found(format!("No such endpoint: {}", uri.path()))
+}
+
+/// Which build is running and how it's configured, for verifying deploys and bug reports.
+/// Secrets (the Plex token) are never included.
+#[utoipa::path(
+    get,
+    path = "/version",
+    responses((status = 200, description = "Version, git commit, build time and non-secret configuration", body = serde_json::Value)),
+)]
+async fn version_info(State(state): State<AppState>) -> impl IntoResponse {
+    Json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_sha": env!("PLEX_RADIO_GIT_SHA"),
+        "build_timestamp": env!("PLEX_RADIO_BUILD_TIMESTAMP").parse::<u64>().unwrap_or(0),
+        "features": { "hls": cfg!(feature = "hls") },
+        "config": {
+            "plex_url": state.plex_url,
+            "bitrate": state.bitrate,
+            "audio_boost": state.audio_boost,
+            "sample_rate": state.output_format.sample_rate,
+            "transcode_context": state.transcode_context,
+            "media_version": state.media_version.as_str(),
+            "channels": state.output_format.channels,
+            "audio_profiles": state.audio_profiles.names(),
+            "default_profile": state.audio_profiles.default,
+            "passthrough": state.passthrough,
+            "disk_cache": state.disk_cache.is_some(),
+            "protocol": state.protocol.as_str(),
+            "max_concurrent_streams": state.max_streams,
+            "client_identifier": state.plex_client.identifier,
+            "product": state.plex_client.product,
+            "tracks_loaded": state.library().tracks.len(),
+        },
+    }))
+}
+
+/// Aggregate numbers about the cached library (also handy to confirm it fully loaded).
+#[utoipa::path(
+    get,
+    path = "/stats/library",
+    responses((status = 200, description = "Track, artist and duration totals plus the top artists", body = serde_json::Value)),
+)]
+async fn library_stats(State(state): State<AppState>) -> impl IntoResponse {
+    let library = state.library();
+    let mut per_artist: HashMap<&str, usize> = HashMap::new();
+    for t in library.tracks.iter() {
+        *per_artist.entry(t.artist.as_str()).or_default() += 1;
+    }
+
+    let total_tracks = library.tracks.len();
+    let total_duration: u64 = library.tracks.iter().map(|t| t.duration).sum();
+    let average_duration = if total_tracks > 0 { total_duration / total_tracks as u64 } else { 0 };
+
+    let mut top_artists: Vec<(&str, usize)> = per_artist.iter().map(|(a, c)| (*a, *c)).collect();
+    // Most tracks first; ties broken by name so the output is stable
+    top_artists.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    top_artists.truncate(10);
+
+    Json(serde_json::json!({
+        "total_tracks": total_tracks,
+        "total_artists": per_artist.len(),
+        "total_duration": total_duration,
+        "average_duration": average_duration,
+        "top_artists": top_artists.into_iter()
+            .map(|(artist, tracks)| serde_json::json!({ "artist": artist, "tracks": tracks }))
+            .collect::<Vec<_>>(),
+    }))
+}
+
+/// Checks the `Authorization: Bearer <ADMIN_TOKEN>` header of an /admin request.
+fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(expected) = &state.admin_token else {
+        return Err(ApiError::forbidden("Admin endpoints are disabled; set ADMIN_TOKEN to enable them"));
+    };
+    let provided = headers.get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match provided {
+        Some(token) if token == expected => Ok(()),
+        _ => Err(ApiError::unauthorized("Missing or wrong admin token")),
+    }
+}
+
+/// `POST /admin/refresh`: reloads the track cache from Plex now (e.g. after adding music).
+/// Listeners keep playing from the old cache until the new one is swapped in.
+#[utoipa::path(
+    post,
+    path = "/admin/refresh",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "New and previous track counts", body = serde_json::Value),
+        (status = 401, description = "Missing or wrong ADMIN_TOKEN", body = ErrorBody),
+        (status = 403, description = "Admin endpoints are disabled", body = ErrorBody),
+        (status = 409, description = "A refresh is already running", body = ErrorBody),
+        (status = 502, description = "Plex returned no tracks", body = ErrorBody),
+    ),
+)]
+async fn refresh_library(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    let Ok(_refreshing) = state.refresh_lock.try_lock() else {
+        return Err(ApiError::conflict("A library refresh is already running"));
+    };
+
+    info!("Library refresh requested by {}", addr);
+    let previous = state.library().tracks.len();
+    let mut tracks = load_library(&state.client, &state.plex_url, &state.plex_token, &state.library_source).await;
+    if state.dedup_tracks {
+        tracks = dedup_library(tracks);
+    }
+
+    // Far more likely a Plex hiccup than an emptied library, so keep what we have
+    if tracks.is_empty() {
+        warn!("Library refresh returned no tracks; keeping the current {} tracks", previous);
+        return Err(ApiError::bad_gateway("Plex returned no tracks; the current library was kept"));
+    }
+
+    if state.block_explicit {
+        report_explicit(&tracks);
+    }
+    let count = tracks.len();
+    *state.library.write().unwrap() = Arc::new(Library::new(tracks));
+    info!("Library refreshed by {}: {} -> {} tracks", addr, previous, count);
+
+    Ok(Json(serde_json::json!({ "tracks": count, "previous_tracks": previous })))
+}
+
+/// `POST /pause?session=...`: stops reading from Plex for a session while its listener
+/// is paused, keeping the transcode alive, so a long pause doesn't skip the track.
+#[utoipa::path(
+    post,
+    path = "/pause",
+    params(
+        ("session" = String, Query, description = "Session id the stream was opened with"),
+        ("client_id" = Option<String>, Query, description = "Listener that opened the stream"),
+    ),
+    responses(
+        (status = 200, description = "Session paused", body = serde_json::Value),
+        (status = 404, description = "No stream is open for this session", body = ErrorBody),
+    ),
+)]
+async fn pause_session(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    set_paused(&state, &params, true)
+}
+
+/// `POST /resume?session=...`: continues a paused session where it stopped.
+#[utoipa::path(
+    post,
+    path = "/resume",
+    params(
+        ("session" = String, Query, description = "Session id the stream was opened with"),
+        ("client_id" = Option<String>, Query, description = "Listener that opened the stream"),
+    ),
+    responses(
+        (status = 200, description = "Session resumed", body = serde_json::Value),
+        (status = 404, description = "No stream is open for this session", body = ErrorBody),
+    ),
+)]
+async fn resume_session(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    set_paused(&state, &params, false)
+}
+
+fn set_paused(
+    state: &AppState,
+    params: &HashMap<String, String>,
+    paused: bool,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let session_id = params.get("session").map(|s| s.as_str()).unwrap_or("");
+    let client_id = params.get("client_id").map(|s| s.as_str()).unwrap_or("anon");
+    let controls = state.session_controls.lock().unwrap();
+    let Some(control) = owned_control(&controls, session_id, client_id) else {
+        return Err(ApiError::not_found("No stream is open for this session"));
+    };
+    control.paused.send_replace(paused);
+    Ok(Json(serde_json::json!({ "session": session_id, "paused": paused })))
+}
+
+/// `POST /admin/stop-all`: ends every open /radio stream (e.g. at closing time).
+/// Clients see the stream finish normally and may reconnect.
+#[utoipa::path(
+    post,
+    path = "/admin/stop-all",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "How many streams were stopped", body = serde_json::Value),
+        (status = 401, description = "Missing or wrong ADMIN_TOKEN", body = ErrorBody),
+        (status = 403, description = "Admin endpoints are disabled", body = ErrorBody),
+    ),
+)]
+async fn stop_all_streams(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+
+    // Each stream removes its own entries as it tears down; clear them now regardless
+    let stopped = {
+        let mut controls = state.session_controls.lock().unwrap();
+        for control in controls.values() {
+            control.stop.notify_one();
+        }
+        controls.drain().count()
+    };
+    state.sessions.lock().unwrap().clear();
+    warn!("All streams stopped by {}: {} sessions ended", addr, stopped);
+
+    Ok(Json(serde_json::json!({ "stopped": stopped })))
+}
+
+/// `GET /admin/debug/transcode?key=...`: the Plex request a stream of the track would
+/// make (transcode or passthrough), without making it, so a failing track can be tried
+/// by hand with `curl`. The token and `PLEX_EXTRA_HEADERS` values are redacted unless
+/// `reveal_token=1`.
+#[utoipa::path(
+    get,
+    path = "/admin/debug/transcode",
+    security(("admin_token" = [])),
+    params(
+        ("key" = String, Query, description = "Track ratingKey"),
+        ("offset" = Option<u64>, Query, description = "Start offset (ms)"),
+        ("bitrate" = Option<u32>, Query, description = "Max transcode bitrate: 128, 192, 256 or 320"),
+        ("profile" = Option<String>, Query, description = "Audio profile from AUDIO_PROFILES (default DEFAULT_PROFILE); `bitrate` still overrides its bitrate"),
+        ("media_index" = Option<usize>, Query, description = "Media version (default: MEDIA_VERSION)"),
+        ("part_index" = Option<usize>, Query, description = "File within the media version (default 0)"),
+        ("reveal_token" = Option<bool>, Query, description = "`1` shows the Plex token and extra headers instead of redacting them"),
+    ),
+    responses(
+        (status = 200, description = "Mode, URL, query params, headers and a curl command", body = serde_json::Value),
+        (status = 400, description = "Missing `key` or bad media/part index", body = ErrorBody),
+        (status = 401, description = "Missing or wrong ADMIN_TOKEN", body = ErrorBody),
+        (status = 403, description = "Admin endpoints are disabled", body = ErrorBody),
+        (status = 404, description = "Unknown track key", body = ErrorBody),
+        (status = 502, description = "Plex has no file for the track (passthrough)", body = ErrorBody),
+    ),
+)]
+async fn debug_transcode(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    require_admin(&state, &headers)?;
+    let key = params.get("key").filter(|k| !k.is_empty())
+        .ok_or_else(|| ApiError::bad_request("Missing track `key`"))?;
+    if !state.library().tracks.iter().any(|t| t.key == *key) {
+        return Err(ApiError::not_found(format!("Unknown track key {}", key)));
+    }
+    let media = MediaIndexes::from_params(&params).map_err(ApiError::bad_request)?;
+    let offset_ms = parse_offset_ms(params.get("offset"));
+    let profile = requested_profile(&state, &params).map_err(ApiError::bad_request)?;
+
+    let TrackRequest { first: request, rest, mode } = prepare_track_request(&state, key, "debug", offset_ms, &profile, media).await
+        .ok_or_else(|| ApiError::bad_gateway("Plex returned no file for this track"))?;
+    let request = request.build()
+        .map_err(|e| ApiError::bad_request(format!("Couldn't build the Plex request: {}", e)))?;
+
+    let reveal = params.get("reveal_token").is_some_and(|v| v == "1" || v == "true");
+    // The token goes in a header (and with TOKEN_IN_QUERY a param), and URL-encoded
+    // inside the transcoder's `path`
+    let token_param = format!("X-Plex-Token={}", url_encode(&state.plex_token.value));
+    let query: Vec<(String, String)> = request.url().query_pairs()
+        .map(|(name, value)| {
+            let value = match () {
+                _ if reveal => value.into_owned(),
+                _ if name == "X-Plex-Token" => "REDACTED".to_string(),
+                _ => value.replace(&token_param, "X-Plex-Token=REDACTED"),
+            };
+            (name.into_owned(), value)
+        })
+        .collect();
+    let mut url = request.url().clone();
+    if !query.is_empty() {
+        url.query_pairs_mut().clear().extend_pairs(&query);
+    }
+    // The client's defaults (user agent, PLEX_EXTRA_HEADERS) aren't part of the built
+    // request; the request's own headers win, as they do when reqwest sends it
+    let mut all_headers = (*state.extra_headers).clone();
+    if let Ok(agent) = reqwest::header::HeaderValue::from_str(&state.plex_client.user_agent()) {
+        all_headers.insert(reqwest::header::USER_AGENT, agent);
+    }
+    for name in request.headers().keys() {
+        all_headers.remove(name);
+    }
+    for (name, value) in request.headers() {
+        all_headers.append(name, value.clone());
+    }
+    let headers: Vec<(String, String)> = all_headers.iter()
+        .map(|(name, value)| {
+            let secret = name.as_str() == "x-plex-token" || value.is_sensitive();
+            let value = if secret && !reveal { "REDACTED" } else { value.to_str().unwrap_or_default() };
+            (name.to_string(), value.to_string())
+        })
+        .collect();
+
+    // Single quotes, with any inside closed, escaped and reopened
+    let quote = |s: &str| format!("'{}'", s.replace('\'', "'\\''"));
+    let mut curl = String::from("curl -sS -o track.out");
+    for (name, value) in &headers {
+        curl.push_str(&format!(" -H {}", quote(&format!("{}: {}", name, value))));
+    }
+    curl.push_str(&format!(" {}", quote(url.as_str())));
+
+    let mode = match mode {
+        StreamMode::Passthrough => "passthrough",
+        _ => "transcode",
+    };
+    Ok(Json(serde_json::json!({
+        "key": key,
+        "mode": mode,
+        "method": request.method().as_str(),
+        "url": url.as_str(),
+        "query": query.into_iter().collect::<HashMap<_, _>>(),
+        "headers": headers.into_iter().collect::<HashMap<_, _>>(),
+        "curl": curl,
+        // A file in several parts: the first part is shown, the others are played after it
+        "parts": rest.len() + 1,
+        "token_redacted": !reveal,
+    })))
+}
+
+/// Returns where a session currently is, so another client can pick up from there
+/// with `/radio?track=<key>&offset=<ms>`.
+#[utoipa::path(
+    get,
+    path = "/resume",
+    params(
+        ("session" = String, Query, description = "Session id the stream was opened with"),
+        ("client_id" = Option<String>, Query, description = "Listener that opened the stream"),
+    ),
+    responses(
+        (status = 200, description = "Track key, offset (ms) and a `resume_url`", body = serde_json::Value),
+        (status = 404, description = "No track is playing for this session", body = ErrorBody),
+    ),
+)]
+async fn resume_info(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let session_id = params.get("session").map(|s| s.as_str()).unwrap_or("");
+    let client_id = params.get("client_id").map(|s| s.as_str()).unwrap_or("anon");
+
+    let sessions = state.sessions.lock().unwrap();
+    match owned_session(&sessions, session_id, client_id) {
+        Some(SessionInfo { track, started_at, .. }) => {
+            let offset = started_at.elapsed().unwrap_or(Duration::from_secs(0)).as_millis() as u64;
+            let body = serde_json::json!({
+                "key": track.key,
+                "offset": offset,
+                "resume_url": track_url(&track.key, Some(offset)),
+            });
+            Ok(Json(body))
+        },
+        None => Err(ApiError::not_found("No track is playing for this session")),
+    }
+}
+
+/// Searches the cached track list for titles or artists matching the query, best
+/// matches first (see `Library::search`). Returns JSON by default; `?format=csv|text` (or an `Accept` header of
+/// `text/csv` / `text/plain`) switches to a script-friendly format.
+#[utoipa::path(
+    get,
+    path = "/search",
+    params(
+        ("q" = String, Query, description = "Text to find in titles or artists (at least 2 characters)"),
+        ("limit" = Option<usize>, Query, description = "Most results to return (default 50, at most 200)"),
+        ("format" = Option<String>, Query, description = "`json` (default), `csv` or `text`"),
+    ),
+    responses(
+        (status = 200, description = "Matching tracks", body = Vec<Track>),
+        (status = 400, description = "Query too short or invalid limit", body = ErrorBody),
+        (status = 503, description = "Library still loading", body = ErrorBody),
+    ),
+)]
+async fn search_tracks(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    if !state.is_ready() {
+        return Err(ApiError::warming_up());
+    }
+    let format = SearchFormat::negotiate(params.get("format").map(|s| s.as_str()), &headers);
+
+    let query = params.get("q").map(|s| s.to_lowercase()).unwrap_or_default();
+    if query.chars().count() < 2 {
+        return Err(ApiError::bad_request("Query `q` must be at least 2 characters"));
+    }
+    let limit = match params.get("limit") {
+        Some(raw) => raw.parse::<usize>().ok().filter(|&l| l > 0)
+            .ok_or_else(|| ApiError::bad_request("`limit` must be a positive number"))?
+            .min(SEARCH_LIMIT_MAX),
+        None => SEARCH_LIMIT_DEFAULT,
+    };
+
+    let library = state.library();
+    let results: Vec<Track> = library.search(&query).into_iter()
+        .map(|idx| &library.tracks[idx])
+        .filter(|t| state.is_playable(t))
+        .take(limit)
+        .cloned()
+        .collect();
+
+    Ok(match format {
+        SearchFormat::Json => Json(results).into_response(),
+        SearchFormat::Csv => {
+            let mut body = String::from("key,title,artist,duration\n");
+            for t in &results {
+                body.push_str(&format!(
+                    "{},{},{},{}\n",
+                    csv_field(&t.key),
+                    csv_field(&t.title),
+                    csv_field(&t.artist),
+                    t.duration
+                ));
+            }
+            ([(header::CONTENT_TYPE, "text/csv; charset=utf-8")], body).into_response()
+        }
+        SearchFormat::Text => {
+            let mut body = String::new();
+            for t in &results {
+                body.push_str(&format!("{}\t{}\t{}\n", tsv_field(&t.key), tsv_field(&t.title), tsv_field(&t.artist)));
+            }
+            ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body).into_response()
+        }
+    })
+}
+
+/// `GET /tracks?offset=0&limit=100&sort=title|artist|duration`: one page of the cached
+/// track list plus the `total`, for browse views. Without `sort`, library order is kept.
+/// `album=<ratingKey>` lists just that album's tracks.
+#[utoipa::path(
+    get,
+    path = "/tracks",
+    params(
+        ("offset" = Option<usize>, Query, description = "Tracks to skip (default 0)"),
+        ("limit" = Option<usize>, Query, description = "Page size (default 100, at most 500)"),
+        ("sort" = Option<String>, Query, description = "`title`, `artist` or `duration`; library order if unset"),
+        ("album" = Option<String>, Query, description = "Only this album's tracks (album ratingKey)"),
+    ),
+    responses(
+        (status = 200, description = "`total`, `offset`, `limit` and the page's `tracks`", body = serde_json::Value),
+        (status = 400, description = "Invalid offset, limit or sort", body = ErrorBody),
+        (status = 503, description = "Library still loading", body = ErrorBody),
+    ),
+)]
+async fn browse_tracks(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if !state.is_ready() {
+        return Err(ApiError::warming_up());
+    }
+    let (offset, limit) = page_params(&params)?;
+    let album = params.get("album").filter(|a| !a.is_empty());
+
+    let library = state.library();
+    let mut indexes: Vec<usize> = (0..library.tracks.len())
+        .filter(|&i| state.is_playable(&library.tracks[i]))
+        .filter(|&i| album.is_none_or(|key| library.tracks[i].album_key == *key))
+        .collect();
+    // Stable sorts, so equal titles/artists stay in library order
+    match params.get("sort").map(|s| s.as_str()) {
+        None => {}
+        Some("title") => indexes.sort_by(|&a, &b| library.search_index[a].0.cmp(&library.search_index[b].0)),
+        Some("artist") => indexes.sort_by(|&a, &b| {
+            let (a, b) = (&library.search_index[a], &library.search_index[b]);
+            a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0))
+        }),
+        Some("duration") => indexes.sort_by_key(|&i| library.tracks[i].duration),
+        Some(other) => {
+            return Err(ApiError::bad_request(format!(
+                "Unknown sort '{}'; expected title, artist or duration",
+                other
+            )))
+        }
+    }
+
+    let total = indexes.len();
+    let tracks: Vec<&Track> = indexes.iter()
+        .skip(offset)
+        .take(limit)
+        .map(|&i| &library.tracks[i])
+        .collect();
+
+    Ok(Json(serde_json::json!({
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+        "tracks": tracks,
+    })))
+}
+
+/// Reads `offset` and `limit` for the browse endpoints.
+fn page_params(params: &HashMap<String, String>) -> Result<(usize, usize), ApiError> {
+    let offset = match params.get("offset") {
+        Some(raw) => raw.parse::<usize>()
+            .map_err(|_| ApiError::bad_request("`offset` must be a non-negative number"))?,
+        None => 0,
+    };
+    let limit = match params.get("limit") {
+        Some(raw) => raw.parse::<usize>().ok().filter(|&l| l > 0)
+            .ok_or_else(|| ApiError::bad_request("`limit` must be a positive number"))?
+            .min(TRACKS_PAGE_MAX),
+        None => TRACKS_PAGE_DEFAULT,
+    };
+    Ok((offset, limit))
+}
+
+/// An artist in `/artists`.
+#[derive(Serialize, ToSchema)]
+struct ArtistSummary {
+    name: String,
+    tracks: usize,
+    albums: usize,
+}
+
+/// `GET /artists?offset=0&limit=100`: every artist in the cached library with their
+/// track and album counts, sorted by name. Artists differing only in case are one.
+#[utoipa::path(
+    get,
+    path = "/artists",
+    params(
+        ("offset" = Option<usize>, Query, description = "Artists to skip (default 0)"),
+        ("limit" = Option<usize>, Query, description = "Page size (default 100, at most 500)"),
+    ),
+    responses(
+        (status = 200, description = "`total`, `offset`, `limit` and the page's `artists`", body = serde_json::Value),
+        (status = 400, description = "Invalid offset or limit", body = ErrorBody),
+        (status = 503, description = "Library still loading", body = ErrorBody),
+    ),
+)]
+async fn browse_artists(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if !state.is_ready() {
+        return Err(ApiError::warming_up());
+    }
+    let (offset, limit) = page_params(&params)?;
+
+    let library = state.library();
+    // Lowercased name -> (name as first seen, track count, album keys)
+    let mut artists: HashMap<&str, (&str, usize, HashSet<&str>)> = HashMap::new();
+    for (track, (_, artist)) in library.tracks.iter().zip(library.search_index.iter()) {
+        if !state.is_playable(track) {
+            continue;
+        }
+        let entry = artists.entry(artist.as_str()).or_insert_with(|| (track.artist.as_str(), 0, HashSet::new()));
+        entry.1 += 1;
+        if !track.album_key.is_empty() {
+            entry.2.insert(track.album_key.as_str());
+        }
+    }
+    let mut artists: Vec<(&str, ArtistSummary)> = artists.into_iter()
+        .map(|(sort_key, (name, tracks, albums))| {
+            (sort_key, ArtistSummary { name: name.to_string(), tracks, albums: albums.len() })
+        })
+        .collect();
+    artists.sort_by(|a, b| a.0.cmp(b.0));
+
+    let total = artists.len();
+    let page: Vec<ArtistSummary> = artists.into_iter().skip(offset).take(limit).map(|(_, a)| a).collect();
+    Ok(Json(serde_json::json!({
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+        "artists": page,
+    })))
+}
+
+/// An album in `/albums`, grouped from the cached tracks.
+#[derive(Serialize, ToSchema)]
+struct AlbumSummary {
+    // Album ratingKey, for `/tracks?album=` and `/radio?album=`
+    key: String,
+    title: String,
+    artist: String,
+    year: Option<u32>,
+    tracks: usize,
+}
+
+/// `GET /albums?artist=&offset=0&limit=100`: albums in the cached library, optionally
+/// just one artist's (case-insensitive), sorted by artist, then year, then title.
+#[utoipa::path(
+    get,
+    path = "/albums",
+    params(
+        ("artist" = Option<String>, Query, description = "Only this artist's albums (case-insensitive)"),
+        ("offset" = Option<usize>, Query, description = "Albums to skip (default 0)"),
+        ("limit" = Option<usize>, Query, description = "Page size (default 100, at most 500)"),
+    ),
+    responses(
+        (status = 200, description = "`total`, `offset`, `limit` and the page's `albums`", body = serde_json::Value),
+        (status = 400, description = "Invalid offset or limit", body = ErrorBody),
+        (status = 404, description = "No albums by this artist", body = ErrorBody),
+        (status = 503, description = "Library still loading", body = ErrorBody),
+    ),
+)]
+async fn browse_albums(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if !state.is_ready() {
+        return Err(ApiError::warming_up());
+    }
+    let (offset, limit) = page_params(&params)?;
+    let artist = params.get("artist").map(|a| a.trim().to_lowercase()).filter(|a| !a.is_empty());
+
+    let library = state.library();
+    // Album key -> summary, in library order of first appearance
+    let mut albums: Vec<AlbumSummary> = Vec::new();
+    let mut positions: HashMap<&str, usize> = HashMap::new();
+    for (track, (_, track_artist)) in library.tracks.iter().zip(library.search_index.iter()) {
+        if track.album_key.is_empty() || !state.is_playable(track) {
+            continue;
+        }
+        if artist.as_ref().is_some_and(|a| a != track_artist) {
+            continue;
+        }
+        let position = *positions.entry(track.album_key.as_str()).or_insert_with(|| {
+            albums.push(AlbumSummary {
+                key: track.album_key.clone(),
+                title: track.album.clone().unwrap_or_else(|| "Unknown Album".to_string()),
+                artist: track.artist.clone(),
+                year: track.year,
+                tracks: 0,
+            });
+            albums.len() - 1
+        });
+        albums[position].tracks += 1;
+    }
+    if artist.is_some() && albums.is_empty() {
+        return Err(ApiError::not_found(format!("No albums by '{}'", params["artist"].trim())));
+    }
+    albums.sort_by(|a, b| {
+        a.artist.to_lowercase().cmp(&b.artist.to_lowercase())
+            .then(a.year.cmp(&b.year))
+            .then_with(|| a.title.to_lowercase().cmp(&b.title.to_lowercase()))
+    });
+
+    let total = albums.len();
+    let page: Vec<AlbumSummary> = albums.into_iter().skip(offset).take(limit).collect();
+    Ok(Json(serde_json::json!({
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+        "albums": page,
+    })))
+}
+
+/// `GET /random?genre=&artist=`: one random track matching the same filters as the
+/// stations, plus the URL that plays it, for "surprise me" buttons and bots.
+/// With a `client_id`, tracks and artists that client blocked are left out.
+#[utoipa::path(
+    get,
+    path = "/random",
+    params(
+        ("genre" = Option<String>, Query, description = "Only tracks with this genre (case-insensitive)"),
+        ("artist" = Option<String>, Query, description = "Only tracks by this artist (case-insensitive)"),
+        ("year_min" = Option<u32>, Query, description = "Earliest release year"),
+        ("year_max" = Option<u32>, Query, description = "Latest release year"),
+        ("dur_min" = Option<u64>, Query, description = "Shortest track length in seconds"),
+        ("dur_max" = Option<u64>, Query, description = "Longest track length in seconds"),
+        ("exclude_artist" = Option<Vec<String>>, Query, description = "Artist to leave out; repeat for several"),
+        ("clean" = Option<bool>, Query, description = "`true` leaves out tracks rated explicit"),
+        ("client_id" = Option<String>, Query, description = "Leave out what this client has blocked"),
+    ),
+    responses(
+        (status = 200, description = "The picked `track` and a `url` that plays it", body = serde_json::Value),
+        (status = 404, description = "No tracks match these filters", body = ErrorBody),
+        (status = 503, description = "Library still loading", body = ErrorBody),
+    ),
+)]
+async fn random_track(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+    Query(pairs): Query<Vec<(String, String)>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if !state.is_ready() {
+        return Err(ApiError::warming_up());
+    }
+    let filter = StationFilter::from_params(&params)
+        .map_err(ApiError::bad_request)?
+        .exclude_artists(&pairs);
+
+    let library = state.library();
+    let mut candidates = state.station_candidates(&library, &filter);
+    if let Some(client_id) = params.get("client_id") {
+        candidates.retain(|&idx| !state.is_blocked(client_id, &library.tracks[idx]));
+    }
+    let Some(&idx) = candidates.choose(&mut rand::thread_rng()) else {
+        return Err(ApiError::not_found("No tracks match these filters"));
+    };
+
+    let track = &library.tracks[idx];
+    Ok(Json(serde_json::json!({
+        "track": track,
+        "url": track_url(&track.key, None),
+    })))
+}
+
+/// `GET /track?key=...`: a cached track's metadata, enriched with album, year, genres
+/// and cover art from Plex, for clients that only have a key (e.g. from an M3U).
+#[utoipa::path(
+    get,
+    path = "/track",
+    params(("key" = String, Query, description = "ratingKey of the track")),
+    responses(
+        (status = 200, description = "The track with album and artwork details", body = TrackDetails),
+        (status = 404, description = "Unknown track key", body = ErrorBody),
+    ),
+)]
+async fn track_info(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Json<TrackDetails>, ApiError> {
+    Ok(Json(lookup_track_details(&state, &params).await?))
+}
+
+/// `GET /track/art?key=...`: the track's cover image, fetched from Plex.
+#[utoipa::path(
+    get,
+    path = "/track/art",
+    params(("key" = String, Query, description = "ratingKey of the track")),
+    responses(
+        (status = 200, description = "Cover image", content_type = "image/jpeg"),
+        (status = 404, description = "Unknown track, or no artwork", body = ErrorBody),
+        (status = 502, description = "Plex could not return the image", body = ErrorBody),
+    ),
+)]
+async fn track_art(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<Response, ApiError> {
+    let details = lookup_track_details(&state, &params).await?;
+    let Some(thumb) = details.thumb else {
+        return Err(ApiError::not_found("This track has no artwork"));
+    };
+
+    let resp = state.client.get(format!("{}{}", state.plex_url, thumb))
+        .plex_token(&state.plex_token)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| {
+            warn!("Failed to fetch artwork for track {}: {}", details.track.key, e);
+            ApiError::bad_gateway("Failed to fetch the artwork from Plex")
+        })?;
+    let content_type = resp.headers().get(header::CONTENT_TYPE).cloned()
+        .unwrap_or(header::HeaderValue::from_static("image/jpeg"));
+    let body = resp.bytes().await
+        .map_err(|_| ApiError::bad_gateway("Failed to fetch the artwork from Plex"))?;
+
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+/// Finds the track named by `key` and fills in its details from Plex (cached briefly).
+/// If Plex can't be reached, the cached track is returned without them.
+async fn lookup_track_details(
+    state: &AppState,
+    params: &HashMap<String, String>,
+) -> Result<TrackDetails, ApiError> {
+    if !state.is_ready() {
+        return Err(ApiError::warming_up());
+    }
+    let Some(key) = params.get("key").filter(|k| !k.is_empty()) else {
+        return Err(ApiError::bad_request("Missing `key`"));
+    };
+
+    let library = state.library();
+    let Some(track) = library.tracks.iter().find(|t| t.key == *key) else {
+        return Err(ApiError::not_found(format!("Unknown track key '{}'", key)));
+    };
+    if !state.is_playable(track) {
+        return Err(ApiError::forbidden("This track is not available here"));
+    }
+
+    if let Some((fetched_at, details)) = state.track_details.lock().unwrap().get(key) {
+        if fetched_at.elapsed() < TRACK_DETAILS_TTL {
+            return Ok(details.clone());
+        }
+    }
+
+    let mut details = TrackDetails { track: track.clone(), art_url: None, thumb: None };
+    let Some(meta) = fetch_track_metadata(state, key).await else {
+        return Ok(details);
+    };
+    if details.track.album.is_none() && !meta.album.is_empty() {
+        details.track.album = Some(meta.album);
+    }
+    if details.track.year.is_none() {
+        details.track.year = meta.year.or(meta.parent_year);
+    }
+    if details.track.genres.is_empty() {
+        details.track.genres = meta.genres.into_iter().map(|g| g.tag).collect();
+    }
+    details.thumb = meta.thumb.or(meta.parent_thumb);
+    if details.thumb.is_some() {
+        details.art_url = Some(format!("/track/art?key={}", key));
+    }
+
+    let mut cache = state.track_details.lock().unwrap();
+    cache.retain(|_, (fetched_at, _)| fetched_at.elapsed() < TRACK_DETAILS_TTL);
+    cache.insert(key.clone(), (Instant::now(), details.clone()));
+    Ok(details)
+}
+
+/// Lowercases every track's title and artist once, at warm time, for `search_tracks`.
+fn build_search_index(tracks: &[Track]) -> Vec<(String, String)> {
+    tracks.iter()
+        .map(|t| (t.title.to_lowercase(), t.artist.to_lowercase()))
+        .collect()
+}
+
+/// Output formats supported by /search.
+enum SearchFormat {
+    Json,
+    Csv,
+    Text,
+}
+
+impl SearchFormat {
+    /// An explicit `format` param wins over the `Accept` header; JSON is the default.
+    fn negotiate(format_param: Option<&str>, headers: &HeaderMap) -> Self {
+        if let Some(format) = format_param {
+            return match format {
+                "csv" => SearchFormat::Csv,
+                "text" | "txt" | "tsv" => SearchFormat::Text,
+                _ => SearchFormat::Json,
+            };
+        }
+
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        if accept.contains("text/csv") {
+            SearchFormat::Csv
+        } else if accept.contains("text/plain") {
+            SearchFormat::Text
+        } else {
+            SearchFormat::Json
+        }
+    }
+}
+
+/// Quotes a CSV field when it contains separators, quotes or line breaks.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Tabs and line breaks would break the tab-separated layout, so flatten them.
+fn tsv_field(value: &str) -> String {
+    value.replace(['\t', '\n', '\r'], " ")
+}
+
+// --- MP3 Frame Alignment ---
+
+/// Give up looking for a frame header after this much data (past any ID3 tag) and pass
+/// it through as-is.
+const MAX_FRAME_SCAN_BYTES: usize = 64 * 1024;
+
+/// Holds back the start of a track until the first MP3 frame header, dropping any
+/// leading ID3 tag or partial frame. Once aligned, chunks pass straight through.
+struct FrameAligner {
+    aligned: bool,
+    buf: Vec<u8>,
+    // Bytes of a leading ID3 tag still to come, dropped as they arrive
+    skip: usize,
+    // Header of the first frame, which the stream heartbeat copies its format from
+    header: Option<[u8; 4]>,
+}
+
+impl FrameAligner {
+    fn new(enabled: bool) -> Self {
+        FrameAligner { aligned: !enabled, buf: Vec::new(), skip: 0, header: None }
+    }
+
+    /// A silent frame in the track's own format, once its first frame has been seen.
+    fn silent_frame(&self) -> Option<Bytes> {
+        self.header.and_then(silent_mp3_frame)
+    }
+
+    /// Feeds a chunk from Plex; returns the bytes that are ready to send, if any.
+    fn push(&mut self, chunk: Bytes) -> Option<Bytes> {
+        if self.aligned {
+            return Some(chunk);
+        }
+
+        let skipped = self.skip.min(chunk.len());
+        self.skip -= skipped;
+        self.buf.extend_from_slice(&chunk[skipped..]);
+        // The tag's header gives its size, so the rest of it is dropped without scanning
+        while let Some(tag_len) = id3v2_tag_len(&self.buf) {
+            let dropped = tag_len.min(self.buf.len());
+            self.buf.drain(..dropped);
+            self.skip = tag_len - dropped;
+        }
+        if self.buf.len() < 10 && b"ID3".starts_with(&self.buf[..self.buf.len().min(3)]) {
+            // Maybe the start of a tag whose size hasn't arrived yet
+            return None;
+        }
+        let start = match find_mp3_frame_start(&self.buf) {
+            Some(start) => start,
+            None if self.buf.len() >= MAX_FRAME_SCAN_BYTES => {
+                warn!("No MP3 frame header in the first {} bytes; streaming unaligned", self.buf.len());
+                0
+            }
+            None => return None,
+        };
+
+        self.aligned = true;
+        self.header = self.buf.get(start..start + 4).and_then(|h| h.try_into().ok());
+        let buf = std::mem::take(&mut self.buf);
+        Some(Bytes::from(buf).slice(start..))
+    }
+}
+
+/// Holds back the first bytes of a connection until `PREBUFFER_BYTES` of audio have
+/// arrived, so clients that start playing on the first chunk don't underrun.
+struct Prebuffer {
+    // None once the buffered start has gone out (or when prebuffering is off)
+    buf: Option<Vec<u8>>,
+    target: usize,
+}
+
+impl Prebuffer {
+    fn new(target: usize) -> Self {
+        Prebuffer { buf: (target > 0).then(Vec::new), target }
+    }
+
+    /// Takes audio for the client: nothing while filling, then everything buffered so
+    /// far at once, then each chunk as it comes.
+    fn push(&mut self, bytes: Bytes) -> Option<Bytes> {
+        let Some(buf) = self.buf.as_mut() else {
+            return Some(bytes);
+        };
+        buf.extend_from_slice(&bytes);
+        if buf.len() < self.target {
+            return None;
+        }
+        self.buf.take().map(Bytes::from)
+    }
+
+    /// At the end of a track shorter than the target: what was buffered, rather than
+    /// waiting for the next track. A track that sent nothing leaves prebuffering on.
+    fn flush(&mut self) -> Option<Bytes> {
+        if self.buf.as_ref().is_some_and(|buf| !buf.is_empty()) {
+            return self.buf.take().map(Bytes::from);
+        }
+        None
+    }
+}
+
+/// Layer III bitrates (kbps) by header index, for MPEG-1 and for MPEG-2/2.5.
+const MP3_BITRATES: [[u32; 15]; 2] = [
+    [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+/// One frame of Layer III silence in the format `header` describes (version, bitrate,
+/// sample rate, channel mode): the header without CRC or padding, then all-zero side
+/// info and data. Used as the stream heartbeat, so it never changes the format mid-stream.
+/// It may land mid-frame of the real stream, which decoders shrug off by resyncing on
+/// the next header. `None` for anything but a fixed-bitrate Layer III header.
+fn silent_mp3_frame(header: [u8; 4]) -> Option<Bytes> {
+    let version = (header[1] >> 3) & 0x03;
+    let layer = (header[1] >> 1) & 0x03;
+    let bitrate_index = (header[2] >> 4) as usize;
+    let rate_index = ((header[2] >> 2) & 0x03) as usize;
+    if header[0] != 0xff || header[1] & 0xe0 != 0xe0 || layer != 0x01 || version == 0x01 || rate_index == 3 {
+        return None;
+    }
+    let mpeg1 = version == 0x03;
+    let bitrate = *MP3_BITRATES[usize::from(!mpeg1)].get(bitrate_index).filter(|&&b| b > 0)?;
+    let sample_rate = [44100, 48000, 32000][rate_index] >> (3 - version.max(1));
+    let factor = if mpeg1 { 144 } else { 72 };
+    let len = (factor * bitrate * 1000 / sample_rate) as usize;
+
+    let mut frame = vec![0u8; len];
+    frame[0] = 0xff;
+    // No CRC, no padding
+    frame[1] = header[1] | 0x01;
+    frame[2] = header[2] & !0x02;
+    frame[3] = header[3];
+    Some(Bytes::from(frame))
+}
+
+/// Returns the offset of the first plausible MP3 frame header (sync word `0xFFE`
+/// plus a valid version/layer/bitrate/sample rate), skipping a leading ID3v2 tag.
+fn find_mp3_frame_start(buf: &[u8]) -> Option<usize> {
+    let from = id3v2_tag_len(buf).unwrap_or(0);
+    (from..buf.len().saturating_sub(3)).find(|&i| {
+        let header = &buf[i..i + 4];
+        header[0] == 0xff
+            && header[1] & 0xe0 == 0xe0
+            && (header[1] >> 3) & 0x03 != 0x01 // version: reserved
+            && (header[1] >> 1) & 0x03 != 0x00 // layer: reserved
+            && header[2] >> 4 != 0x0f // bitrate: bad
+            && (header[2] >> 2) & 0x03 != 0x03 // sample rate: reserved
+    })
+}
+
+/// The full length of the ID3v2 tag at the start of `buf` (header, body and footer),
+/// once its 10-byte header is there. Tags with cover art often run to hundreds of KB.
+fn id3v2_tag_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 10 || &buf[..3] != b"ID3" {
+        return None;
+    }
+    // ID3v2 size is a 28-bit "syncsafe" integer (7 bits per byte)
+    let size = buf[6..10].iter().fold(0usize, |acc, b| (acc << 7) | (*b as usize & 0x7f));
+    // ID3v2.4 may end with a 10-byte footer, flagged in the header
+    let footer = if buf[5] & 0x10 != 0 { 10 } else { 0 };
+    Some(10 + size + footer)
+}
+
+/// Implement IntoResponse for our stream to set headers manually
+impl IntoResponse for PlexStreamResponse {
+    fn into_response(self) -> Response {
+        let body = Body::from_stream(self.stream);
+        
+        let mut builder = Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, self.content_type)
+            .header(header::CACHE_CONTROL, "no-cache")
+            .header(header::CONNECTION, "keep-alive");
+        // Shoutcast-style station name, shown by players like VLC and foobar2000
+        if let Some(name) = self.icy_name.and_then(|n| header::HeaderValue::from_str(&n).ok()) {
+            builder = builder.header("icy-name", name);
+        }
+        builder.body(body).unwrap()
+    }
+}
+
+/// Polls (and finally drops) a stream inside a tracing span. `Instrument` only covers
+/// futures, and entering a span across `.await`s inside the stream would leak it.
+struct SpannedStream<S> {
+    span: tracing::Span,
+    // Taken in `drop` so the stream's own teardown logs land inside the span
+    inner: Option<std::pin::Pin<Box<S>>>,
+}
+
+impl<S: Stream> Stream for SpannedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<S::Item>> {
+        let this = self.get_mut();
+        let _enter = this.span.enter();
+        match this.inner.as_mut() {
+            Some(inner) => inner.as_mut().poll_next(cx),
+            None => std::task::Poll::Ready(None),
+        }
+    }
+}
+
+impl<S> Drop for SpannedStream<S> {
+    fn drop(&mut self) {
+        let _enter = self.span.enter();
+        self.inner.take();
+    }
+}
+
+/// An audio body plus the headers describing it.
+struct PlexStreamResponse {
+    stream: std::pin::Pin<Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send>>,
+    /// MIME type of the audio (`audio/mpeg`, `audio/flac`, ...)
+    content_type: &'static str,
+    icy_name: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn track(key: &str) -> Track {
+        Track {
+            key: key.to_string(),
+            title: format!("Title {}", key),
+            artist: "Artist".to_string(),
+            duration: 200_000,
+            album_key: "album".to_string(),
+            album: None,
+            genres: Vec::new(),
+            year: None,
+            section_id: "1".to_string(),
+            container: None,
+            content_rating: None,
+            multi_part: false,
+        }
+    }
+
+    fn session(client_id: &str) -> SessionInfo {
+        SessionInfo {
+            track: track("1"),
+            client_id: client_id.to_string(),
+            started_at: SystemTime::now(),
+            bitrate: 320,
+            mode: StreamMode::Transcode,
+            selector: None,
+            queued: Vec::new(),
+        }
+    }
+
+    fn control(client_id: &str, stream_id: u64) -> SessionControl {
+        SessionControl {
+            paused: watch::channel(false).0,
+            stop: Arc::new(Notify::new()),
+            client_id: client_id.to_string(),
+            stream_id,
+        }
+    }
+
+    /// An `AppState` with default settings over the given tracks; it never reaches Plex.
+    fn app_state(tracks: Vec<Track>) -> AppState {
+        let output_format = OutputFormat { sample_rate: None, channels: None };
+        AppState {
+            client: Client::new(),
+            stream_client: Client::new(),
+            plex_url: "http://127.0.0.1:9".to_string(),
+            plex_token: PlexToken { value: "token".to_string(), in_query: false },
+            library: Arc::new(std::sync::RwLock::new(Arc::new(Library::new(tracks)))),
+            library_source: Arc::new(LibrarySource {
+                section_ids: vec!["1".to_string()],
+                track_type: "10".to_string(),
+                playlist_id: None,
+            }),
+            dedup_tracks: false,
+            refresh_lock: Arc::new(tokio::sync::Mutex::new(())),
+            admin_token: None,
+            sessions: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            session_controls: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            track_changes: broadcast::channel(64).0,
+            queues: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            history: Some(Arc::new(std::sync::Mutex::new(HashMap::new()))),
+            preferences: Some(Arc::new(std::sync::Mutex::new(HashMap::new()))),
+            seeded_selectors: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            feedback: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            blocked: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            track_details: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            playable_sections: None,
+            block_explicit: false,
+            bitrate: 320,
+            audio_boost: 100,
+            output_format,
+            audio_profiles: Arc::new(AudioProfiles { named: HashMap::new(), default: None }),
+            transcode_context: "static".to_string(),
+            media_version: MediaVersion::First,
+            passthrough: false,
+            stream_health: StreamHealth {
+                min_bytes: 1024,
+                min_duration: Duration::from_secs(2),
+                error_backoff: Duration::from_secs(5),
+                heartbeat: None,
+                first_byte_timeout: Some(Duration::from_secs(8)),
+                idle_timeout: None,
+            },
+            transcoder: Arc::new(TranscoderStatus::default()),
+            auto_passthrough: false,
+            prebuffer_bytes: 0,
+            disk_cache: None,
+            stream_permits: Arc::new(Semaphore::new(4)),
+            max_streams: 4,
+            #[cfg(feature = "hls")]
+            hls_permits: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            transcode_stop_tx: mpsc::unbounded_channel().0,
+            protocol: StreamProtocol::Http,
+            plex_client: Arc::new(PlexClientInfo {
+                identifier: "test-client".to_string(),
+                product: "Plex Radio".to_string(),
+                version: "1.0".to_string(),
+                device: "Plex Radio".to_string(),
+            }),
+            extra_headers: Arc::new(reqwest::header::HeaderMap::new()),
+            web: WebConfig { template_path: "templates/index.html".into(), hot_reload: false },
+            default_shuffle: true,
+            enable_visualizer: false,
+            dayparts: Arc::new(Dayparts { parts: Vec::new(), utc_offset_mins: 0 }),
+        }
+    }
+
+    /// A stand-in Plex server answering each path (query ignored) with its body, `404`
+    /// otherwise. Returns its URL and the head of every request it received.
+    async fn fake_plex(routes: Vec<(&'static str, &'static str)>) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let log = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let routes = routes.clone();
+                let log = log.clone();
+                tokio::spawn(async move {
+                    let mut buf = vec![0u8; 8192];
+                    let mut len = 0;
+                    while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+                        match socket.read(&mut buf[len..]).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => len += n,
+                        }
+                    }
+                    let head = String::from_utf8_lossy(&buf[..len]).into_owned();
+                    let path = head.split(' ').nth(1).unwrap_or("").split('?').next().unwrap_or("").to_string();
+                    log.lock().unwrap().push(head);
+                    let response = match routes.iter().find(|(route, _)| *route == path) {
+                        Some((_, body)) => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body),
+                        None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                    };
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn other_clients_cannot_read_a_session() {
+        let sessions = HashMap::from([("s1".to_string(), session("alice"))]);
+        assert!(owned_session(&sessions, "s1", "alice").is_some());
+        assert!(owned_session(&sessions, "s1", "bob").is_none());
+    }
+
+    #[test]
+    fn other_clients_cannot_control_a_session() {
+        let mut controls = HashMap::new();
+        assert_eq!(claim_session(&mut controls, "s1", control("alice", 1)), SessionClaim::New);
+        assert!(owned_control(&controls, "s1", "bob").is_none());
+        // Bob can't take the session id over either
+        assert_eq!(claim_session(&mut controls, "s1", control("bob", 2)), SessionClaim::Refused);
+        assert_eq!(owned_control(&controls, "s1", "alice").map(|c| c.stream_id), Some(1));
+    }
+
+    #[test]
+    fn reopening_a_session_replaces_the_clients_own_stream() {
+        let mut controls = HashMap::new();
+        claim_session(&mut controls, "s1", control("alice", 1));
+        let old_stop = controls["s1"].stop.clone();
+        assert_eq!(claim_session(&mut controls, "s1", control("alice", 2)), SessionClaim::Replaced);
+        assert_eq!(controls["s1"].stream_id, 2);
+        // The earlier stream was told to stop
+        assert!(old_stop.notified().now_or_never().is_some());
+    }
+
+    #[test]
+    fn prebuffer_holds_back_the_first_chunk_until_the_target() {
+        let mut prebuffer = Prebuffer::new(1000);
+        assert!(prebuffer.push(Bytes::from(vec![0; 400])).is_none());
+        assert_eq!(prebuffer.push(Bytes::from(vec![0; 700])).map(|b| b.len()), Some(1100));
+        // Afterwards chunks pass straight through
+        assert_eq!(prebuffer.push(Bytes::from(vec![0; 10])).map(|b| b.len()), Some(10));
+        assert!(prebuffer.flush().is_none());
+    }
+
+    #[test]
+    fn prebuffer_survives_a_track_that_sent_nothing() {
+        let mut prebuffer = Prebuffer::new(1000);
+        assert!(prebuffer.flush().is_none());
+        assert!(prebuffer.push(Bytes::from(vec![0; 500])).is_none());
+        // A short track is sent when it ends
+        assert_eq!(prebuffer.flush().map(|b| b.len()), Some(500));
+        assert_eq!(prebuffer.push(Bytes::from(vec![0; 10])).map(|b| b.len()), Some(10));
+    }
+
+    #[test]
+    fn prebuffer_off_passes_everything_through() {
+        let mut prebuffer = Prebuffer::new(0);
+        assert_eq!(prebuffer.push(Bytes::from(vec![0; 10])).map(|b| b.len()), Some(10));
+    }
+
+    #[test]
+    fn silent_frame_matches_the_stream_format() {
+        // MPEG-1, 128 kbps, 44.1 kHz, mono
+        let frame = silent_mp3_frame([0xff, 0xfb, 0x90, 0xc4]).unwrap();
+        assert_eq!(frame.len(), 417);
+        assert_eq!(&frame[..4], &[0xff, 0xfb, 0x90, 0xc4]);
+        assert!(frame[4..].iter().all(|&b| b == 0));
+        // MPEG-1, 320 kbps, 48 kHz, stereo with CRC and padding: both dropped
+        let frame = silent_mp3_frame([0xff, 0xfa, 0xe6, 0x00]).unwrap();
+        assert_eq!(frame.len(), 960);
+        assert_eq!(&frame[..4], &[0xff, 0xfb, 0xe4, 0x00]);
+        // MPEG-2, 64 kbps, 24 kHz, joint stereo
+        let frame = silent_mp3_frame([0xff, 0xf3, 0x84, 0x40]).unwrap();
+        assert_eq!(frame.len(), 192);
+        // MPEG-2.5, 32 kbps, 8 kHz
+        assert_eq!(silent_mp3_frame([0xff, 0xe3, 0x48, 0xc0]).unwrap().len(), 288);
+        assert_eq!(find_mp3_frame_start(&frame), Some(0));
+    }
+
+    #[test]
+    fn silent_frame_needs_a_layer_3_header() {
+        // Layer II, free bitrate and a reserved sample rate
+        assert!(silent_mp3_frame([0xff, 0xfd, 0x90, 0xc4]).is_none());
+        assert!(silent_mp3_frame([0xff, 0xfb, 0x00, 0xc4]).is_none());
+        assert!(silent_mp3_frame([0xff, 0xfb, 0x9c, 0xc4]).is_none());
+        assert!(FrameAligner::new(false).silent_frame().is_none());
+    }
+
+    #[test]
+    fn audio_boost_is_clamped_to_what_plex_accepts() {
+        assert_eq!(clamp_audio_boost(0, "PLEX_AUDIO_BOOST"), 100);
+        assert_eq!(clamp_audio_boost(99, "PLEX_AUDIO_BOOST"), 100);
+        assert_eq!(clamp_audio_boost(100, "PLEX_AUDIO_BOOST"), 100);
+        assert_eq!(clamp_audio_boost(180, "PLEX_AUDIO_BOOST"), 180);
+        assert_eq!(clamp_audio_boost(300, "PLEX_AUDIO_BOOST"), 300);
+        assert_eq!(clamp_audio_boost(301, "PLEX_AUDIO_BOOST"), 300);
+        assert_eq!(clamp_audio_boost(1000, "AUDIO_PROFILES home: boost"), 300);
+    }
+
+    #[test]
+    fn track_urls_encode_the_key() {
+        assert_eq!(track_url("12345", None), "/radio?track=12345");
+        assert_eq!(track_url("a&session=x", None), "/radio?track=a%26session%3Dx");
+        assert_eq!(track_url("what?now", Some(5000)), "/radio?track=what%3Fnow&offset=5000");
+        assert_eq!(track_url("two words", Some(0)), "/radio?track=two%20words&offset=0");
+    }
+
+    #[tokio::test]
+    async fn an_empty_library_answers_warming_up() {
+        let state = app_state(Vec::new());
+        assert!(!state.is_ready());
+        let params = HashMap::from([("q".to_string(), "title".to_string())]);
+        let resp = search_tracks(State(state.clone()), Query(params), HeaderMap::new())
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "5");
+        let err = resolve_station(&state, &HashMap::new(), &[]).err().unwrap();
+        assert_eq!(err.error, "warming_up");
+
+        *state.library.write().unwrap() = Arc::new(Library::new(vec![track("1")]));
+        assert!(state.is_ready());
+        assert!(resolve_station(&state, &HashMap::new(), &[]).is_ok());
+    }
+
+    #[test]
+    fn rapid_skips_never_repeat_the_skipped_track() {
+        let tracks: Vec<Track> = (0..4).map(|i| track(&i.to_string())).collect();
+        let state = app_state(tracks);
+        let library = state.library();
+        let candidates: Vec<usize> = (0..library.tracks.len()).collect();
+
+        let mut previous: Option<usize> = None;
+        for _ in 0..200 {
+            // Each skip reconnects, so every pick comes from a fresh selector
+            let mut selector = Selector::new(PlayOrder::Shuffle, StationFilter::default(), None);
+            let weights = state.shuffle_weights(&library, &candidates, "c", true);
+            let pos = selector.next_index_weighted(candidates.len(), weights.as_deref()).unwrap();
+            assert_ne!(Some(pos), previous);
+            state.record_history("c", &library.tracks[candidates[pos]]);
+            previous = Some(pos);
+        }
+        // Tracks that follow on within a stream shuffle without the exclusion
+        assert!(state.shuffle_weights(&library, &candidates, "c", false).is_none());
+    }
+
+    #[test]
+    fn only_replacing_or_marked_streams_count_as_skips() {
+        let params = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<HashMap<_, _>>();
+        // A first tune-in is not a skip
+        assert!(!is_skip(&params(&[]), &SessionClaim::New));
+        assert!(is_skip(&params(&[]), &SessionClaim::Replaced));
+        assert!(is_skip(&params(&[("skip", "true")]), &SessionClaim::New));
+        assert!(!is_skip(&params(&[("skip", "false")]), &SessionClaim::New));
+        // Jumping to a track isn't a skip, whatever it replaces
+        assert!(!is_skip(&params(&[("track", "1")]), &SessionClaim::Replaced));
+        assert!(!is_skip(&params(&[("track", "1"), ("skip", "true")]), &SessionClaim::New));
+    }
+
+    #[test]
+    fn token_goes_in_the_query_only_when_configured() {
+        let client = Client::new();
+        let header_only = PlexToken { value: "secret".to_string(), in_query: false };
+        let request = client.get("http://plex/identity").plex_token(&header_only).build().unwrap();
+        assert_eq!(request.headers()["X-Plex-Token"], "secret");
+        assert_eq!(request.url().query(), None);
+
+        let in_query = PlexToken { in_query: true, ..header_only };
+        let request = client.get("http://plex/identity").plex_token(&in_query).build().unwrap();
+        assert_eq!(request.headers()["X-Plex-Token"], "secret");
+        assert_eq!(request.url().query(), Some("X-Plex-Token=secret"));
+    }
+
+    #[test]
+    fn search_ranks_exact_then_prefix_then_substring() {
+        let named = |key: &str, title: &str, artist: &str| Track {
+            title: title.to_string(),
+            artist: artist.to_string(),
+            ..track(key)
+        };
+        let library = Library::new(vec![
+            named("substring", "Blue Moon Rising", "Someone"),
+            named("other", "Yellow", "Nobody"),
+            named("prefix", "Moonlight", "Someone"),
+            named("exact-artist", "Anything", "Moon"),
+            named("exact", "Moon", "Someone"),
+            named("prefix-artist", "Whatever", "Moonband"),
+        ]);
+        let keys: Vec<&str> = library.search("moon").into_iter()
+            .map(|idx| library.tracks[idx].key.as_str())
+            .collect();
+        assert_eq!(keys, ["exact-artist", "exact", "prefix", "prefix-artist", "substring"]);
+        // With a limit, the best matches are the ones kept
+        let top: Vec<&str> = library.search("moon").into_iter()
+            .take(2)
+            .map(|idx| library.tracks[idx].key.as_str())
+            .collect();
+        assert_eq!(top, ["exact-artist", "exact"]);
+    }
+
+    #[test]
+    fn selecting_from_an_empty_station_yields_nothing() {
+        for order in [PlayOrder::Shuffle, PlayOrder::Sequential, PlayOrder::Bag] {
+            let mut selector = Selector::new(order, StationFilter::default(), None);
+            assert_eq!(selector.next_index(0), None);
+            assert_eq!(selector.next_index_weighted(0, Some(&[])), None);
+            assert_eq!(selector.random_index(0), None);
+            assert!(selector.peek(0, 5).is_empty());
+            // Once it had tracks, a station emptied by a refresh still yields nothing
+            assert!(selector.next_index(3).is_some());
+            assert_eq!(selector.next_index(0), None);
+        }
+        let library = Library::new(Vec::new());
+        let candidates = app_state(Vec::new()).station_candidates(&library, &StationFilter::default());
+        let mut selector = Selector::new(PlayOrder::Shuffle, StationFilter::default(), Some(7));
+        assert_eq!(selector.next_index(candidates.len()), None);
+    }
+
+    #[test]
+    fn bag_shuffle_deals_every_track_once_per_cycle() {
+        let count = 7;
+        let mut selector = Selector::new(PlayOrder::Bag, StationFilter::default(), Some(42));
+        let mut last = None;
+        for _ in 0..20 {
+            let mut cycle: Vec<usize> = (0..count).map(|_| selector.next_index(count).unwrap()).collect();
+            // The last track of one cycle never opens the next
+            assert_ne!(Some(cycle[0]), last);
+            last = cycle.last().copied();
+            cycle.sort_unstable();
+            assert_eq!(cycle, (0..count).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn shuffle_follows_the_feedback_weights() {
+        let state = app_state(vec![track("liked"), track("plain"), track("disliked")]);
+        let library = state.library();
+        let candidates = [0, 1, 2];
+        assert!(state.shuffle_weights(&library, &candidates, "c", false).is_none());
+
+        state.feedback.lock().unwrap().extend([("liked".to_string(), 2), ("disliked".to_string(), -2)]);
+        let weights = state.shuffle_weights(&library, &candidates, "c", false).unwrap();
+        assert_eq!(weights, [2.25, 1.0, 1.0 / 2.25]);
+
+        let draws = 30_000;
+        let mut counts = [0usize; 3];
+        let mut selector = Selector::new(PlayOrder::Shuffle, StationFilter::default(), None);
+        for _ in 0..draws {
+            counts[selector.next_index_weighted(3, Some(&weights)).unwrap()] += 1;
+        }
+        let total: f64 = weights.iter().sum();
+        for (count, weight) in counts.iter().zip(&weights) {
+            let share = *count as f64 / draws as f64;
+            assert!((share - weight / total).abs() < 0.02, "drew {:?} for weights {:?}", counts, weights);
+        }
+    }
+
+    #[test]
+    fn strict_params_reject_what_lenient_parsing_falls_back_on() {
+        // (param, values STRICT_PARAMS accepts, a value it rejects)
+        let cases: &[(&str, &[&str], &str)] = &[
+            ("offset", &["0", "90000"], "abc"),
+            ("count", &["5"], "-1"),
+            ("limit", &["10"], "ten"),
+            ("bitrate", &["128", "320"], "999"),
+            ("shuffle", &["true", "false"], "no"),
+            ("shuffle_mode", &["bag"], "random"),
+            ("loop", &["true", "false"], "yes"),
+            ("continue_on_error", &["true", "false"], "1"),
+            ("clean", &["true", "false", "1", "0"], "yes"),
+            ("lenient", &["true", "false", "1", "0"], "yes"),
+            ("start_paused", &["true", "false", "1", "0"], "yes"),
+            ("skip", &["true", "false", "1", "0"], "yes"),
+            ("format", &["json", "csv", "text", "txt", "tsv"], "xml"),
+        ];
+        for (name, _) in STRICT_PARAM_RULES {
+            assert!(cases.iter().any(|(n, _, _)| n == name), "no case for `{}`", name);
+        }
+        for (name, good, bad) in cases {
+            for value in *good {
+                assert_eq!(check_strict_param(name, value), Ok(()), "{}={}", name, value);
+            }
+            let message = check_strict_param(name, bad).unwrap_err();
+            assert!(message.contains(&format!("`{}`", name)) && message.contains(bad), "{}", message);
+        }
+        assert_eq!(check_strict_param("genre", "anything"), Ok(()));
+
+        // Without STRICT_PARAMS the same values fall back to the defaults
+        let params = |name: &str, value: &str| HashMap::from([(name.to_string(), value.to_string())]);
+        assert_eq!(parse_offset_ms(Some(&"abc".to_string())), 0);
+        assert_eq!(PlayOrder::from_params(&params("shuffle", "no")), PlayOrder::Shuffle);
+        assert_eq!(PlayOrder::from_params(&params("shuffle_mode", "random")), PlayOrder::Shuffle);
+        let state = app_state(Vec::new());
+        assert_eq!(requested_profile(&state, &params("bitrate", "999")).unwrap().bitrate, state.bitrate);
+        assert!(!StationFilter::from_params(&params("clean", "yes")).unwrap().clean);
+        assert!(matches!(SearchFormat::negotiate(Some("xml"), &HeaderMap::new()), SearchFormat::Json));
+    }
+
+    #[tokio::test]
+    async fn head_probes_never_reach_plex() {
+        let plex = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut state = app_state(vec![track("1"), track("2")]);
+        state.plex_url = format!("http://{}", plex.local_addr().unwrap());
+
+        for query in [vec![], vec![("track", "1")], vec![("playlist", "5")], vec![("album", "9")]] {
+            let params: HashMap<String, String> = query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            let pairs: Vec<(String, String)> = params.clone().into_iter().collect();
+            let resp = stream_radio(Method::HEAD, State(state.clone()), Query(params), Query(pairs))
+                .await
+                .into_response();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "audio/mpeg");
+        }
+        assert!(state.sessions.lock().unwrap().is_empty());
+        assert_eq!(state.stream_permits.available_permits(), state.max_streams);
+        let connected = tokio::time::timeout(Duration::from_millis(200), plex.accept()).await;
+        assert!(connected.is_err(), "a HEAD request connected to Plex");
+    }
+
+    #[test]
+    fn missing_artists_fall_back_to_the_album_then_a_placeholder() {
+        let body = r#"{"MediaContainer": {"Metadata": [
+            {"ratingKey": "1", "title": "Song", "grandparentTitle": "Band", "parentTitle": "Record"},
+            {"ratingKey": "2", "title": "Single", "parentTitle": "The Single"},
+            {"ratingKey": "3", "title": "Episode", "grandparentTitle": " ", "parentTitle": ""},
+            {"ratingKey": "4", "title": "Bare"}
+        ]}}"#;
+        let container: PlexContainer = serde_json::from_str(body).unwrap();
+        let artists: Vec<String> = container.media_container.metadata.into_iter()
+            .map(|m| Track::from(m).artist)
+            .collect();
+        assert_eq!(artists, ["Band", "The Single", "Unknown Artist", "Unknown Artist"]);
+    }
+
+    #[test]
+    fn seek_offsets_stay_inside_the_track() {
+        assert_eq!(clamp_offset(0, 200_000), 0);
+        assert_eq!(clamp_offset(60_000, 200_000), 60_000);
+        assert_eq!(clamp_offset(200_000, 200_000), 200_000);
+        assert_eq!(clamp_offset(900_000, 200_000), 200_000);
+        // An unknown length can't be checked
+        assert_eq!(clamp_offset(900_000, 0), 900_000);
+        // Offsets are whole seconds, as fastSeek only honors those
+        assert_eq!(parse_offset_ms(Some(&"61999".to_string())), 61_000);
+
+        let library = Library::new(vec![track("1")]);
+        let seek = |offset: &str| {
+            let params = HashMap::from([("track".to_string(), "1".to_string()), ("offset".to_string(), offset.to_string())]);
+            validate_seek(&library, &params)
+        };
+        assert!(seek("200000").is_ok());
+        assert!(seek("200999").is_ok());
+        assert!(seek("201000").unwrap_err().contains("beyond the track length"));
+    }
+
+    #[test]
+    fn sequential_genre_station_walks_only_that_genre_in_order() {
+        let tracks: Vec<Track> = (0..8)
+            .map(|i| Track {
+                genres: vec![if i % 3 == 0 { "Rock" } else { "Jazz" }.to_string()],
+                ..track(&i.to_string())
+            })
+            .collect();
+        let state = app_state(tracks);
+        let library = state.library();
+        let params = HashMap::from([("genre".to_string(), "rock".to_string()), ("shuffle".to_string(), "false".to_string())]);
+        let filter = StationFilter::from_params(&params).unwrap();
+        let candidates = state.station_candidates(&library, &filter);
+        assert_eq!(candidates, [0, 3, 6]);
+
+        let mut selector = Selector::new(PlayOrder::from_params(&params), filter, None);
+        selector.jump_to(0);
+        let keys: Vec<&str> = (0..6)
+            .map(|_| library.tracks[candidates[selector.next_index(candidates.len()).unwrap()]].key.as_str())
+            .collect();
+        assert_eq!(keys, ["3", "6", "0", "3", "6", "0"]);
+    }
+
+    #[test]
+    fn zero_duration_tracks_are_never_judged_too_short() {
+        let state = app_state(Vec::new());
+        let health = state.stream_health;
+        let unknown = Track { duration: 0, ..track("1") };
+        let known = track("2");
+
+        // A quick end is still noted (so the transcode isn't cached) but causes no back-off
+        let reason = health.quick_end(&unknown, 50_000, Duration::from_millis(500), false);
+        assert!(reason.as_deref().is_some_and(|r| r.contains("MIN_STREAM_SECS")));
+        assert_eq!(health.backoff_after(&unknown, reason.as_deref()), None);
+        let reason = health.quick_end(&known, 50_000, Duration::from_millis(500), false);
+        assert_eq!(health.backoff_after(&known, reason.as_deref()), Some(health.error_backoff));
+        assert_eq!(health.quick_end(&unknown, 50_000, Duration::from_secs(60), false), None);
+
+        // Seeks and the now-playing line don't assume a length either
+        assert_eq!(clamp_offset(30_000, 0), 30_000);
+        let library = Library::new(vec![unknown.clone()]);
+        let params = HashMap::from([("track".to_string(), "1".to_string()), ("offset".to_string(), "999000".to_string())]);
+        assert!(validate_seek(&library, &params).is_ok());
+        assert_eq!(now_playing_line(&unknown, 65_000), "Artist - Title 1 (01:05)");
+        assert_eq!(now_playing_line(&known, 65_000), "Artist - Title 2 (01:05/03:20)");
+    }
+
+    #[tokio::test]
+    async fn stream_responses_carry_the_audio_and_icy_headers() {
+        let chunks = vec![Ok(Bytes::from_static(b"ab")), Ok(Bytes::from_static(b"cd"))];
+        let resp = PlexStreamResponse {
+            stream: Box::pin(futures::stream::iter(chunks)),
+            content_type: "audio/flac",
+            icy_name: Some("Plex Radio".to_string()),
+        }
+        .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "audio/flac");
+        assert_eq!(resp.headers()[header::CACHE_CONTROL], "no-cache");
+        assert_eq!(resp.headers()["icy-name"], "Plex Radio");
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"abcd");
+
+        // A name that isn't a valid header value is left out rather than failing the stream
+        for icy_name in [None, Some("Bad\nName".to_string())] {
+            let resp = PlexStreamResponse {
+                stream: Box::pin(futures::stream::empty()),
+                content_type: "audio/mpeg",
+                icy_name,
+            }
+            .into_response();
+            assert_eq!(resp.headers()[header::CONTENT_TYPE], "audio/mpeg");
+            assert!(!resp.headers().contains_key("icy-name"));
+        }
+    }
+
+    #[tokio::test]
+    async fn track_pages_are_capped_bounded_and_sorted() {
+        let tracks: Vec<Track> = (0..600)
+            .map(|i| Track {
+                title: format!("Title {:03}", 599 - i),
+                artist: format!("Artist {}", i % 3),
+                duration: 1000 + (i as u64 * 7919) % 600,
+                ..track(&i.to_string())
+            })
+            .collect();
+        let state = app_state(tracks);
+        let page = |query: &[(&str, &str)]| {
+            let params = query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            browse_tracks(State(state.clone()), Query(params)).now_or_never().unwrap()
+        };
+
+        let Json(body) = page(&[]).unwrap();
+        assert_eq!((body["total"].as_u64(), body["limit"].as_u64()), (Some(600), Some(TRACKS_PAGE_DEFAULT as u64)));
+        let Json(body) = page(&[("limit", "100000")]).unwrap();
+        assert_eq!(body["limit"].as_u64(), Some(TRACKS_PAGE_MAX as u64));
+        assert_eq!(body["tracks"].as_array().unwrap().len(), TRACKS_PAGE_MAX);
+        let Json(body) = page(&[("offset", "590"), ("limit", "50")]).unwrap();
+        assert_eq!(body["tracks"].as_array().unwrap().len(), 10);
+        let Json(body) = page(&[("offset", "5000")]).unwrap();
+        assert!(body["tracks"].as_array().unwrap().is_empty());
+        assert_eq!(body["total"].as_u64(), Some(600));
+        for bad in [[("offset", "-1")], [("limit", "0")], [("sort", "year")]] {
+            assert_eq!(page(&bad).unwrap_err().status, StatusCode::BAD_REQUEST);
+        }
+
+        let field = |body: &serde_json::Value, name: &str| -> Vec<serde_json::Value> {
+            body["tracks"].as_array().unwrap().iter().map(|t| t[name].clone()).collect()
+        };
+        let Json(body) = page(&[("sort", "title"), ("limit", "500")]).unwrap();
+        let titles = field(&body, "title");
+        assert!(titles.windows(2).all(|w| w[0].as_str() <= w[1].as_str()));
+        let Json(body) = page(&[("sort", "duration"), ("limit", "500")]).unwrap();
+        let durations = field(&body, "duration");
+        assert!(durations.windows(2).all(|w| w[0].as_u64() <= w[1].as_u64()));
+        let Json(body) = page(&[("sort", "artist"), ("limit", "500")]).unwrap();
+        let artists = field(&body, "artist");
+        assert!(artists.windows(2).all(|w| w[0].as_str() <= w[1].as_str()));
+        // Within an artist, titles are in order
+        assert_eq!(field(&body, "title")[..2], ["Title 002", "Title 005"]);
+    }
+
+    #[test]
+    fn output_format_limits_only_what_is_configured() {
+        let extra = |sample_rate, channels| OutputFormat { sample_rate, channels }.profile_extra();
+        assert_eq!(extra(None, None), None);
+        let rate = "add-limitation(scope=musicCodec&scopeName=mp3&type=upperBound&name=audio.samplingRate&value=44100)";
+        let mono = "add-limitation(scope=musicCodec&scopeName=mp3&type=upperBound&name=audio.channels&value=1)";
+        assert_eq!(extra(Some(44100), None).as_deref(), Some(rate));
+        assert_eq!(extra(None, Some(1)).as_deref(), Some(mono));
+        assert_eq!(extra(Some(44100), Some(1)), Some(format!("{}+{}", rate, mono)));
+    }
+
+    /// Serves one response whose body trickles out a chunk every `gap`, `chunks` times.
+    async fn trickling_server(chunks: usize, gap: Duration) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await;
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Type: audio/mpeg\r\nContent-Length: {}\r\n\r\n", chunks * 4);
+            socket.write_all(head.as_bytes()).await.unwrap();
+            for _ in 0..chunks {
+                tokio::time::sleep(gap).await;
+                if socket.write_all(b"mp3!").await.is_err() {
+                    return;
+                }
+            }
+        });
+        format!("http://{}/stream", addr)
+    }
+
+    #[tokio::test]
+    async fn streams_outlive_the_metadata_timeout() {
+        let limit = Duration::from_millis(300);
+        // Six chunks 100ms apart: longer than the limit in total, never silent for that long
+        let url = trickling_server(6, Duration::from_millis(100)).await;
+        let stream_client = stream_client_builder(limit, limit).build().unwrap();
+        let body = stream_client.get(&url).send().await.unwrap().bytes().await.unwrap();
+        assert_eq!(body.len(), 24);
+
+        // A total timeout, as the metadata client has, would have cut the same body off
+        let url = trickling_server(6, Duration::from_millis(100)).await;
+        let client = Client::builder().timeout(limit).build().unwrap();
+        let cut_off = match client.get(&url).send().await {
+            Ok(resp) => resp.bytes().await.is_err(),
+            Err(_) => true,
+        };
+        assert!(cut_off);
+
+        // A Plex that goes quiet for longer than the read timeout is still given up on
+        let url = trickling_server(2, Duration::from_millis(600)).await;
+        let stalled = stream_client.get(&url).send().await.unwrap().bytes().await;
+        assert!(stalled.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_listener_leaving_closes_the_plex_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let plex = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut state = app_state(vec![track("1")]);
+        state.plex_url = format!("http://{}", plex.local_addr().unwrap());
+        let (stop_tx, mut stop_rx) = mpsc::unbounded_channel();
+        state.transcode_stop_tx = stop_tx;
+
+        // A transcoder that sends MP3 frames until the connection is closed on it
+        let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = plex.accept().await.unwrap();
+            let mut request = [0u8; 4096];
+            let n = socket.read(&mut request).await.unwrap();
+            assert!(String::from_utf8_lossy(&request[..n]).contains("/transcode/universal/start.mp3"));
+            socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: audio/mpeg\r\n\r\n").await.unwrap();
+            let frame = silent_mp3_frame([0xff, 0xfb, 0x90, 0xc4]).unwrap();
+            while socket.write_all(&frame).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            let _ = closed_tx.send(());
+        });
+
+        let params = HashMap::from([("track".to_string(), "1".to_string()), ("session".to_string(), "s1".to_string())]);
+        let pairs: Vec<(String, String)> = params.clone().into_iter().collect();
+        let resp = stream_radio(Method::GET, State(state.clone()), Query(params), Query(pairs))
+            .await
+            .into_response();
+        let mut body = resp.into_body().into_data_stream();
+        let mut received = 0;
+        while received < 8192 {
+            received += futures::StreamExt::next(&mut body).await.unwrap().unwrap().len();
+        }
+        assert!(state.sessions.lock().unwrap().contains_key("s1"));
+
+        drop(body);
+        tokio::time::timeout(Duration::from_secs(2), closed_rx).await
+            .expect("the Plex connection was still open after the listener left")
+            .unwrap();
+        assert!(state.sessions.lock().unwrap().is_empty());
+        assert!(state.session_controls.lock().unwrap().is_empty());
+        assert_eq!(stop_rx.recv().await.as_deref(), Some("s1"));
+    }
+
+    #[test]
+    fn disabled_history_records_nothing() {
+        let mut state = app_state(vec![track("1"), track("2")]);
+        state.history = None;
+        state.preferences = None;
+        state.sessions.lock().unwrap().insert("s1".to_string(), session("alice"));
+
+        let params = HashMap::from([("client_id".to_string(), "alice".to_string()), ("genre".to_string(), "rock".to_string())]);
+        for key in ["1", "2", "1"] {
+            state.record_history("alice", &track(key));
+        }
+        state.record_preferences(&params, &[]);
+
+        let body = now_playing_body(&state, "s1", "alice").unwrap();
+        assert_eq!(body["history"], serde_json::json!([]));
+        let library = state.library();
+        assert!(state.shuffle_weights(&library, &[0, 1], "alice", true).is_none());
+        let saved = client_preferences(State(state.clone()), Query(params.clone())).now_or_never().unwrap();
+        assert_eq!(saved.unwrap_err().status, StatusCode::NOT_FOUND);
+
+        // With history on, the same calls are kept
+        let state = app_state(vec![track("1"), track("2")]);
+        state.sessions.lock().unwrap().insert("s1".to_string(), session("alice"));
+        state.record_history("alice", &track("2"));
+        state.record_history("alice", &track("1"));
+        state.record_preferences(&params, &[]);
+        let body = now_playing_body(&state, "s1", "alice").unwrap();
+        assert_eq!(body["history"].as_array().unwrap().len(), 1);
+        let saved = client_preferences(State(state.clone()), Query(params)).now_or_never().unwrap();
+        assert_eq!(saved.unwrap().url, "/radio?genre=rock");
+    }
+
+    #[tokio::test]
+    async fn transcode_context_is_sent_to_plex() {
+        let mut state = app_state(vec![track("1")]);
+        let profile = requested_profile(&state, &HashMap::new()).unwrap();
+        let media = MediaIndexes { media_index: None, part_index: None };
+        for context in ["static", "streaming"] {
+            state.transcode_context = context.to_string();
+            let request = prepare_track_request(&state, "1", "s1", 0, &profile, media).await.unwrap();
+            let request = request.first.build().unwrap();
+            let sent: Vec<String> = request.url().query_pairs()
+                .filter(|(name, _)| name == "context")
+                .map(|(_, value)| value.into_owned())
+                .collect();
+            assert_eq!(sent, [context]);
+        }
+    }
+
+    #[test]
+    fn library_responses_read_the_same_as_xml_or_json() {
+        let json = r#"{"MediaContainer": {"size": 2, "Metadata": [
+            {"ratingKey": "101", "title": "First", "grandparentTitle": "Band", "parentTitle": "Record",
+             "parentRatingKey": "100", "duration": 215000, "index": 1, "parentIndex": 1,
+             "parentYear": 1999, "librarySectionID": 3, "contentRating": "Explicit",
+             "Genre": [{"tag": "Rock"}, {"tag": "Indie"}],
+             "Media": [{"duration": 215000, "bitrate": 320, "container": "mp3",
+                        "Part": [{"key": "/library/parts/1/file.mp3", "duration": 215000}]}]},
+            {"ratingKey": "102", "title": "Second & Last", "parentTitle": "Record", "parentRatingKey": "100",
+             "duration": 0, "originallyAvailableAt": "2001-05-04",
+             "Media": [{"container": "flac", "Part": [{"key": "/library/parts/2/a.flac"}, {"key": "/library/parts/2/b.flac"}]}]}
+        ]}}"#;
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <MediaContainer size="2">
+              <Track ratingKey="101" title="First" grandparentTitle="Band" parentTitle="Record"
+                     parentRatingKey="100" duration="215000" index="1" parentIndex="1"
+                     parentYear="1999" librarySectionID="3" contentRating="Explicit">
+                <Media duration="215000" bitrate="320" container="mp3">
+                  <Part key="/library/parts/1/file.mp3" duration="215000" />
+                </Media>
+                <Genre tag="Rock" />
+                <Genre tag="Indie" />
+              </Track>
+              <Track ratingKey="102" title="Second &amp; Last" parentTitle="Record" parentRatingKey="100"
+                     duration="0" originallyAvailableAt="2001-05-04">
+                <Media container="flac">
+                  <Part key="/library/parts/2/a.flac" />
+                  <Part key="/library/parts/2/b.flac" />
+                </Media>
+              </Track>
+            </MediaContainer>"#;
+        let tracks = |container: PlexContainer| -> Vec<Track> {
+            container.media_container.metadata.into_iter().map(Track::from).collect()
+        };
+        let from_json = tracks(serde_json::from_str(json).unwrap());
+        let from_xml = tracks(serde_json::from_value(plex_xml_to_json(xml.as_bytes()).unwrap()).unwrap());
+        assert_eq!(format!("{:?}", from_xml), format!("{:?}", from_json));
+        assert_eq!(from_xml[0].genres, ["Rock", "Indie"]);
+        assert_eq!((from_xml[0].year, from_xml[1].year), (Some(1999), Some(2001)));
+        assert_eq!(from_xml[1].title, "Second & Last");
+        assert!(from_xml[1].multi_part);
+
+        // Library sections stay `Directory`, as in JSON
+        let sections = r#"<MediaContainer size="1"><Directory key="3" type="artist" title="Music" /></MediaContainer>"#;
+        let sections: PlexContainer = serde_json::from_value(plex_xml_to_json(sections.as_bytes()).unwrap()).unwrap();
+        let music = &sections.media_container.directories[0];
+        assert_eq!((music.key.as_str(), music.section_type.as_str(), music.title.as_str()), ("3", "artist", "Music"));
+    }
+
+    #[test]
+    fn now_playing_answers_stopped_only_when_lenient() {
+        let state = app_state(vec![track("1")]);
+        state.sessions.lock().unwrap().insert("s1".to_string(), session("alice"));
+        let ask = |query: &[(&str, &str)]| {
+            let params = query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            now_playing(State(state.clone()), Query(params)).now_or_never().unwrap()
+        };
+
+        for lenient in [None, Some("1"), Some("true")] {
+            let mut query = vec![("session", "s1"), ("client_id", "alice")];
+            query.extend(lenient.map(|v| ("lenient", v)));
+            let Json(body) = ask(&query).unwrap();
+            assert_eq!((body["playing"].as_bool(), body["key"].as_str()), (Some(true), Some("1")));
+        }
+
+        assert_eq!(ask(&[("session", "gone")]).unwrap_err().status, StatusCode::NOT_FOUND);
+        assert_eq!(ask(&[("session", "gone"), ("lenient", "false")]).unwrap_err().status, StatusCode::NOT_FOUND);
+        for lenient in ["1", "true"] {
+            let Json(body) = ask(&[("session", "gone"), ("lenient", lenient)]).unwrap();
+            assert_eq!(body, serde_json::json!({ "playing": false }));
+        }
+    }
+
+    #[test]
+    fn duration_filters_bound_the_station() {
+        let lengths = [("skit", 8), ("song", 180), ("long", 300), ("jam", 1200)];
+        let state = app_state(lengths.iter().map(|&(key, secs)| Track { duration: secs * 1000, ..track(key) }).collect());
+        let station = |query: &[(&str, &str)]| {
+            let params: HashMap<String, String> = query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            resolve_station(&state, &params, &[]).map(|station| {
+                station.candidates.iter().map(|&i| station.library.tracks[i].key.clone()).collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(station(&[("dur_min", "10")]).unwrap(), ["song", "long", "jam"]);
+        assert_eq!(station(&[("dur_max", "600")]).unwrap(), ["skit", "song", "long"]);
+        assert_eq!(station(&[("dur_min", "10"), ("dur_max", "600")]).unwrap(), ["song", "long"]);
+        // Bounds are inclusive
+        assert_eq!(station(&[("dur_min", "180"), ("dur_max", "180")]).unwrap(), ["song"]);
+
+        let rejected = |query: &[(&str, &str)]| station(query).unwrap_err().status;
+        assert_eq!(rejected(&[("dur_min", "600"), ("dur_max", "10")]), StatusCode::BAD_REQUEST);
+        assert_eq!(rejected(&[("dur_min", "ten")]), StatusCode::BAD_REQUEST);
+        assert_eq!(rejected(&[("dur_min", "-5")]), StatusCode::BAD_REQUEST);
+        // A range no track falls in leaves nothing to play
+        assert_eq!(rejected(&[("dur_min", "2000")]), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn now_playing_shows_next_only_when_the_order_is_known() {
+        let state = app_state((0..5).map(|i| track(&i.to_string())).collect());
+        let next_of = |info: SessionInfo| {
+            state.sessions.lock().unwrap().insert("s1".to_string(), info);
+            now_playing_body(&state, "s1", "alice").unwrap().get("next").map(|next| next["key"].as_str().unwrap().to_string())
+        };
+        let with_selector = |selector: Selector| SessionInfo { selector: Some(selector), ..session("alice") };
+
+        // Random: unknown
+        let mut random = Selector::new(PlayOrder::Shuffle, StationFilter::default(), None);
+        random.jump_to(1);
+        assert_eq!(next_of(with_selector(random)), None);
+        assert_eq!(next_of(session("alice")), None);
+
+        // Sequential: the following track of the station
+        let mut sequential = Selector::new(PlayOrder::Sequential, StationFilter::default(), None);
+        sequential.jump_to(3);
+        assert_eq!(next_of(with_selector(sequential.clone())).as_deref(), Some("4"));
+        sequential.jump_to(4);
+        assert_eq!(next_of(with_selector(sequential)).as_deref(), Some("0"));
+
+        // Seeded: whatever the station's selector will actually pick next
+        let mut seeded = Selector::new(PlayOrder::Shuffle, StationFilter::default(), Some(99));
+        seeded.jump_to(2);
+        let shown = next_of(with_selector(seeded.clone()));
+        let played = seeded.next_index(5).unwrap();
+        assert_eq!(shown, Some(played.to_string()));
+
+        // Album and playlist: the next queued track of the album
+        let album = SessionInfo { queued: vec![track("7"), track("8")], ..session("alice") };
+        assert_eq!(next_of(album).as_deref(), Some("7"));
+
+        // A request from POST /queue plays before any of these
+        state.queues.lock().unwrap().insert("s1".to_string(), VecDeque::from([track("9")]));
+        let mut sequential = Selector::new(PlayOrder::Sequential, StationFilter::default(), None);
+        sequential.jump_to(0);
+        assert_eq!(next_of(with_selector(sequential)).as_deref(), Some("9"));
+    }
+
+    #[test]
+    fn short_tracks_that_were_meant_to_be_short_are_not_failures() {
+        let health = app_state(Vec::new()).stream_health;
+        let quick = Duration::from_millis(800);
+        let jingle = Track { duration: 1500, ..track("jingle") };
+        let song = track("song");
+
+        // A jingle that ends at its own length, requested or not
+        assert_eq!(health.quick_end(&jingle, 20_000, quick, false), None);
+        // A normal track that was asked for and sent real audio
+        assert_eq!(health.quick_end(&song, 20_000, quick, true), None);
+        // The same track picked by the station: that's a broken transcode
+        let reason = health.quick_end(&song, 20_000, quick, false);
+        assert!(health.backoff_after(&song, reason.as_deref()).is_some());
+        // Nothing at all is a failure even for a request or a jingle
+        for (track, requested) in [(&song, true), (&jingle, false)] {
+            let reason = health.quick_end(track, 100, quick, requested);
+            assert!(reason.as_deref().is_some_and(|r| r.contains("MIN_STREAM_BYTES")));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_track_change_produces_an_event() {
+        let state = app_state(vec![track("1")]);
+        let params = HashMap::from([("session".to_string(), "s1".to_string()), ("client_id".to_string(), "alice".to_string())]);
+        let resp = now_playing_events(State(state.clone()), Query(params)).await.unwrap().into_response();
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/event-stream");
+        let mut events = resp.into_body().into_data_stream();
+        async fn next_event(events: &mut axum::body::BodyDataStream) -> String {
+            let chunk = tokio::time::timeout(Duration::from_secs(1), futures::StreamExt::next(events)).await;
+            String::from_utf8(chunk.expect("no event was sent").unwrap().unwrap().to_vec()).unwrap()
+        }
+
+        // The current state on connect
+        let first = next_event(&mut events).await;
+        assert!(first.starts_with("event: now-playing\n") && first.contains(r#"{"playing":false}"#), "{}", first);
+
+        // Changes to other sessions are not sent; this session's next track is
+        state.sessions.lock().unwrap().insert("s1".to_string(), session("alice"));
+        state.track_changes.send("s2".to_string()).unwrap();
+        state.track_changes.send("s1".to_string()).unwrap();
+        let changed = next_event(&mut events).await;
+        assert!(changed.contains(r#""playing":true"#) && changed.contains(r#""key":"1""#), "{}", changed);
+    }
+
+    #[test]
+    fn explicit_tracks_are_left_out_of_clean_stations() {
+        let body = r#"{"MediaContainer": {"Metadata": [
+            {"ratingKey": "1", "title": "Radio Edit", "contentRating": "Clean"},
+            {"ratingKey": "2", "title": "Album Version", "contentRating": "Explicit"},
+            {"ratingKey": "3", "title": "Unrated"},
+            {"ratingKey": "4", "title": "Shouty", "contentRating": " explicit "},
+            {"ratingKey": "5", "title": "Blank", "contentRating": ""}
+        ]}}"#;
+        let container: PlexContainer = serde_json::from_str(body).unwrap();
+        let tracks: Vec<Track> = container.media_container.metadata.into_iter().map(Track::from).collect();
+        let explicit: Vec<bool> = tracks.iter().map(Track::is_explicit).collect();
+        assert_eq!(explicit, [false, true, false, true, false]);
+        assert_eq!(tracks[4].content_rating, None);
+
+        // `?clean=true` on one station
+        let mut state = app_state(tracks);
+        let keys = |state: &AppState, query: &[(&str, &str)]| {
+            let params: HashMap<String, String> = query.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            resolve_station(state, &params, &[]).map(|station| {
+                station.candidates.iter().map(|&i| station.library.tracks[i].key.clone()).collect::<Vec<_>>()
+            })
+        };
+        assert_eq!(keys(&state, &[]).unwrap(), ["1", "2", "3", "4", "5"]);
+        assert_eq!(keys(&state, &[("clean", "true")]).unwrap(), ["1", "3", "5"]);
+
+        // BLOCK_EXPLICIT for every station, search and request
+        state.block_explicit = true;
+        assert_eq!(keys(&state, &[]).unwrap(), ["1", "3", "5"]);
+        assert!(!state.is_playable(&state.library().tracks[1]));
+
+        // A rotation emptied by the filter is refused rather than played from nothing
+        let state = app_state(vec![Track { content_rating: Some("Explicit".to_string()), ..track("1") }]);
+        assert_eq!(keys(&state, &[("clean", "1")]).unwrap_err().status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn every_part_of_a_split_file_is_streamed() {
+        let metadata = r#"{"MediaContainer": {"Metadata": [{"duration": 7200000, "Media": [{"Part": [
+            {"key": "/library/parts/1/side-a.mp3", "duration": 3600000},
+            {"key": "/library/parts/2/side-b.mp3", "duration": 3600000}
+        ]}]}]}}"#;
+        let (url, requests) = fake_plex(vec![
+            ("/library/metadata/1", metadata),
+            ("/library/parts/1/side-a.mp3", "side A;"),
+            ("/library/parts/2/side-b.mp3", "side B;"),
+        ]).await;
+        let mut state = app_state(vec![Track { multi_part: true, ..track("1") }]);
+        state.plex_url = url;
+        let profile = requested_profile(&state, &HashMap::new()).unwrap();
+        let media = MediaIndexes { media_index: None, part_index: None };
+
+        // Passthrough reads the files one after the other
+        state.passthrough = true;
+        let request = prepare_track_request(&state, "1", "s1", 0, &profile, media).await.unwrap();
+        assert_eq!(request.rest.len(), 1);
+        let first = request.first.send().await.unwrap();
+        let first: ByteStream = Box::pin(futures::TryStreamExt::map_err(first.bytes_stream(), std::io::Error::other));
+        let chunks: Vec<Bytes> = futures::TryStreamExt::try_collect(chain_parts(first, request.rest)).await.unwrap();
+        assert_eq!(chunks.concat(), b"side A;side B;");
+        assert!(requests.lock().unwrap().iter().any(|head| head.starts_with("GET /library/parts/2/side-b.mp3")));
+
+        // The transcoder is asked for each part in turn, starting from the one the offset is in
+        state.passthrough = false;
+        let part_indexes = |request: TrackRequest| -> Vec<String> {
+            std::iter::once(request.first).chain(request.rest)
+                .map(|r| r.build().unwrap().url().query_pairs().find(|(k, _)| k == "partIndex").unwrap().1.into_owned())
+                .collect()
+        };
+        let request = prepare_track_request(&state, "1", "s1", 0, &profile, media).await.unwrap();
+        assert_eq!(part_indexes(request), ["0", "1"]);
+        let request = prepare_track_request(&state, "1", "s1", 4_000_000, &profile, media).await.unwrap();
+        assert_eq!(part_indexes(request), ["1"]);
+        assert_eq!(locate_part(&[3_600_000, 3_600_000], 4_000_000), (1, 400_000));
+        assert_eq!(locate_part(&[3_600_000, 0], 9_000_000), (1, 5_400_000));
+    }
+
+    #[test]
+    fn now_playing_text_formats_elapsed_and_length() {
+        assert_eq!(format_clock(0), "00:00");
+        assert_eq!(format_clock(59_999), "00:59");
+        assert_eq!(format_clock(61_000), "01:01");
+        assert_eq!(format_clock(3_599_000), "59:59");
+        assert_eq!(format_clock(3_600_000), "1:00:00");
+        assert_eq!(format_clock(37_230_000), "10:20:30");
+
+        let song = Track { artist: "Band".to_string(), title: "Song".to_string(), duration: 245_000, ..track("1") };
+        assert_eq!(now_playing_line(&song, 83_500), "Band - Song (01:23/04:05)");
+        // Elapsed time never runs past the end, e.g. while the next track is being fetched
+        assert_eq!(now_playing_line(&song, 300_000), "Band - Song (04:05/04:05)");
+        let set = Track { duration: 5_400_000, ..song.clone() };
+        assert_eq!(now_playing_line(&set, 3_725_000), "Band - Song (1:02:05/1:30:00)");
+
+        let state = app_state(Vec::new());
+        let params = HashMap::from([("session".to_string(), "gone".to_string())]);
+        let resp = now_playing_text(State(state), Query(params)).now_or_never().unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn a_clients_last_station_is_saved_and_restored() {
+        let mut state = app_state(vec![Track { genres: vec!["Jazz & Blues".to_string()], ..track("1") }]);
+        state.audio_profiles = Arc::new(AudioProfiles {
+            named: HashMap::from([("mobile".to_string(), requested_profile(&state, &HashMap::new()).unwrap())]),
+            default: None,
+        });
+        let restore = |client_id: &str| {
+            let params = HashMap::from([("client_id".to_string(), client_id.to_string())]);
+            client_preferences(State(state.clone()), Query(params)).now_or_never().unwrap()
+        };
+        let query = |pairs: &[(&str, &str)]| -> (HashMap<String, String>, Vec<(String, String)>) {
+            let pairs: Vec<(String, String)> = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            (pairs.iter().cloned().collect(), pairs)
+        };
+
+        let (params, pairs) = query(&[
+            ("client_id", "alice"), ("session", "s1"), ("genre", "Jazz & Blues"), ("profile", "mobile"),
+            ("exclude_artist", "A"), ("exclude_artist", "B"),
+        ]);
+        state.record_preferences(&params, &pairs);
+        let Json(saved) = restore("alice").unwrap();
+        assert_eq!(saved.station, "radio");
+        assert_eq!((saved.genre.as_deref(), saved.profile.as_deref()), (Some("Jazz & Blues"), Some("mobile")));
+        // Session ids and client ids are the listener's, not the station's
+        assert_eq!(saved.url, "/radio?genre=Jazz%20%26%20Blues&exclude_artist=A&exclude_artist=B&profile=mobile");
+        // The saved URL opens the same station again
+        let (params, pairs) = query(&[("genre", "Jazz & Blues"), ("profile", "mobile")]);
+        assert!(resolve_station(&state, &params, &pairs).is_ok());
+        assert_eq!(restore("bob").unwrap_err().status, StatusCode::NOT_FOUND);
+        assert_eq!(restore("").unwrap_err().status, StatusCode::BAD_REQUEST);
+
+        // The latest station wins
+        let (params, pairs) = query(&[("client_id", "alice"), ("album", "42")]);
+        state.record_preferences(&params, &pairs);
+        let Json(saved) = restore("alice").unwrap();
+        assert_eq!((saved.station.as_str(), saved.url.as_str(), saved.genre), ("album", "/radio?album=42", None));
+
+        // Stations older than PREFERENCES_TTL are forgotten
+        let expired = Instant::now().checked_sub(PREFERENCES_TTL + Duration::from_secs(1)).unwrap();
+        state.preferences.as_ref().unwrap().lock().unwrap().get_mut("alice").unwrap().0 = expired;
+        assert_eq!(restore("alice").unwrap_err().status, StatusCode::NOT_FOUND);
+        let (params, pairs) = query(&[("client_id", "bob")]);
+        state.record_preferences(&params, &pairs);
+        assert!(!state.preferences.as_ref().unwrap().lock().unwrap().contains_key("alice"));
+    }
+
+    #[tokio::test]
+    async fn debug_curl_carries_the_clients_default_headers() {
+        let mut state = app_state(vec![track("1")]);
+        state.admin_token = Some("admin".to_string());
+        state.extra_headers = Arc::new(parse_extra_headers("CF-Access-Client-Secret:xyz").unwrap());
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer admin".parse().unwrap());
+
+        for (reveal, secret, token) in [("0", "REDACTED", "REDACTED"), ("1", "xyz", "token")] {
+            let params = HashMap::from([("key".to_string(), "1".to_string()), ("reveal_token".to_string(), reveal.to_string())]);
+            let Json(debug) = debug_transcode(State(state.clone()), headers.clone(), Query(params)).await.unwrap();
+            assert_eq!(debug["headers"]["user-agent"], "Plex-Radio/1.0");
+            assert_eq!(debug["headers"]["cf-access-client-secret"], secret);
+            assert_eq!(debug["headers"]["x-plex-token"], token);
+            assert_eq!(debug["headers"]["x-plex-client-identifier"], "test-client");
+            let curl = debug["curl"].as_str().unwrap();
+            assert!(curl.contains(&format!("-H 'cf-access-client-secret: {}'", secret)), "{}", curl);
+            assert!(curl.contains("-H 'user-agent: Plex-Radio/1.0'"), "{}", curl);
+        }
+    }
+
+    #[tokio::test]
+    async fn server_probes_carry_the_extra_headers() {
+        let (server_url, server_requests) = fake_plex(vec![("/identity", "<MediaContainer/>")]).await;
+        let resources = format!(r#"[{{"name": "Home", "provides": "server", "connections": [{{"uri": "{}", "local": true}}]}}]"#, server_url);
+        let (plex_tv, plex_tv_requests) = fake_plex(vec![("/api/v2/resources", Box::leak(resources.into_boxed_str()))]).await;
+        let plex_client = app_state(Vec::new()).plex_client;
+        let server_client = Client::builder()
+            .default_headers(parse_extra_headers("CF-Access-Client-Id:abc").unwrap())
+            .build()
+            .unwrap();
+        let token = PlexToken { value: "token".to_string(), in_query: false };
+        let url = discover_server_url(&Client::new(), &server_client, &plex_client, &plex_tv, &token, "home").await.unwrap();
+        assert_eq!(url, server_url);
+
+        let probe = server_requests.lock().unwrap()[0].to_lowercase();
+        assert!(probe.contains("cf-access-client-id: abc"), "{}", probe);
+        assert!(probe.contains("x-plex-client-identifier: test-client"), "{}", probe);
+        assert!(probe.contains("x-plex-token: token"), "{}", probe);
+        assert!(!plex_tv_requests.lock().unwrap()[0].to_lowercase().contains("cf-access-client-id"));
+    }
+
+    #[test]
+    fn large_id3_tags_are_skipped_by_their_size() {
+        let frame = silent_mp3_frame([0xff, 0xf3, 0x84, 0x40]).unwrap();
+        // A 300 KB tag (cover art) with a footer, full of bytes that look like frame headers
+        let body_len: usize = 300 * 1024;
+        let mut track = b"ID3\x04\x00\x10".to_vec();
+        track.extend((0..4).rev().map(|i| ((body_len >> (7 * i)) & 0x7f) as u8));
+        track.extend([0xff, 0xfb, 0x90, 0x44].repeat(body_len / 4));
+        track.extend(b"3DI\x04\x00\x10\x00\x00\x00\x00");
+        track.extend_from_slice(&frame);
+        assert_eq!(id3v2_tag_len(&track), Some(10 + body_len + 10));
+
+        let mut aligner = FrameAligner::new(true);
+        let mut out = Vec::new();
+        for chunk in track.chunks(4096) {
+            if let Some(bytes) = aligner.push(Bytes::copy_from_slice(chunk)) {
+                out.extend_from_slice(&bytes);
+            }
+            assert!(aligner.buf.len() <= 4096);
+        }
+        assert_eq!(out, frame);
+        assert_eq!(aligner.header, Some([0xff, 0xf3, 0x84, 0x40]));
+    }
+
+    #[tokio::test]
+    async fn a_stream_opened_paused_waits_for_resume_before_contacting_plex() {
+        let (plex_url, requests) = fake_plex(Vec::new()).await;
+        let mut state = app_state(vec![track("1")]);
+        state.plex_url = plex_url;
+
+        let params = HashMap::from([("start_paused".to_string(), "true".to_string()), ("session".to_string(), "s1".to_string())]);
+        let pairs: Vec<(String, String)> = params.clone().into_iter().collect();
+        let resp = stream_radio(Method::GET, State(state.clone()), Query(params), Query(pairs))
+            .await
+            .into_response();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let mut body = resp.into_body().into_data_stream();
+        tokio::spawn(async move { while futures::StreamExt::next(&mut body).await.is_some() {} });
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(requests.lock().unwrap().is_empty());
+        assert!(state.session_controls.lock().unwrap().contains_key("s1"));
+
+        let params = HashMap::from([("session".to_string(), "s1".to_string())]);
+        assert!(resume_session(State(state.clone()), Query(params)).await.is_ok());
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while requests.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Plex was not contacted after /resume");
+    }
+
+    #[cfg(feature = "hls")]
+    #[tokio::test]
+    async fn hls_transcodes_count_against_the_stream_limit() {
+        let (plex_url, _) = fake_plex(vec![("/music/:/transcode/universal/start.m3u8", "#EXTM3U\nsegment.ts\n")]).await;
+        let mut state = app_state(vec![track("1")]);
+        state.plex_url = plex_url;
+        state.protocol = StreamProtocol::Hls;
+        state.stream_permits = Arc::new(Semaphore::new(1));
+        state.max_streams = 1;
+
+        let open = |session: &str| {
+            let params = HashMap::from([("session".to_string(), session.to_string())]);
+            let pairs: Vec<(String, String)> = params.clone().into_iter().collect();
+            stream_radio(Method::GET, State(state.clone()), Query(params), Query(pairs))
+        };
+        assert_eq!(open("s1").await.into_response().status(), StatusCode::OK);
+        assert_eq!(open("s2").await.into_response().status(), StatusCode::SERVICE_UNAVAILABLE);
+        // The session's next track takes over its own slot
+        assert_eq!(open("s1").await.into_response().status(), StatusCode::OK);
+        assert_eq!(state.stream_permits.available_permits(), 0);
+    }
+
+    #[tokio::test]
+    async fn config_lists_the_stations_the_library_can_play() {
+        let genre_track = |key: &str, genres: &[&str]| Track { genres: genres.iter().map(|g| g.to_string()).collect(), ..track(key) };
+        let mut state = app_state(vec![
+            genre_track("1", &["Rock", "Jazz & Blues"]),
+            genre_track("2", &["rock"]),
+            Track { content_rating: Some("Explicit".to_string()), ..genre_track("3", &["Rap"]) },
+        ]);
+        state.block_explicit = true;
+
+        let config = client_config(State(state.clone())).await.into_response();
+        let body = axum::body::to_bytes(config.into_body(), usize::MAX).await.unwrap();
+        let config: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(config["genres"], serde_json::json!([
+            { "name": "Jazz & Blues", "tracks": 1 },
+            { "name": "Rock", "tracks": 2 },
+        ]));
+        let paths: Vec<&str> = config["stations"].as_array().unwrap().iter()
+            .map(|s| s["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(paths, ["/radio", "/radio?genre=Jazz%20%26%20Blues", "/radio?genre=Rock"]);
+
+        state.library_source = Arc::new(LibrarySource {
+            section_ids: Vec::new(),
+            track_type: "10".to_string(),
+            playlist_id: Some("42".to_string()),
+        });
+        state.dayparts = Arc::new(Dayparts {
+            parts: vec![Daypart { start_hour: 6, end_hour: 12, genre: "rock".to_string() }],
+            utc_offset_mins: 0,
+        });
+        let config = client_config(State(state)).await.into_response();
+        let body = axum::body::to_bytes(config.into_body(), usize::MAX).await.unwrap();
+        let config: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let ids: Vec<&str> = config["stations"].as_array().unwrap().iter()
+            .map(|s| s["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["library", "playlist", "auto", "genre:jazz & blues", "genre:rock"]);
+    }
+
+    #[tokio::test]
+    async fn blocked_tracks_are_left_out_of_playlists_and_the_queue() {
+        let (plex_url, requests) = fake_plex(vec![("/playlists/7/items", r#"{"MediaContainer": {"Metadata": [
+            {"ratingKey": "1", "title": "One", "Media": [{"Part": [{"key": "/library/parts/1/a.mp3"}]}]},
+            {"ratingKey": "2", "title": "Two", "Media": [{"Part": [{"key": "/library/parts/2/a.mp3"}]}]},
+            {"ratingKey": "3", "title": "Three", "Media": [{"Part": [{"key": "/library/parts/3/a.mp3"}]}]}
+        ]}}"#)]).await;
+        let mut state = app_state((1..=4).map(|i| track(&i.to_string())).collect());
+        state.plex_url = plex_url;
+        state.stream_health.error_backoff = Duration::ZERO;
+        let blocks = BlockList { keys: HashSet::from(["2".to_string(), "4".to_string()]), artists: HashSet::new() };
+        state.blocked.lock().unwrap().insert("alice".to_string(), blocks);
+        state.queues.lock().unwrap().insert("s1".to_string(), VecDeque::from([track("4")]));
+
+        let params = HashMap::from([
+            ("playlist".to_string(), "7".to_string()),
+            ("session".to_string(), "s1".to_string()),
+            ("client_id".to_string(), "alice".to_string()),
+        ]);
+        let pairs: Vec<(String, String)> = params.clone().into_iter().collect();
+        let resp = stream_radio(Method::GET, State(state.clone()), Query(params), Query(pairs))
+            .await
+            .into_response();
+        let mut body = resp.into_body().into_data_stream();
+        tokio::spawn(async move { while futures::StreamExt::next(&mut body).await.is_some() {} });
+
+        // Every transcode fails, so the stream walks on through the playlist
+        let played = || -> Vec<String> {
+            requests.lock().unwrap().iter()
+                .filter(|head| head.contains("/transcode/universal/start"))
+                .map(|head| head.split("metadata%2F").nth(1).unwrap_or("").chars().take_while(char::is_ascii_digit).collect())
+                .collect()
+        };
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while played().len() < 4 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the stream stopped walking the playlist");
+        assert_eq!(played()[..4], ["1", "3", "1", "3"]);
+    }
+
+    #[tokio::test]
+    async fn startup_steps_are_retried_only_while_unreachable() {
+        // Nothing listens on port 1, like a Plex that is still booting
+        let unreachable = || async { Client::new().get("http://127.0.0.1:1/identity").send().await };
+
+        let attempts = AtomicU32::new(0);
+        let up = retry_startup("Test", 3, Duration::ZERO, || async {
+            if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                unreachable().await?;
+            }
+            Ok("up")
+        })
+        .await;
+        assert_eq!(up.unwrap(), "up");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = AtomicU32::new(0);
+        let down = retry_startup("Test", 2, Duration::ZERO, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            unreachable().await?;
+            Ok(())
+        })
+        .await;
+        assert!(down.unwrap_err().to_string().contains("still failed after 3 attempts"));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // A configuration mistake fails straight away
+        let attempts = AtomicU32::new(0);
+        let wrong: anyhow::Result<()> = retry_startup("Test", 3, Duration::ZERO, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            anyhow::bail!("no server named 'home' on this Plex account")
+        })
+        .await;
+        assert!(wrong.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        // A server whose every address is down is worth waiting for
+        let (plex_tv, _) = fake_plex(vec![("/api/v2/resources", r#"[{"name": "Home", "provides": "server", "connections": [{"uri": "http://127.0.0.1:1"}]}]"#)]).await;
+        let token = PlexToken { value: "token".to_string(), in_query: false };
+        let plex_client = app_state(Vec::new()).plex_client;
+        let error = discover_server_url(&Client::new(), &Client::new(), &plex_client, &plex_tv, &token, "home").await.unwrap_err();
+        assert!(is_transient(&error), "{:#}", error);
+    }
+}